@@ -0,0 +1,84 @@
+/*
+    Benchmark for evaluating a sheet with 100k formulas
+
+    run with `cargo bench`, it prints how long evaluating every formula takes
+    when each one is parsed on every evaluation (like before formulas were
+    cached), the first time through (parsing and compiling them) and once they
+    are compiled
+*/
+
+
+use std::time::{Duration, Instant};
+
+use rust_sheet::{sheet, parsing, dtypes};
+
+
+const N_ROWS: usize = 1000;
+const N_FORMULA_COLS: usize = 100;
+
+
+fn col_name (idx: usize) -> String {
+    // column letters for a 0-based column index (0 -> A, 26 -> AA)
+    let mut name = String::new();
+    let mut idx = idx + 1;
+    while idx > 0 {
+        name.insert(0, (b'A' + ((idx - 1) % 26) as u8) as char);
+        idx = (idx - 1) / 26;
+    }
+    name
+}
+
+
+fn build_sheet () -> (sheet::Sheet, Vec<(dtypes::CellLoc, dtypes::CellVal)>) {
+    // column A has values, the next 100 columns have formulas using them
+    let mut sheet = sheet::Sheet::new();
+    let mut formulas = Vec::new();
+    for row in 1..=N_ROWS {
+        sheet.write_cell(parsing::parse_loc(&format!("A{}", row)).unwrap(), dtypes::CellVal::Int(row as i64));
+    }
+    for c in 1..=N_FORMULA_COLS {
+        let col = col_name(c);
+        for row in 1..=N_ROWS {
+            let loc = parsing::parse_loc(&format!("{}{}", col, row)).unwrap();
+            let val = parsing::parse_val(&format!("=(A{} * {} + A{} / 2) ^ 2 - (A{} > 500) + DAY(DATE(2024, 1, 31))", row, c, row, row));
+            sheet.write_cell(loc.clone(), val.clone());
+            formulas.push((loc, val));
+        }
+    }
+    (sheet, formulas)
+}
+
+
+fn eval_all (sheet: &sheet::Sheet, formulas: &[(dtypes::CellLoc, dtypes::CellVal)]) -> Duration {
+    let start = Instant::now();
+    let view = sheet.view();
+    for (loc, _) in formulas {
+        assert!(view.cell(loc).is_ok(), "{:?} failed to evaluate", loc);
+    }
+    start.elapsed()
+}
+
+
+fn main () {
+    let (mut sheet, formulas) = build_sheet();
+    eprintln!("{} formulas", formulas.len());
+
+    // parsing every formula every time it gets evaluated
+    let start = Instant::now();
+    for (loc, val) in &formulas {
+        let shown = sheet.eval_formula_cell(val);
+        assert!(!matches!(shown, dtypes::CellVal::Text(_)), "{:?} failed to evaluate", loc);
+    }
+    let uncached = start.elapsed();
+
+    // first time through the formulas get parsed and cached
+    let first = eval_all(&sheet, &formulas);
+
+    // changing a cell throws out the results but not the parsed formulas
+    sheet.write_cell(parsing::parse_loc("A1").unwrap(), dtypes::CellVal::Int(2));
+    let cached = eval_all(&sheet, &formulas);
+
+    println!("parsed every time: {:>10.1?}", uncached);
+    println!("first evaluation:  {:>10.1?}", first);
+    println!("cached:            {:>10.1?} ({:.1}x faster)", cached, uncached.as_secs_f64() / cached.as_secs_f64());
+}
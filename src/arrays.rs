@@ -0,0 +1,311 @@
+/*
+    Module with the dynamic array formula functions, these give back blocks
+    of values that spill into the cells below and to the right of the formula
+*/
+
+
+use std::cmp::Ordering;
+
+use crate::dtypes::{CellGrid, CellVal, EvalErr, EvalVal, Op};
+use crate::formulas;
+use crate::sheet;
+
+
+// arrays bigger than this many values give #NUM! instead of trying to spill
+const MAX_CELLS: usize = 1_000_000;
+
+
+fn to_grid (val: &EvalVal) -> CellGrid {
+    // a single value is just a 1x1 block
+    match val {
+        EvalVal::Val(v) => CellGrid { n_rows: 1, n_cols: 1, vals: vec![Some(v.clone())] },
+        EvalVal::Grid(grid) => grid.clone(),
+    }
+}
+
+
+fn grid_rows (grid: &CellGrid) -> Vec<Vec<Option<CellVal>>> {
+    grid.vals.chunks(grid.n_cols).map(|row| row.to_vec()).collect()
+}
+
+
+fn from_rows (rows: Vec<Vec<Option<CellVal>>>, n_cols: usize) -> CellGrid {
+    CellGrid { n_rows: rows.len(), n_cols, vals: rows.into_iter().flatten().collect() }
+}
+
+
+fn transpose (grid: &CellGrid) -> CellGrid {
+    let mut vals = Vec::with_capacity(grid.vals.len());
+    for j in 0..grid.n_cols {
+        for i in 0..grid.n_rows {
+            vals.push(grid.vals[i * grid.n_cols + j].clone());
+        }
+    }
+    CellGrid { n_rows: grid.n_cols, n_cols: grid.n_rows, vals }
+}
+
+
+fn opt_bool (args: &[EvalVal], i: usize) -> Result<bool, EvalErr> {
+    // optional TRUE/FALSE argument, FALSE if it is not there
+    match args.get(i) {
+        Some(val) => formulas::arg_bool(val),
+        None => Ok(false),
+    }
+}
+
+
+fn opt_val (args: &[EvalVal], i: usize, default: CellVal) -> Result<CellVal, EvalErr> {
+    // optional numeric argument that keeps its type (e.g. stays an Int)
+    match args.get(i) {
+        Some(val) => {
+            formulas::arg_num(val)?;
+            formulas::scalar(val).cloned()
+        },
+        None => Ok(default),
+    }
+}
+
+
+fn compare_opt (left: &Option<CellVal>, right: &Option<CellVal>) -> Ordering {
+    // compare_vals, with empty cells after everything else
+    match (left, right) {
+        (Some(l), Some(r)) => formulas::compare_vals(l, r),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+
+fn rows_equal (left: &[Option<CellVal>], right: &[Option<CellVal>]) -> bool {
+    left.iter().zip(right).all(|(l, r)| compare_opt(l, r) == Ordering::Equal)
+}
+
+
+fn is_included (val: &Option<CellVal>) -> Result<bool, EvalErr> {
+    // whether a value from the include argument of FILTER keeps its row/column
+    match val {
+        Some(CellVal::Bool(v)) => Ok(*v),
+        Some(CellVal::Int(v)) => Ok(*v != 0),
+        Some(CellVal::Real(v)) => Ok(*v != 0.0),
+        Some(CellVal::Decimal(v)) => Ok(v.to_f64() != 0.0),
+        None => Ok(false),
+        _ => Err(EvalErr::Value),
+    }
+}
+
+
+pub fn fn_sequence (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // SEQUENCE(rows, [cols], [start], [step])
+    // numbers counting up from start (default 1) by step (default 1),
+    // filling in each row before moving on to the next one
+    let n_rows = formulas::arg_int(&args[0])?;
+    let n_cols = match args.get(1) {
+        Some(val) => formulas::arg_int(val)?,
+        None => 1,
+    };
+    if n_rows < 0 || n_cols < 0 {
+        return Err(EvalErr::Value)
+    }
+    if n_rows == 0 || n_cols == 0 {
+        return Err(EvalErr::Calc)
+    }
+    let (n_rows, n_cols) = (n_rows as usize, n_cols as usize);
+    if n_rows.saturating_mul(n_cols) > MAX_CELLS {
+        return Err(EvalErr::Num)
+    }
+    let start = opt_val(args, 2, CellVal::Int(1))?;
+    let step = opt_val(args, 3, CellVal::Int(1))?;
+    let mut vals = Vec::with_capacity(n_rows * n_cols);
+    for k in 0..(n_rows * n_cols) {
+        let offset = formulas::apply_binop(&Op::Mul, step.clone(), CellVal::Int(k as i64))?;
+        vals.push(Some(formulas::apply_binop(&Op::Plus, start.clone(), offset)?));
+    }
+    Ok(EvalVal::Grid(CellGrid { n_rows, n_cols, vals }))
+}
+
+
+pub fn fn_sort (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // SORT(array, [sort_index], [sort_order], [by_col])
+    // sorts the rows (or the columns if by_col is TRUE) by the values in
+    // column (row) number sort_index, sort_order is 1 for ascending (the
+    // default) or -1 for descending, empty cells always go last
+    let by_col = opt_bool(args, 3)?;
+    let grid = if by_col { transpose(&to_grid(&args[0])) } else { to_grid(&args[0]) };
+    let sort_index = match args.get(1) {
+        Some(val) => formulas::arg_int(val)?,
+        None => 1,
+    };
+    if sort_index < 1 || sort_index as usize > grid.n_cols {
+        return Err(EvalErr::Value)
+    }
+    let descending = match args.get(2) {
+        Some(val) => match formulas::arg_int(val)? {
+            1 => false,
+            -1 => true,
+            _ => return Err(EvalErr::Value),
+        },
+        None => false,
+    };
+    let key = sort_index as usize - 1;
+    let mut rows = grid_rows(&grid);
+    rows.sort_by(|l, r| {
+        match (&l[key], &r[key]) {
+            (Some(_), Some(_)) if descending => compare_opt(&r[key], &l[key]),
+            _ => compare_opt(&l[key], &r[key]),
+        }
+    });
+    let sorted = from_rows(rows, grid.n_cols);
+    Ok(EvalVal::Grid(if by_col { transpose(&sorted) } else { sorted }))
+}
+
+
+pub fn fn_filter (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // FILTER(array, include, [if_empty])
+    // keeps the rows of array where include (a column the same height as
+    // array) is TRUE or a non-zero number, include can also be a row the same
+    // width as array to keep columns instead
+    // if nothing is kept it gives if_empty or #CALC! if there is no if_empty
+    let grid = to_grid(&args[0]);
+    let include = to_grid(&args[1]);
+    let by_col = if include.n_cols == 1 && include.n_rows == grid.n_rows {
+        false
+    } else if include.n_rows == 1 && include.n_cols == grid.n_cols {
+        true
+    } else {
+        return Err(EvalErr::Value)
+    };
+    let grid = if by_col { transpose(&grid) } else { grid };
+    let mut rows = Vec::new();
+    for (row, keep) in grid_rows(&grid).into_iter().zip(include.vals.iter()) {
+        if is_included(keep)? {
+            rows.push(row);
+        }
+    }
+    if rows.is_empty() {
+        return match args.get(2) {
+            Some(val) => Ok(val.clone()),
+            None => Err(EvalErr::Calc),
+        }
+    }
+    let filtered = from_rows(rows, grid.n_cols);
+    Ok(EvalVal::Grid(if by_col { transpose(&filtered) } else { filtered }))
+}
+
+
+pub fn fn_unique (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // UNIQUE(array, [by_col], [exactly_once])
+    // the distinct rows (or columns if by_col is TRUE) of array in the order
+    // they first show up, with exactly_once only the ones that show up once
+    // text is compared ignoring case like in the comparison operators
+    let by_col = opt_bool(args, 1)?;
+    let exactly_once = opt_bool(args, 2)?;
+    let grid = if by_col { transpose(&to_grid(&args[0])) } else { to_grid(&args[0]) };
+    let mut distinct: Vec<(Vec<Option<CellVal>>, usize)> = Vec::new();
+    for row in grid_rows(&grid) {
+        match distinct.iter_mut().find(|(seen, _)| rows_equal(seen, &row)) {
+            Some((_, count)) => *count += 1,
+            None => distinct.push((row, 1)),
+        }
+    }
+    let rows: Vec<_> = distinct.into_iter()
+        .filter(|(_, count)| !exactly_once || *count == 1)
+        .map(|(row, _)| row)
+        .collect();
+    if rows.is_empty() {
+        return Err(EvalErr::Calc)
+    }
+    let unique = from_rows(rows, grid.n_cols);
+    Ok(EvalVal::Grid(if by_col { transpose(&unique) } else { unique }))
+}
+
+
+pub fn fn_transpose (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // TRANSPOSE(array)
+    // rows become columns and columns become rows
+    Ok(EvalVal::Grid(transpose(&to_grid(&args[0]))))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int (v: i64) -> EvalVal {
+        EvalVal::Val(CellVal::Int(v))
+    }
+
+    fn text (s: &str) -> Option<CellVal> {
+        Some(CellVal::Text(String::from(s)))
+    }
+
+    fn grid (n_rows: usize, n_cols: usize, vals: Vec<Option<CellVal>>) -> EvalVal {
+        EvalVal::Grid(CellGrid { n_rows, n_cols, vals })
+    }
+
+    fn ints (res: Result<EvalVal, EvalErr>) -> (usize, usize, Vec<i64>) {
+        // dimensions and values of an all Int result
+        match res {
+            Ok(EvalVal::Grid(g)) => {
+                let vals = g.vals.iter().map(|v| match v {
+                    Some(CellVal::Int(v)) => *v,
+                    other => panic!("expected an Int got {:?}", other),
+                }).collect();
+                (g.n_rows, g.n_cols, vals)
+            },
+            other => panic!("expected a grid got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sequence () {
+        let sheet = sheet::Sheet::new();
+        assert_eq!(ints(fn_sequence(&[int(3)], &sheet)), (3, 1, vec![1, 2, 3]));
+        assert_eq!(ints(fn_sequence(&[int(2), int(3), int(0), int(5)], &sheet)), (2, 3, vec![0, 5, 10, 15, 20, 25]));
+        assert!(matches!(fn_sequence(&[int(0)], &sheet), Err(EvalErr::Calc)));
+        assert!(matches!(fn_sequence(&[int(-1)], &sheet), Err(EvalErr::Value)));
+        assert!(matches!(fn_sequence(&[int(1_000_000), int(1_000_000)], &sheet), Err(EvalErr::Num)));
+        let res = fn_sequence(&[int(2), int(1), EvalVal::Val(CellVal::Real(0.5))], &sheet);
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if matches!(g.vals[1], Some(CellVal::Real(v)) if v == 1.5)), "{:?}", res);
+    }
+
+    #[test]
+    fn sort () {
+        let sheet = sheet::Sheet::new();
+        let vals = grid(4, 1, vec![Some(CellVal::Int(3)), None, Some(CellVal::Int(1)), Some(CellVal::Int(2))]);
+        let res = fn_sort(std::slice::from_ref(&vals), &sheet);
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if matches!(g.vals[..], [Some(CellVal::Int(1)), Some(CellVal::Int(2)), Some(CellVal::Int(3)), None])), "{:?}", res);
+        let res = fn_sort(&[vals, int(1), int(-1)], &sheet);
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if matches!(g.vals[..], [Some(CellVal::Int(3)), Some(CellVal::Int(2)), Some(CellVal::Int(1)), None])), "{:?}", res);
+        // sorting rows by the second column
+        let table = grid(3, 2, vec![text("a"), Some(CellVal::Int(2)), text("b"), Some(CellVal::Int(1)), text("c"), Some(CellVal::Int(3))]);
+        let res = fn_sort(&[table.clone(), int(2)], &sheet);
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if matches!(&g.vals[0], Some(CellVal::Text(t)) if t == "b")), "{:?}", res);
+        assert!(matches!(fn_sort(&[table, int(3)], &sheet), Err(EvalErr::Value)));
+    }
+
+    #[test]
+    fn filter () {
+        let sheet = sheet::Sheet::new();
+        let vals = grid(3, 1, vec![Some(CellVal::Int(10)), Some(CellVal::Int(20)), Some(CellVal::Int(30))]);
+        let keep = grid(3, 1, vec![Some(CellVal::Bool(true)), Some(CellVal::Bool(false)), Some(CellVal::Int(1))]);
+        assert_eq!(ints(fn_filter(&[vals.clone(), keep], &sheet)), (2, 1, vec![10, 30]));
+        let none = grid(3, 1, vec![Some(CellVal::Bool(false)); 3]);
+        assert!(matches!(fn_filter(&[vals.clone(), none.clone()], &sheet), Err(EvalErr::Calc)));
+        assert!(matches!(fn_filter(&[vals.clone(), none, int(0)], &sheet), Ok(EvalVal::Val(CellVal::Int(0)))));
+        let wrong_size = grid(2, 1, vec![Some(CellVal::Bool(true)); 2]);
+        assert!(matches!(fn_filter(&[vals, wrong_size], &sheet), Err(EvalErr::Value)));
+    }
+
+    #[test]
+    fn unique_and_transpose () {
+        let sheet = sheet::Sheet::new();
+        let vals = grid(5, 1, vec![text("a"), text("B"), text("A"), Some(CellVal::Int(1)), text("b")]);
+        let res = fn_unique(std::slice::from_ref(&vals), &sheet);
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if g.n_rows == 3), "{:?}", res);
+        let res = fn_unique(&[vals, EvalVal::Val(CellVal::Bool(false)), EvalVal::Val(CellVal::Bool(true))], &sheet);
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if g.n_rows == 1 && matches!(g.vals[0], Some(CellVal::Int(1)))), "{:?}", res);
+        let res = fn_transpose(&[grid(2, 3, (1..=6).map(|v| Some(CellVal::Int(v))).collect())], &sheet);
+        assert_eq!(ints(res), (3, 2, vec![1, 4, 2, 5, 3, 6]));
+    }
+}
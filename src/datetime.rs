@@ -0,0 +1,585 @@
+/*
+    Module with date, date-time and duration types and the date formula functions
+*/
+
+
+use std::fmt;
+use std::time;
+
+use crate::dtypes::{CellVal, EvalErr, EvalVal};
+use crate::formulas;
+use crate::sheet;
+
+
+const SECS_PER_DAY: i64 = 86_400;
+// dates are limited to 4 digit years so that they
+// always print as (and parse back from) ISO-8601
+const MIN_YEAR: i64 = 1;
+const MAX_YEAR: i64 = 9999;
+
+
+fn days_from_civil (y: i64, m: u32, d: u32) -> i64 {
+    // number of days since 1970-01-01 for a (proleptic Gregorian) calendar date
+    // from http://howardhinnant.github.io/date_algorithms.html
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;  // months starting from March
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+
+fn civil_from_days (z: i64) -> (i64, u32, u32) {
+    // inverse of days_from_civil, returns (year, month, day)
+    let z = z + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let y = yoe + era * 400;
+    (if m <= 2 { y + 1 } else { y }, m, d)
+}
+
+
+fn days_in_month (y: i64, m: u32) -> u32 {
+    match m {
+        4 | 6 | 9 | 11 => 30,
+        2 => if (y % 4 == 0 && y % 100 != 0) || y % 400 == 0 { 29 } else { 28 },
+        _ => 31,
+    }
+}
+
+
+fn parse_digits (s: &str, n: usize) -> Option<i64> {
+    // parse exactly n ascii digits
+    if s.len() != n || !s.chars().all(|c| c.is_ascii_digit()) {
+        return Option::None
+    }
+    s.parse::<i64>().ok()
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    // days since 1970-01-01
+    days: i64,
+}
+
+
+impl Date {
+    fn from_days (days: i64) -> Option<Date> {
+        // checked before converting so huge day counts cannot overflow
+        if days < days_from_civil(MIN_YEAR, 1, 1) || days > days_from_civil(MAX_YEAR, 12, 31) {
+            return Option::None
+        }
+        Option::Some(Date { days })
+    }
+
+    pub fn from_ymd (y: i64, m: u32, d: u32) -> Option<Date> {
+        if !(MIN_YEAR..=MAX_YEAR).contains(&y) || !(1..=12).contains(&m) || d < 1 || d > days_in_month(y, m) {
+            return Option::None
+        }
+        Date::from_days(days_from_civil(y, m, d))
+    }
+
+    pub fn from_ymd_normalized (y: i64, m: i64, d: i64) -> Option<Date> {
+        // like from_ymd but months and days outside of their normal ranges roll
+        // over into the next/previous years and months (e.g. month 13 is January
+        // of the next year, day 0 is the last day of the previous month)
+        let months = y.checked_mul(12)?.checked_add(m.checked_sub(1)?)?;
+        let first = Date::from_ymd(months.div_euclid(12), months.rem_euclid(12) as u32 + 1, 1)?;
+        first.add_days(d.checked_sub(1)?)
+    }
+
+    pub fn ymd (&self) -> (i64, u32, u32) {
+        civil_from_days(self.days)
+    }
+
+    pub fn weekday (&self) -> u32 {
+        // 0 = Monday ... 6 = Sunday (1970-01-01 was a Thursday)
+        (self.days + 3).rem_euclid(7) as u32
+    }
+
+    pub fn add_days (&self, days: i64) -> Option<Date> {
+        Date::from_days(self.days.checked_add(days)?)
+    }
+
+    pub fn add_months (&self, months: i64) -> Option<Date> {
+        // same day of the month, clamped to the end of the month if it is too short
+        let (y, m, d) = self.ymd();
+        let months = y.checked_mul(12)?.checked_add(m as i64 - 1)?.checked_add(months)?;
+        let (y, m) = (months.div_euclid(12), months.rem_euclid(12) as u32 + 1);
+        Date::from_ymd(y, m, d.min(days_in_month(y, m)))
+    }
+
+    pub fn end_of_month (&self) -> Date {
+        let (y, m, _) = self.ymd();
+        Date::from_ymd(y, m, days_in_month(y, m)).unwrap()
+    }
+
+    pub fn days_until (&self, other: &Date) -> i64 {
+        other.days - self.days
+    }
+
+    pub fn at_midnight (&self) -> DateTime {
+        DateTime { secs: self.days * SECS_PER_DAY }
+    }
+
+    pub fn parse (s: &str) -> Option<Date> {
+        // ISO-8601 calendar date: YYYY-MM-DD
+        let mut parts = s.split('-');
+        let y = parse_digits(parts.next()?, 4)?;
+        let m = parse_digits(parts.next()?, 2)?;
+        let d = parse_digits(parts.next()?, 2)?;
+        if parts.next().is_some() {
+            return Option::None
+        }
+        Date::from_ymd(y, m as u32, d as u32)
+    }
+}
+
+
+impl fmt::Display for Date {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (y, m, d) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", y, m, d)
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    // seconds since 1970-01-01T00:00:00, no time zone
+    secs: i64,
+}
+
+
+impl DateTime {
+    pub fn from_unix_secs (secs: i64) -> Option<DateTime> {
+        Date::from_days(secs.div_euclid(SECS_PER_DAY))?;
+        Option::Some(DateTime { secs })
+    }
+
+    pub fn date (&self) -> Date {
+        Date { days: self.secs.div_euclid(SECS_PER_DAY) }
+    }
+
+    fn secs_of_day (&self) -> i64 {
+        self.secs.rem_euclid(SECS_PER_DAY)
+    }
+
+    pub fn add_secs (&self, secs: i64) -> Option<DateTime> {
+        DateTime::from_unix_secs(self.secs.checked_add(secs)?)
+    }
+
+    pub fn secs_until (&self, other: &DateTime) -> i64 {
+        other.secs - self.secs
+    }
+
+    pub fn parse (s: &str) -> Option<DateTime> {
+        // ISO-8601 date and time: YYYY-MM-DDTHH:MM[:SS]
+        let (date, time) = s.split_once('T')?;
+        let date = Date::parse(date)?;
+        let mut parts = time.split(':');
+        let h = parse_digits(parts.next()?, 2)?;
+        let m = parse_digits(parts.next()?, 2)?;
+        let sec = match parts.next() {
+            Some(sec) => parse_digits(sec, 2)?,
+            None => 0,
+        };
+        if parts.next().is_some() || h > 23 || m > 59 || sec > 59 {
+            return Option::None
+        }
+        date.at_midnight().add_secs(h * 3600 + m * 60 + sec)
+    }
+}
+
+
+impl fmt::Display for DateTime {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.secs_of_day();
+        write!(f, "{}T{:02}:{:02}:{:02}", self.date(), secs / 3600, secs % 3600 / 60, secs % 60)
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration {
+    secs: i64,
+}
+
+
+impl Duration {
+    pub fn from_secs (secs: i64) -> Duration {
+        Duration { secs }
+    }
+
+    pub fn secs (&self) -> i64 {
+        self.secs
+    }
+
+    pub fn whole_days (&self) -> Option<i64> {
+        // number of days if the duration is a whole number of days
+        if self.secs % SECS_PER_DAY == 0 {
+            Option::Some(self.secs / SECS_PER_DAY)
+        } else {
+            Option::None
+        }
+    }
+
+    pub fn parse (s: &str) -> Option<Duration> {
+        // ISO-8601 duration with day and time components: [-]PnDTnHnMnS
+        // (e.g. P3D, PT1H30M, -P1DT12H), years and months are not allowed
+        // because they do not have a fixed length
+        let (neg, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let s = s.strip_prefix('P')?;
+        let (date_part, time_part) = match s.split_once('T') {
+            Some((d, t)) => {
+                if t.is_empty() {
+                    return Option::None
+                }
+                (d, t)
+            },
+            None => (s, ""),
+        };
+        if date_part.is_empty() && time_part.is_empty() {
+            return Option::None
+        }
+        let mut secs: i64 = 0;
+        for (part, units) in [(date_part, [('W', 7 * SECS_PER_DAY), ('D', SECS_PER_DAY)].as_slice()),
+                              (time_part, [('H', 3600), ('M', 60), ('S', 1)].as_slice())] {
+            let mut buf = String::new();
+            // units have to appear in order and at most once
+            let mut next_unit = 0;
+            for c in part.chars() {
+                if c.is_ascii_digit() {
+                    buf.push(c);
+                } else {
+                    let idx = next_unit + units[next_unit..].iter().position(|(u, _)| *u == c)?;
+                    let n = buf.parse::<i64>().ok()?;
+                    secs = secs.checked_add(n.checked_mul(units[idx].1)?)?;
+                    buf.clear();
+                    next_unit = idx + 1;
+                }
+            }
+            if !buf.is_empty() {
+                return Option::None
+            }
+        }
+        Option::Some(Duration { secs: if neg { -secs } else { secs } })
+    }
+}
+
+
+impl fmt::Display for Duration {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.secs < 0 {
+            write!(f, "-")?;
+        }
+        let secs = self.secs.unsigned_abs();
+        let (d, h, m, s) = (secs / 86_400, secs % 86_400 / 3600, secs % 3600 / 60, secs % 60);
+        write!(f, "P")?;
+        if d > 0 || secs == 0 {
+            write!(f, "{}D", d)?;
+        }
+        if h > 0 || m > 0 || s > 0 {
+            write!(f, "T")?;
+            if h > 0 {
+                write!(f, "{}H", h)?;
+            }
+            if m > 0 {
+                write!(f, "{}M", m)?;
+            }
+            if s > 0 {
+                write!(f, "{}S", s)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// the Debug output of these types is what gets written into sheet.txt
+// so it is the ISO-8601 form that gets parsed back in by parse_val
+impl fmt::Debug for Date {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+
+impl fmt::Debug for DateTime {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+
+impl fmt::Debug for Duration {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+
+pub trait Clock: fmt::Debug {
+    // source of the current date and time for TODAY() and NOW(),
+    // swap in a FixedClock to make evaluation deterministic
+    fn now (&self) -> DateTime;
+}
+
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+
+impl Clock for SystemClock {
+    fn now (&self) -> DateTime {
+        // UTC, there is no time zone handling
+        let secs = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs();
+        DateTime::from_unix_secs(secs as i64).unwrap()
+    }
+}
+
+
+#[derive(Debug)]
+pub struct FixedClock(pub DateTime);
+
+
+impl Clock for FixedClock {
+    fn now (&self) -> DateTime {
+        self.0
+    }
+}
+
+
+/*
+    date formula functions, these get registered in formulas::BUILTINS
+*/
+
+
+pub fn arg_date (val: &EvalVal) -> Result<Date, EvalErr> {
+    // date function argument, the date part of a DateTime is also fine
+    match formulas::scalar(val)? {
+        CellVal::Date(d) => Ok(*d),
+        CellVal::DateTime(dt) => Ok(dt.date()),
+        _ => Err(EvalErr::Value),
+    }
+}
+
+
+pub fn fn_date (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // DATE(year, month, day)
+    let y = formulas::arg_int(&args[0])?;
+    let m = formulas::arg_int(&args[1])?;
+    let d = formulas::arg_int(&args[2])?;
+    match Date::from_ymd_normalized(y, m, d) {
+        Some(date) => Ok(CellVal::Date(date)),
+        None => Err(EvalErr::Num),
+    }
+}
+
+
+pub fn fn_year (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // YEAR(date)
+    Ok(CellVal::Int(arg_date(&args[0])?.ymd().0))
+}
+
+
+pub fn fn_month (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // MONTH(date)
+    Ok(CellVal::Int(arg_date(&args[0])?.ymd().1 as i64))
+}
+
+
+pub fn fn_day (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // DAY(date)
+    Ok(CellVal::Int(arg_date(&args[0])?.ymd().2 as i64))
+}
+
+
+pub fn fn_weekday (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // WEEKDAY(date, [return_type])
+    // return_type 1 (default): Sunday = 1 ... Saturday = 7
+    // return_type 2: Monday = 1 ... Sunday = 7
+    // return_type 3: Monday = 0 ... Sunday = 6
+    let wd = arg_date(&args[0])?.weekday() as i64;
+    let return_type = match args.get(1) {
+        Some(val) => formulas::arg_int(val)?,
+        None => 1,
+    };
+    match return_type {
+        1 => Ok(CellVal::Int((wd + 1) % 7 + 1)),
+        2 => Ok(CellVal::Int(wd + 1)),
+        3 => Ok(CellVal::Int(wd)),
+        _ => Err(EvalErr::Num),
+    }
+}
+
+
+pub fn fn_edate (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // EDATE(start_date, months)
+    let start = arg_date(&args[0])?;
+    let months = formulas::arg_int(&args[1])?;
+    match start.add_months(months) {
+        Some(date) => Ok(CellVal::Date(date)),
+        None => Err(EvalErr::Num),
+    }
+}
+
+
+pub fn fn_eomonth (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // EOMONTH(start_date, months)
+    let start = arg_date(&args[0])?;
+    let months = formulas::arg_int(&args[1])?;
+    match start.add_months(months) {
+        Some(date) => Ok(CellVal::Date(date.end_of_month())),
+        None => Err(EvalErr::Num),
+    }
+}
+
+
+pub fn fn_datedif (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // DATEDIF(start_date, end_date, unit)
+    // unit is one of "Y", "M", "D" (complete years/months/days between the dates)
+    // or "MD", "YM", "YD" (days/months/days ignoring the months/years/years)
+    let start = arg_date(&args[0])?;
+    let end = arg_date(&args[1])?;
+    let unit = formulas::arg_text(&args[2])?.to_ascii_uppercase();
+    if start > end {
+        return Err(EvalErr::Num)
+    }
+    let (y0, m0, d0) = start.ymd();
+    let (y1, m1, d1) = end.ymd();
+    // complete months between the two dates
+    let mut months = (y1 - y0) * 12 + m1 as i64 - m0 as i64;
+    if d1 < d0 {
+        months -= 1;
+    }
+    let res = match unit.as_str() {
+        "Y" => months / 12,
+        "M" => months,
+        "D" => start.days_until(&end),
+        "YM" => months % 12,
+        "MD" => {
+            // days since the last time the day of the month of start came around
+            start.add_months(months).ok_or(EvalErr::Num)?.days_until(&end)
+        },
+        "YD" => {
+            // days since the last anniversary of start
+            start.add_months(months / 12 * 12).ok_or(EvalErr::Num)?.days_until(&end)
+        },
+        _ => return Err(EvalErr::Num),
+    };
+    Ok(CellVal::Int(res))
+}
+
+
+pub fn fn_networkdays (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // NETWORKDAYS(start_date, end_date, [holidays, ...])
+    // number of weekdays (Mon-Fri) from start to end inclusive that are not holidays,
+    // negative if end is before start, holidays can be single dates or ranges of them
+    // (empty cells in the ranges are skipped)
+    let start = arg_date(&args[0])?;
+    let end = arg_date(&args[1])?;
+    let mut holidays = Vec::new();
+    for val in &args[2..] {
+        match val {
+            EvalVal::Grid(grid) => {
+                for v in grid.vals.iter().flatten() {
+                    holidays.push(arg_date(&EvalVal::Val(v.clone()))?);
+                }
+            },
+            _ => holidays.push(arg_date(val)?),
+        }
+    }
+    let (first, last, sign) = if start <= end { (start, end, 1) } else { (end, start, -1) };
+    let mut count: i64 = 0;
+    let mut day = first;
+    while day <= last {
+        if day.weekday() < 5 && !holidays.contains(&day) {
+            count += 1;
+        }
+        day = match day.add_days(1) {
+            Some(next) => next,
+            None => break,
+        };
+    }
+    Ok(CellVal::Int(sign * count))
+}
+
+
+pub fn fn_today (_args: &[EvalVal], sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // TODAY()
+    Ok(CellVal::Date(sheet.now().date()))
+}
+
+
+pub fn fn_now (_args: &[EvalVal], sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // NOW()
+    Ok(CellVal::DateTime(sheet.now()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn civil_round_trip () {
+        for days in [-719_162, -1, 0, 1, 59, 11_016, 19_782, 2_932_896] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(days_from_civil(2000, 3, 1), 11_017);
+    }
+
+    #[test]
+    fn parse_and_print () {
+        for s in ["2024-02-29", "0001-01-01", "9999-12-31"] {
+            assert_eq!(Date::parse(s).unwrap().to_string(), s);
+        }
+        assert!(Date::parse("2023-02-29").is_none(), "not a leap year");
+        assert!(Date::parse("2024-1-31").is_none(), "month needs 2 digits");
+        assert!(Date::parse("2024-01-31-").is_none());
+        assert_eq!(DateTime::parse("2024-01-31T08:30:15").unwrap().to_string(), "2024-01-31T08:30:15");
+        assert_eq!(DateTime::parse("2024-01-31T08:30").unwrap().to_string(), "2024-01-31T08:30:00");
+        assert!(DateTime::parse("2024-01-31T24:00").is_none());
+        for s in ["P3D", "PT1H30M", "-P1DT12H", "PT0S", "P1DT2H3M4S"] {
+            let dur = Duration::parse(s).unwrap();
+            let printed = if s == "PT0S" { "P0D" } else { s };
+            assert_eq!(dur.to_string(), printed);
+        }
+        assert_eq!(Duration::parse("P1W").unwrap().whole_days(), Some(7));
+        assert!(Duration::parse("P").is_none());
+        assert!(Duration::parse("P1DT").is_none());
+        assert!(Duration::parse("P1M").is_none(), "months have no fixed length");
+        assert!(Duration::parse("PT1S1H").is_none(), "units out of order");
+    }
+
+    #[test]
+    fn month_arithmetic () {
+        let d = Date::parse("2024-01-31").unwrap();
+        assert_eq!(d.add_months(1).unwrap().to_string(), "2024-02-29");
+        assert_eq!(d.add_months(-2).unwrap().to_string(), "2023-11-30");
+        assert_eq!(d.add_months(1).unwrap().end_of_month().to_string(), "2024-02-29");
+        assert_eq!(Date::from_ymd_normalized(2024, 13, 1).unwrap().to_string(), "2025-01-01");
+        assert_eq!(Date::from_ymd_normalized(2024, 3, 0).unwrap().to_string(), "2024-02-29");
+        assert_eq!(Date::from_ymd_normalized(2024, 0, 1).unwrap().to_string(), "2023-12-01");
+        assert!(Date::from_ymd(10_000, 1, 1).is_none());
+        assert!(Date::from_ymd_normalized(i64::MAX / 12, 1, 1).is_none());
+        assert!(Date::from_ymd_normalized(2024, i64::MIN, 1).is_none());
+        assert!(Date::from_ymd_normalized(2024, 1, i64::MIN).is_none());
+        // 2024-01-01 was a Monday
+        assert_eq!(Date::parse("2024-01-01").unwrap().weekday(), 0);
+    }
+}
@@ -0,0 +1,251 @@
+/*
+    Module with a fixed-point decimal number type for exact (e.g. currency) values
+*/
+
+
+use std::fmt;
+use std::cmp::Ordering;
+
+
+// maximum number of digits after the decimal point
+pub const MAX_SCALE: u32 = 28;
+// maximum number of significant digits that can be stored
+pub const MAX_DIGITS: u32 = 28;
+
+
+#[derive(Clone, Copy)]
+pub struct Decimal {
+    // the value is mantissa * 10^-scale
+    // e.g. 1.50 is stored as mantissa 150 with scale 2
+    mantissa: i128,
+    scale: u32,
+}
+
+
+fn pow10 (exp: u32) -> i128 {
+    10_i128.pow(exp)
+}
+
+
+impl Decimal {
+    pub fn new (mantissa: i128, scale: u32) -> Option<Decimal> {
+        // returns None if the value does not fit within MAX_DIGITS/MAX_SCALE
+        if scale > MAX_SCALE || mantissa.abs() >= pow10(MAX_DIGITS) {
+            return Option::None
+        }
+        Option::Some(Decimal { mantissa, scale })
+    }
+
+    pub fn from_i64 (val: i64) -> Option<Decimal> {
+        Decimal::new(val as i128, 0)
+    }
+
+    pub fn parse (s: &str) -> Option<Decimal> {
+        // parse a plain decimal literal like "12", "-0.10" or ".5"
+        // the number of digits after the decimal point is kept as the scale
+        // so that "0.10" prints back out as "0.10"
+        let (neg, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (int_part, frac_part) = match digits.split_once('.') {
+            Some((i, f)) => (i, f),
+            None => (digits, ""),
+        };
+        if int_part.is_empty() && frac_part.is_empty() {
+            return Option::None
+        }
+        let mut mantissa: i128 = 0;
+        for c in int_part.chars().chain(frac_part.chars()) {
+            let d = c.to_digit(10)? as i128;
+            mantissa = mantissa.checked_mul(10)?.checked_add(d)?;
+        }
+        if neg {
+            mantissa = -mantissa;
+        }
+        Decimal::new(mantissa, frac_part.len() as u32)
+    }
+
+    pub fn scale (&self) -> u32 {
+        // number of digits after the decimal point
+        self.scale
+    }
+
+    pub fn to_f64 (self) -> f64 {
+        self.mantissa as f64 / pow10(self.scale) as f64
+    }
+
+    fn rescale (&self, scale: u32) -> Option<Decimal> {
+        // increase the scale (add trailing zeros) without changing the value
+        let mantissa = self.mantissa.checked_mul(pow10(scale - self.scale))?;
+        Decimal::new(mantissa, scale)
+    }
+
+    fn aligned (&self, other: &Decimal) -> Option<(i128, i128, u32)> {
+        // bring both values to the same scale so their mantissas can be combined
+        let scale = self.scale.max(other.scale);
+        Option::Some((self.rescale(scale)?.mantissa, other.rescale(scale)?.mantissa, scale))
+    }
+
+    pub fn checked_add (&self, other: &Decimal) -> Option<Decimal> {
+        let (l, r, scale) = self.aligned(other)?;
+        Decimal::new(l.checked_add(r)?, scale)
+    }
+
+    pub fn checked_sub (&self, other: &Decimal) -> Option<Decimal> {
+        let (l, r, scale) = self.aligned(other)?;
+        Decimal::new(l.checked_sub(r)?, scale)
+    }
+
+    pub fn compare (&self, other: &Decimal) -> Ordering {
+        // exact unless lining up the scales overflows, then it is 
+        // close enough to compare them as f64s
+        match self.aligned(other) {
+            Some((l, r, _)) => l.cmp(&r),
+            None => self.to_f64().total_cmp(&other.to_f64()),
+        }
+    }
+
+    fn round_to_fit (mut mantissa: i128, mut scale: u32) -> Option<Decimal> {
+        // drop digits after the decimal point (rounding half away from zero)
+        // until the value fits within MAX_DIGITS/MAX_SCALE
+        while scale > MAX_SCALE || (scale > 0 && mantissa.abs() >= pow10(MAX_DIGITS)) {
+            let rem = mantissa % 10;
+            mantissa /= 10;
+            if rem >= 5 {
+                mantissa += 1;
+            } else if rem <= -5 {
+                mantissa -= 1;
+            }
+            scale -= 1;
+        }
+        Decimal::new(mantissa, scale)
+    }
+
+    pub fn checked_mul (&self, other: &Decimal) -> Option<Decimal> {
+        // exact unless there are more than MAX_SCALE digits after the decimal
+        // point, then it is rounded
+        Decimal::round_to_fit(self.mantissa.checked_mul(other.mantissa)?, self.scale + other.scale)
+    }
+
+    pub fn checked_div (&self, other: &Decimal) -> Option<Decimal> {
+        // long division, digits after the decimal point are added until it
+        // comes out even or the result is out of digits, then it is rounded
+        // returns None when dividing by zero
+        if other.mantissa == 0 {
+            return Option::None
+        }
+        let num = self.mantissa.unsigned_abs();
+        let den = other.mantissa.unsigned_abs();
+        let neg = (self.mantissa < 0) != (other.mantissa < 0);
+        let mut quot = num / den;
+        let mut rem = num % den;
+        // the quotient of the mantissas gets this scale
+        let mut scale = self.scale as i64 - other.scale as i64;
+        let limit = pow10(MAX_DIGITS) as u128;
+        while rem != 0 && scale < MAX_SCALE as i64 && quot * 10 < limit {
+            rem *= 10;
+            quot = quot * 10 + rem / den;
+            rem %= den;
+            scale += 1;
+        }
+        // round half away from zero on whatever is left over
+        if rem != 0 && rem * 2 >= den {
+            quot += 1;
+        }
+        let mut mantissa = quot as i128;
+        if scale < 0 {
+            mantissa = mantissa.checked_mul(pow10((-scale) as u32))?;
+            scale = 0;
+        }
+        Decimal::round_to_fit(if neg { -mantissa } else { mantissa }, scale as u32)
+    }
+}
+
+
+impl fmt::Display for Decimal {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.mantissa < 0 { "-" } else { "" };
+        let digits = self.mantissa.unsigned_abs().to_string();
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}{}", sign, digits)
+        }
+        // pad with leading zeros so there is at least one digit before the point
+        let digits = format!("{:0>width$}", digits, width = scale + 1);
+        let (int_part, frac_part) = digits.split_at(digits.len() - scale);
+        write!(f, "{}{}.{}", sign, int_part, frac_part)
+    }
+}
+
+
+impl fmt::Debug for Decimal {
+    // this is what gets written into sheet.txt so it needs to
+    // be exactly what Decimal::parse reads back in
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_print_round_trip () {
+        for s in ["0", "12", "-0.10", "0.05", "123.4500", "-7", "0.0000000000000000000000000001"] {
+            let d = Decimal::parse(s).unwrap();
+            assert_eq!(d.to_string(), s, "failed to round trip {}", s);
+        }
+        assert_eq!(Decimal::parse(".5").unwrap().to_string(), "0.5");
+        assert_eq!(Decimal::parse("+1.5").unwrap().to_string(), "1.5");
+    }
+
+    #[test]
+    fn parse_bad_values () {
+        assert!(Decimal::parse("").is_none());
+        assert!(Decimal::parse(".").is_none());
+        assert!(Decimal::parse("1.2.3").is_none());
+        assert!(Decimal::parse("abc").is_none());
+        // too many digits
+        assert!(Decimal::parse("12345678901234567890123456789").is_none());
+    }
+
+    #[test]
+    fn add_sub_exact () {
+        let a = Decimal::parse("0.1").unwrap();
+        let b = Decimal::parse("0.2").unwrap();
+        assert_eq!(a.checked_add(&b).unwrap().to_string(), "0.3");
+        let c = Decimal::parse("1.25").unwrap();
+        assert_eq!(a.checked_sub(&c).unwrap().to_string(), "-1.15");
+        let max = Decimal::parse("9999999999999999999999999999").unwrap();
+        assert!(max.checked_add(&Decimal::from_i64(1).unwrap()).is_none(), "overflow should give None");
+    }
+
+    #[test]
+    fn compare_exact () {
+        let a = Decimal::parse("0.10").unwrap();
+        assert_eq!(a.compare(&Decimal::parse("0.1").unwrap()), Ordering::Equal);
+        assert_eq!(a.compare(&Decimal::parse("0.1000000000000000000000000001").unwrap()), Ordering::Less);
+        assert_eq!(Decimal::parse("-2").unwrap().compare(&a), Ordering::Less);
+    }
+
+    #[test]
+    fn mul_div () {
+        let a = Decimal::parse("1.10").unwrap();
+        let b = Decimal::parse("-0.3").unwrap();
+        assert_eq!(a.checked_mul(&b).unwrap().to_string(), "-0.330");
+        assert_eq!(a.checked_div(&Decimal::parse("2").unwrap()).unwrap().to_string(), "0.55");
+        assert_eq!(Decimal::parse("1").unwrap().checked_div(&Decimal::parse("3").unwrap()).unwrap().to_string(),
+                   "0.3333333333333333333333333333");
+        assert_eq!(Decimal::parse("2").unwrap().checked_div(&Decimal::parse("3").unwrap()).unwrap().to_string(),
+                   "0.6666666666666666666666666667");
+        assert_eq!(Decimal::parse("100").unwrap().checked_div(&Decimal::parse("0.5").unwrap()).unwrap().to_string(), "200");
+        assert_eq!(Decimal::parse("-7.5").unwrap().checked_div(&Decimal::parse("2.5").unwrap()).unwrap().to_string(), "-3");
+        assert!(a.checked_div(&Decimal::parse("0.00").unwrap()).is_none(), "dividing by zero should give None");
+        // too many digits after the point get rounded off
+        let tiny = Decimal::parse("0.0000000000000000000000000001").unwrap();
+        assert_eq!(tiny.checked_mul(&Decimal::parse("0.5").unwrap()).unwrap().to_string(), "0.0000000000000000000000000001");
+    }
+}
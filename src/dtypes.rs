@@ -3,15 +3,111 @@
 */
 
 
+use std::fmt;
+
+use crate::decimal;
+use crate::datetime;
+
+
 #[derive(Debug, Clone)]
 pub enum CellVal {
-    Int(i32),
+    Int(i64),
     Real(f64),
+    Decimal(decimal::Decimal),
+    Date(datetime::Date),
+    DateTime(datetime::DateTime),
+    Duration(datetime::Duration),
+    // only ever the result of a formula (e.g. a comparison), not parsed from input
+    Bool(bool),
     Text(String),
     Formula(String),
 }
 
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvalErr {
+    // generic error (e.g. referencing an empty cell)
+    Err,
+    // wrong type of value for an operator or function argument
+    Value,
+    // invalid numeric value (e.g. a date that is out of range)
+    Num,
+    // unknown function name
+    Name,
+    // division by zero
+    Div0,
+    // reference to something that is not there (e.g. A1# where A1 does not spill)
+    Ref,
+    // array result that cannot spill because there are cells in the way
+    Spill,
+    // formula that (indirectly) depends on its own value
+    Circ,
+    // calculation that has no result (e.g. FILTER with nothing left)
+    Calc,
+    // value that is not there (e.g. REGEXEXTRACT with no match)
+    NA,
+    // formula that cannot be parsed (see ParseError)
+    Parse,
+}
+
+
+impl fmt::Display for EvalErr {
+    // this is the text that shows up in place of
+    // the value of a formula cell that failed to evaluate
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            EvalErr::Err => "#ERR",
+            EvalErr::Value => "#VALUE!",
+            EvalErr::Num => "#NUM!",
+            EvalErr::Name => "#NAME?",
+            EvalErr::Div0 => "#DIV/0!",
+            EvalErr::Ref => "#REF!",
+            EvalErr::Spill => "#SPILL!",
+            EvalErr::Circ => "#CIRC!",
+            EvalErr::Calc => "#CALC!",
+            EvalErr::NA => "#N/A",
+            EvalErr::Parse => "#PARSE!",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ParseError {
+    // why a formula could not be parsed: the character offset where it went 
+    // wrong (counting the = at the start) and what should have been there
+    pub offset: usize,
+    pub expected: &'static str,
+}
+
+
+impl fmt::Display for ParseError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "expected {} at character {}", self.expected, self.offset + 1)
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct CellGrid {
+    // 2-D block of values (e.g. the cells in a range), stored row by row
+    // with None for empty cells
+    pub n_rows: usize,
+    pub n_cols: usize,
+    pub vals: Vec<Option<CellVal>>,
+}
+
+
+#[derive(Debug, Clone)]
+pub enum EvalVal {
+    // result of evaluating (part of) a formula, either a single 
+    // value or a 2-D block of them
+    Val(CellVal),
+    Grid(CellGrid),
+}
+
+
 #[derive(Debug, Clone)]
 pub struct CellLoc {
     pub col: String,
@@ -30,23 +126,61 @@ pub struct Cell{
 pub enum Op {
     Plus,
     Minus,
+    Mul,
+    Div,
+    Pow,
+    // joins two values as text (&)
+    Concat,
+    // comparisons, these give a CellVal::Bool
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
 }
 
 #[derive(Debug, Clone)]
 pub enum FormToken {
-    Num(f64),
+    // numeric literal, holds a CellVal::Int, CellVal::Real or CellVal::Decimal
+    Num(CellVal),
     Loc(CellLoc),
+    // rectangular block of cells between two corners (e.g. A1:B5)
+    Range(CellLoc, CellLoc),
+    // whole columns (e.g. A:A or B:D) and whole rows (e.g. 3:3), these only
+    // reach as far as the sheet does when the formula is evaluated
+    Cols(String, String),
+    Rows(usize, usize),
+    // all of the cells an array formula spills into (e.g. A1#)
+    SpillRef(CellLoc),
+    // TRUE or FALSE
+    Bool(bool),
     BinOp(Op),
+    // unary minus, the operand ends up in TknNode.left
+    // (in the token list a leading - is still just a BinOp(Op::Minus))
+    Neg,
+    // string literal
+    Str(String),
+    // function name (upper case), the arguments end up in TknNode.args
+    Func(String),
+    // name bound by LET or a LAMBDA parameter or a defined name (upper case)
+    Name(String),
+    // these only appear in the token list, never in the tree
+    LParen,
+    RParen,
+    Comma,
 }
 
 
 pub type TknLink = Option<Box<TknNode>>;
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TknNode {
     pub token: FormToken,
     pub left: TknLink,
     pub right: TknLink,
+    // arguments of a FormToken::Func node, empty for everything else
+    pub args: Vec<TknNode>,
 }
 
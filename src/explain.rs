@@ -0,0 +1,161 @@
+/*
+    Module for showing how a formula got its value (explain subcommand)
+*/
+
+
+use crate::dtypes;
+use crate::formulas;
+use crate::sheet::Explanation;
+
+
+fn val_str (res: &Result<dtypes::EvalVal, dtypes::EvalErr>) -> String {
+    // values look like they do in read_sheet, blocks of values are
+    // written row by row like {Int(1),Int(2);Int(3),Int(4)}
+    match res {
+        Ok(dtypes::EvalVal::Val(val)) => format!("{:?}", val),
+        Ok(dtypes::EvalVal::Grid(grid)) => {
+            let rows: Vec<String> = grid.vals.chunks(grid.n_cols.max(1)).map(|row| {
+                let vals: Vec<String> = row.iter().map(|val| match val {
+                    Some(val) => format!("{:?}", val),
+                    None => String::new(),
+                }).collect();
+                vals.join(",")
+            }).collect();
+            format!("{{{}}}", rows.join(";"))
+        },
+        Err(err) => err.to_string(),
+    }
+}
+
+
+fn children (node: &dtypes::TknNode) -> Vec<&dtypes::TknNode> {
+    // operands (left then right) or arguments of a node
+    let mut nodes: Vec<&dtypes::TknNode> = node.left.iter().chain(node.right.iter()).map(|node| node.as_ref()).collect();
+    nodes.extend(node.args.iter());
+    nodes
+}
+
+
+fn tree_text (node: &dtypes::TknNode, depth: usize, out: &mut String) {
+    out.push_str(&format!("{}{}\n", "  ".repeat(depth + 1), formulas::node_label(node)));
+    for child in children(node) {
+        tree_text(child, depth + 1, out);
+    }
+}
+
+
+pub fn to_text (explanation: &Explanation) -> String {
+    // the formula, its parse tree, every step in the order it was evaluated
+    // (indented by how deep in the tree it is) and the result
+    let mut out = format!("{}{} {}\n", explanation.loc.col, explanation.loc.row, explanation.formula);
+    match &explanation.tree {
+        Ok(tree) => {
+            out.push_str("tree:\n");
+            if let Some(root) = &tree.root {
+                tree_text(root, 0, &mut out);
+            }
+        },
+        Err(err) => {
+            // point at where the formula stops making sense
+            let prefix = format!("{}{} ", explanation.loc.col, explanation.loc.row);
+            out.push_str(&format!("{}^\n{}\n", " ".repeat(prefix.chars().count() + err.offset), err));
+        },
+    }
+    out.push_str("steps:\n");
+    for step in &explanation.steps {
+        out.push_str(&format!("{}{} -> {}\n", "  ".repeat(step.depth + 1), formulas::print_expr(&step.node), val_str(&step.result)));
+    }
+    out.push_str(&format!("result: {}\n", val_str(&explanation.result)));
+    out
+}
+
+
+fn json_str (s: &str) -> String {
+    // quoted and escaped JSON string
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+
+fn tree_json (node: &dtypes::TknNode) -> String {
+    let children: Vec<String> = children(node).into_iter().map(tree_json).collect();
+    format!("{{\"label\":{},\"children\":[{}]}}", json_str(&formulas::node_label(node)), children.join(","))
+}
+
+
+fn result_json (res: &Result<dtypes::EvalVal, dtypes::EvalErr>) -> String {
+    format!("\"value\":{},\"is_error\":{}", json_str(&val_str(res)), res.is_err())
+}
+
+
+pub fn to_json (explanation: &Explanation) -> String {
+    // same as to_text but as a single JSON object for tooling
+    let (tree, parse_error) = match &explanation.tree {
+        Ok(tree) => (tree.root.as_ref().map_or(String::from("null"), |root| tree_json(root)), String::from("null")),
+        Err(err) => (String::from("null"), format!("{{\"offset\":{},\"expected\":{}}}", err.offset, json_str(err.expected))),
+    };
+    let steps: Vec<String> = explanation.steps.iter().map(|step| {
+        format!("{{\"depth\":{},\"expr\":{},{}}}", step.depth, json_str(&formulas::print_expr(&step.node)), result_json(&step.result))
+    }).collect();
+    format!("{{\"loc\":{},\"formula\":{},\"tree\":{},\"parse_error\":{},\"steps\":[{}],{}}}",
+        json_str(&format!("{}{}", explanation.loc.col, explanation.loc.row)), json_str(&explanation.formula),
+        tree, parse_error, steps.join(","), result_json(&explanation.result))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{parsing, sheet};
+
+    fn example () -> sheet::Sheet {
+        let mut sheet = sheet::Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("5"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("=A1*2"));
+        sheet.write_cell(parsing::parse_loc("A3").unwrap(), parsing::parse_val("=A2+-(A1-1)"));
+        sheet.write_cell(parsing::parse_loc("B1").unwrap(), parsing::parse_val("=SEQUENCE(2)*\"x\""));
+        sheet.write_cell(parsing::parse_loc("B2").unwrap(), parsing::parse_val("=1+"));
+        sheet
+    }
+
+    #[test]
+    fn explain_text () {
+        let sheet = example();
+        let explanation = sheet.explain(&parsing::parse_loc("A3").unwrap()).unwrap();
+        // other formula cells only show up with their value
+        assert_eq!(to_text(&explanation), "A3 =A2+-(A1-1)\ntree:\n  +\n    A2\n    -\n      -\n        A1\n        1\n\
+                                           steps:\n    A2 -> Int(10)\n        A1 -> Int(5)\n        1 -> Int(1)\n      A1-1 -> Int(4)\n    -(A1-1) -> Int(-4)\n  A2+-(A1-1) -> Int(6)\n\
+                                           result: Int(6)\n");
+        let explanation = sheet.explain(&parsing::parse_loc("B2").unwrap()).unwrap();
+        assert_eq!(to_text(&explanation), "B2 =1+\n      ^\nexpected a value at character 4\nsteps:\nresult: #PARSE!\n");
+        assert!(sheet.explain(&parsing::parse_loc("A1").unwrap()).is_none(), "A1 has no formula to explain");
+    }
+
+    #[test]
+    fn explain_json () {
+        let sheet = example();
+        let explanation = sheet.explain(&parsing::parse_loc("B1").unwrap()).unwrap();
+        assert_eq!(to_json(&explanation), "{\"loc\":\"B1\",\"formula\":\"=SEQUENCE(2)*\\\"x\\\"\",\
+                                           \"tree\":{\"label\":\"*\",\"children\":[{\"label\":\"SEQUENCE()\",\"children\":[{\"label\":\"2\",\"children\":[]}]},{\"label\":\"\\\"x\\\"\",\"children\":[]}]},\
+                                           \"parse_error\":null,\"steps\":[{\"depth\":2,\"expr\":\"2\",\"value\":\"Int(2)\",\"is_error\":false},\
+                                           {\"depth\":1,\"expr\":\"SEQUENCE(2)\",\"value\":\"{Int(1);Int(2)}\",\"is_error\":false},\
+                                           {\"depth\":1,\"expr\":\"\\\"x\\\"\",\"value\":\"Text(\\\"x\\\")\",\"is_error\":false},\
+                                           {\"depth\":0,\"expr\":\"SEQUENCE(2)*\\\"x\\\"\",\"value\":\"#VALUE!\",\"is_error\":true}],\
+                                           \"value\":\"#VALUE!\",\"is_error\":true}");
+        let explanation = sheet.explain(&parsing::parse_loc("B2").unwrap()).unwrap();
+        assert!(to_json(&explanation).contains("\"tree\":null,\"parse_error\":{\"offset\":3,\"expected\":\"a value\"}"));
+    }
+}
@@ -0,0 +1,342 @@
+/*
+    Module with the financial formula functions
+*/
+
+
+use crate::dtypes::{CellVal, EvalErr, EvalVal};
+use crate::datetime;
+use crate::formulas;
+use crate::sheet;
+
+
+// the iterative solvers (RATE, IRR, XIRR) stop after this many iterations
+// without converging and give #NUM!, they converge once a step is smaller
+// than TOLERANCE
+const MAX_ITER: usize = 100;
+const TOLERANCE: f64 = 1e-10;
+
+
+fn opt_num (args: &[EvalVal], i: usize, default: f64) -> Result<f64, EvalErr> {
+    // optional numeric argument
+    match args.get(i) {
+        Some(val) => formulas::arg_num(val),
+        None => Ok(default),
+    }
+}
+
+
+fn opt_type (args: &[EvalVal], i: usize) -> Result<f64, EvalErr> {
+    // optional payment type argument: 0 (default) payments at the end
+    // of each period, anything else payments at the beginning
+    Ok(if opt_num(args, i, 0.0)? != 0.0 { 1.0 } else { 0.0 })
+}
+
+
+fn real (val: f64) -> Result<CellVal, EvalErr> {
+    if val.is_finite() {
+        Ok(CellVal::Real(val))
+    } else {
+        Err(EvalErr::Num)
+    }
+}
+
+
+fn newton<F> (f: F, guess: f64) -> Result<f64, EvalErr>
+where F: Fn(f64) -> (f64, f64) {
+    // Newton's method for a rate starting from guess, f gives the value
+    // and derivative of the function whose root is being found
+    // the same inputs always take the same steps so this is deterministic
+    let mut x = guess;
+    for _ in 0..MAX_ITER {
+        let (y, dy) = f(x);
+        if !y.is_finite() || !dy.is_finite() || dy == 0.0 {
+            return Err(EvalErr::Num)
+        }
+        let step = y / dy;
+        x -= step;
+        if x <= -1.0 || !x.is_finite() {
+            // rates of -100% or lower make no sense
+            return Err(EvalErr::Num)
+        }
+        if step.abs() < TOLERANCE {
+            return Ok(x)
+        }
+    }
+    Err(EvalErr::Num)
+}
+
+
+fn annuity_factor (rate: f64, nper: f64) -> f64 {
+    // ((1 + rate)^nper - 1) / rate, which is nper when rate is 0
+    if rate == 0.0 {
+        nper
+    } else {
+        ((1.0 + rate).powf(nper) - 1.0) / rate
+    }
+}
+
+
+pub fn fn_pmt (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // PMT(rate, nper, pv, [fv], [type])
+    let rate = formulas::arg_num(&args[0])?;
+    let nper = formulas::arg_num(&args[1])?;
+    let pv = formulas::arg_num(&args[2])?;
+    let fv = opt_num(args, 3, 0.0)?;
+    let t = opt_type(args, 4)?;
+    if nper == 0.0 {
+        return Err(EvalErr::Num)
+    }
+    let growth = (1.0 + rate).powf(nper);
+    real(-(fv + pv * growth) / ((1.0 + rate * t) * annuity_factor(rate, nper)))
+}
+
+
+pub fn fn_pv (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // PV(rate, nper, pmt, [fv], [type])
+    let rate = formulas::arg_num(&args[0])?;
+    let nper = formulas::arg_num(&args[1])?;
+    let pmt = formulas::arg_num(&args[2])?;
+    let fv = opt_num(args, 3, 0.0)?;
+    let t = opt_type(args, 4)?;
+    let growth = (1.0 + rate).powf(nper);
+    real(-(fv + pmt * (1.0 + rate * t) * annuity_factor(rate, nper)) / growth)
+}
+
+
+pub fn fn_fv (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // FV(rate, nper, pmt, [pv], [type])
+    let rate = formulas::arg_num(&args[0])?;
+    let nper = formulas::arg_num(&args[1])?;
+    let pmt = formulas::arg_num(&args[2])?;
+    let pv = opt_num(args, 3, 0.0)?;
+    let t = opt_type(args, 4)?;
+    let growth = (1.0 + rate).powf(nper);
+    real(-(pv * growth + pmt * (1.0 + rate * t) * annuity_factor(rate, nper)))
+}
+
+
+pub fn fn_nper (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // NPER(rate, pmt, pv, [fv], [type])
+    let rate = formulas::arg_num(&args[0])?;
+    let pmt = formulas::arg_num(&args[1])?;
+    let pv = formulas::arg_num(&args[2])?;
+    let fv = opt_num(args, 3, 0.0)?;
+    let t = opt_type(args, 4)?;
+    if rate == 0.0 {
+        if pmt == 0.0 {
+            return Err(EvalErr::Num)
+        }
+        return real(-(pv + fv) / pmt)
+    }
+    // solve pv * (1 + rate)^n + pmt * (1 + rate * type) * ((1 + rate)^n - 1) / rate + fv = 0 for n
+    let pmt_t = pmt * (1.0 + rate * t);
+    let ratio = (pmt_t - fv * rate) / (pmt_t + pv * rate);
+    if ratio <= 0.0 || !ratio.is_finite() {
+        return Err(EvalErr::Num)
+    }
+    real(ratio.ln() / (1.0 + rate).ln())
+}
+
+
+pub fn fn_rate (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // RATE(nper, pmt, pv, [fv], [type], [guess])
+    let nper = formulas::arg_num(&args[0])?;
+    let pmt = formulas::arg_num(&args[1])?;
+    let pv = formulas::arg_num(&args[2])?;
+    let fv = opt_num(args, 3, 0.0)?;
+    let t = opt_type(args, 4)?;
+    let guess = opt_num(args, 5, 0.1)?;
+    // root of pv * (1 + r)^n + pmt * (1 + r * type) * ((1 + r)^n - 1) / r + fv
+    let f = |r: f64| {
+        let growth = (1.0 + r).powf(nper);
+        let d_growth = nper * (1.0 + r).powf(nper - 1.0);
+        if r == 0.0 {
+            // limits as r goes to 0
+            let y = pv + pmt * nper + fv;
+            let dy = pv * nper + pmt * (t * nper + nper * (nper - 1.0) / 2.0);
+            return (y, dy)
+        }
+        let af = (growth - 1.0) / r;
+        let d_af = (d_growth * r - (growth - 1.0)) / (r * r);
+        let y = pv * growth + pmt * (1.0 + r * t) * af + fv;
+        let dy = pv * d_growth + pmt * (t * af + (1.0 + r * t) * d_af);
+        (y, dy)
+    };
+    real(newton(f, guess)?)
+}
+
+
+pub fn fn_npv (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // NPV(rate, value1, [value2, ...])
+    // values are at the end of periods 1, 2, ... and can be ranges
+    let rate = formulas::arg_num(&args[0])?;
+    let mut total = 0.0;
+    let mut period = 1;
+    for arg in &args[1..] {
+        for val in formulas::arg_nums(arg)? {
+            total += val / (1.0 + rate).powi(period);
+            period += 1;
+        }
+    }
+    real(total)
+}
+
+
+fn check_signs (values: &[f64]) -> Result<(), EvalErr> {
+    // the internal rate of return is only defined if there is
+    // at least one positive and one negative cash flow
+    if values.iter().any(|v| *v > 0.0) && values.iter().any(|v| *v < 0.0) {
+        Ok(())
+    } else {
+        Err(EvalErr::Num)
+    }
+}
+
+
+pub fn fn_irr (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // IRR(values, [guess])
+    // values are at the start of periods 0, 1, ...
+    let values = formulas::arg_nums(&args[0])?;
+    let guess = opt_num(args, 1, 0.1)?;
+    check_signs(&values)?;
+    let f = |r: f64| {
+        let mut y = 0.0;
+        let mut dy = 0.0;
+        for (i, v) in values.iter().enumerate() {
+            let i = i as f64;
+            y += v / (1.0 + r).powf(i);
+            dy -= i * v / (1.0 + r).powf(i + 1.0);
+        }
+        (y, dy)
+    };
+    real(newton(f, guess)?)
+}
+
+
+fn cash_flow_years (args: &[EvalVal]) -> Result<(Vec<f64>, Vec<f64>), EvalErr> {
+    // values and dates for XNPV/XIRR, the dates get turned into the number
+    // of years (of 365 days) since the first date
+    let values = formulas::arg_nums(&args[0])?;
+    let dates = match &args[1] {
+        EvalVal::Grid(grid) => {
+            let mut dates = Vec::new();
+            for v in grid.vals.iter().flatten() {
+                dates.push(datetime::arg_date(&EvalVal::Val(v.clone()))?);
+            }
+            dates
+        },
+        val => vec![datetime::arg_date(val)?],
+    };
+    if values.len() != dates.len() || values.is_empty() {
+        return Err(EvalErr::Num)
+    }
+    let mut years = Vec::with_capacity(dates.len());
+    for date in &dates {
+        let days = dates[0].days_until(date);
+        if days < 0 {
+            // nothing can come before the first cash flow
+            return Err(EvalErr::Num)
+        }
+        years.push(days as f64 / 365.0);
+    }
+    Ok((values, years))
+}
+
+
+pub fn fn_xnpv (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // XNPV(rate, values, dates)
+    let rate = formulas::arg_num(&args[0])?;
+    if rate <= -1.0 {
+        return Err(EvalErr::Num)
+    }
+    let (values, years) = cash_flow_years(&args[1..])?;
+    let total: f64 = values.iter().zip(&years).map(|(v, y)| v / (1.0 + rate).powf(*y)).sum();
+    real(total)
+}
+
+
+pub fn fn_xirr (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // XIRR(values, dates, [guess])
+    let (values, years) = cash_flow_years(args)?;
+    let guess = opt_num(args, 2, 0.1)?;
+    check_signs(&values)?;
+    let f = |r: f64| {
+        let mut y = 0.0;
+        let mut dy = 0.0;
+        for (v, e) in values.iter().zip(&years) {
+            y += v / (1.0 + r).powf(*e);
+            dy -= e * v / (1.0 + r).powf(e + 1.0);
+        }
+        (y, dy)
+    };
+    real(newton(f, guess)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dtypes::CellGrid;
+
+    fn num (v: f64) -> EvalVal {
+        EvalVal::Val(CellVal::Real(v))
+    }
+
+    fn column (vals: Vec<CellVal>) -> EvalVal {
+        EvalVal::Grid(CellGrid { n_rows: vals.len(), n_cols: 1, vals: vals.into_iter().map(Some).collect() })
+    }
+
+    fn approx (res: Result<CellVal, EvalErr>, expected: f64, tol: f64) {
+        match res {
+            Ok(CellVal::Real(v)) => assert!((v - expected).abs() < tol, "expected {} got {}", expected, v),
+            other => panic!("expected Real({}) got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn annuity_functions () {
+        let sheet = sheet::Sheet::new();
+        approx(fn_pmt(&[num(0.08 / 12.0), num(10.0), num(10000.0)], &sheet), -1037.03, 0.01);
+        approx(fn_pmt(&[num(0.0), num(10.0), num(10000.0)], &sheet), -1000.0, 1e-9);
+        approx(fn_pv(&[num(0.08 / 12.0), num(240.0), num(500.0)], &sheet), -59777.15, 0.01);
+        approx(fn_fv(&[num(0.06 / 12.0), num(10.0), num(-200.0), num(-500.0), num(1.0)], &sheet), 2581.40, 0.01);
+        approx(fn_nper(&[num(0.01), num(-100.0), num(-1000.0), num(10000.0), num(1.0)], &sheet), 59.6739, 1e-4);
+        // payments smaller than the interest never pay off the loan
+        assert!(matches!(fn_nper(&[num(0.1), num(-50.0), num(1000.0)], &sheet), Err(EvalErr::Num)));
+    }
+
+    #[test]
+    fn rate_solver () {
+        let sheet = sheet::Sheet::new();
+        approx(fn_rate(&[num(48.0), num(-200.0), num(8000.0)], &sheet), 0.007701, 1e-6);
+        // RATE should invert PMT
+        approx(fn_rate(&[num(10.0), num(-1037.0320), num(10000.0)], &sheet), 0.08 / 12.0, 1e-7);
+        // no rate can make these payments work so it does not converge
+        assert!(matches!(fn_rate(&[num(10.0), num(100.0), num(100.0)], &sheet), Err(EvalErr::Num)));
+    }
+
+    #[test]
+    fn npv_irr () {
+        let sheet = sheet::Sheet::new();
+        let flows = column(vec![CellVal::Int(-10000), CellVal::Int(3000), CellVal::Int(4200), CellVal::Int(6800)]);
+        approx(fn_npv(&[num(0.1), flows], &sheet), 1188.44, 0.01);
+        approx(fn_npv(&[num(0.1), num(-10000.0), num(3000.0), num(4200.0), num(6800.0)], &sheet), 1188.44, 0.01);
+        let flows = column(vec![CellVal::Int(-70000), CellVal::Int(12000), CellVal::Int(15000),
+                                CellVal::Int(18000), CellVal::Int(21000), CellVal::Int(26000)]);
+        approx(fn_irr(&[flows], &sheet), 0.086631, 1e-6);
+        let flows = column(vec![CellVal::Int(100), CellVal::Int(200)]);
+        assert!(matches!(fn_irr(&[flows], &sheet), Err(EvalErr::Num)), "IRR needs positive and negative values");
+    }
+
+    #[test]
+    fn xnpv_xirr () {
+        let sheet = sheet::Sheet::new();
+        let flows = column(vec![CellVal::Int(-10000), CellVal::Int(2750), CellVal::Int(4250), CellVal::Int(3250), CellVal::Int(2750)]);
+        let dates = column(["2008-01-01", "2008-03-01", "2008-10-30", "2009-02-15", "2009-04-01"].iter()
+                           .map(|d| CellVal::Date(datetime::Date::parse(d).unwrap())).collect());
+        approx(fn_xnpv(&[num(0.09), flows.clone(), dates.clone()], &sheet), 2086.65, 0.01);
+        approx(fn_xirr(&[flows.clone(), dates], &sheet), 0.373363, 1e-6);
+        let short_dates = column(vec![CellVal::Date(datetime::Date::parse("2008-01-01").unwrap())]);
+        assert!(matches!(fn_xirr(&[flows, short_dates], &sheet), Err(EvalErr::Num)), "values and dates need to match up");
+    }
+}
@@ -3,7 +3,21 @@
 */
 
 
+use std::cmp::Ordering;
+use std::fmt;
+use std::rc::Rc;
+
 use crate::dtypes;
+use crate::dtypes::{CellVal, EvalErr, EvalVal};
+use crate::decimal;
+use crate::parsing;
+use crate::datetime;
+use crate::finance;
+use crate::arrays;
+use crate::math;
+use crate::text;
+use crate::random;
+use crate::sheet;
 
 
 #[derive(Debug)]
@@ -12,52 +26,1033 @@ pub struct TknTree {
 }
 
 
-impl TknTree {
-    fn new() -> Self {
-        TknTree { root: None}
+fn leaf (token: dtypes::FormToken) -> dtypes::TknNode {
+    dtypes::TknNode { token, left: None, right: None, args: Vec::new() }
+}
+
+
+struct Parser<'a> {
+    // tokens with the character offset each one starts at, the position
+    // of the next token and the length of the formula (for errors at the end)
+    tokens: &'a [(dtypes::FormToken, usize)],
+    pos: usize,
+    end: usize,
+}
+
+
+impl Parser<'_> {
+    fn peek (&self) -> Option<&dtypes::FormToken> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
     }
 
-    fn push(&mut self, token: dtypes::FormToken) {
-        let new_node = Box::new(dtypes::TknNode {
-            token,
-            left: self.root.take(),
-            right: None
-        });
-        self.root = dtypes::TknLink::Some(new_node);
+    fn error (&self, expected: &'static str) -> dtypes::ParseError {
+        // points at the next token, or just past the end if there is none
+        let offset = self.tokens.get(self.pos).map_or(self.end, |(_, offset)| *offset);
+        dtypes::ParseError { offset, expected }
     }
-}
 
-pub fn tokens_to_tree (tokens: &mut Vec<dtypes::FormToken>) -> TknTree {
-    let mut tree = TknTree::new();
-    let mut token_it = tokens.iter_mut();
-    // push the first token to the tree
-    tree.push(token_it.next().unwrap().clone());
-    while let Some(token) = token_it.next() {
+    fn parse_term (&mut self) -> Result<dtypes::TknNode, dtypes::ParseError> {
+        // a single operand: a literal, a function call or an expression in parentheses
+        let token = self.peek().ok_or(self.error("a value"))?.clone();
         match token {
-            dtypes::FormToken::BinOp(_op) => {
-                // push the binop then get the next token and set the right side to that
-                tree.push(token.clone());
-                let new_node = Box::new(dtypes::TknNode {
-                    token: token_it.next().unwrap().clone(),
-                    left: None,
-                    right: None,
-                });
-                let root = tree.root.as_mut().unwrap();
-                root.right = dtypes::TknLink::Some(new_node);
+            dtypes::FormToken::Num(_) | dtypes::FormToken::Loc(_) | dtypes::FormToken::Range(_, _) 
+            | dtypes::FormToken::Cols(_, _) | dtypes::FormToken::Rows(_, _)
+            | dtypes::FormToken::SpillRef(_) | dtypes::FormToken::Str(_) | dtypes::FormToken::Bool(_)
+            | dtypes::FormToken::Name(_) => {
+                self.pos += 1;
+                Ok(leaf(token))
+            },
+            dtypes::FormToken::Func(_) => {
+                // comma separated arguments up to the closing parenthesis
+                self.pos += 1;
+                let mut node = leaf(token);
+                if let Some(dtypes::FormToken::RParen) = self.peek() {
+                    self.pos += 1;
+                    return Ok(node)
+                }
+                loop {
+                    node.args.push(self.parse_expr()?);
+                    match self.peek() {
+                        Some(dtypes::FormToken::Comma) => self.pos += 1,
+                        Some(dtypes::FormToken::RParen) => {
+                            self.pos += 1;
+                            break;
+                        },
+                        _ => return Err(self.error("',' or ')'")),
+                    }
+                }
+                Ok(node)
+            },
+            dtypes::FormToken::BinOp(dtypes::Op::Minus) => {
+                // a minus where an operand should be is a unary minus
+                self.pos += 1;
+                let operand = self.parse_term()?;
+                let mut node = leaf(dtypes::FormToken::Neg);
+                node.left = Option::Some(Box::new(operand));
+                Ok(node)
+            },
+            dtypes::FormToken::BinOp(dtypes::Op::Plus) => {
+                // and a unary plus does nothing
+                self.pos += 1;
+                self.parse_term()
             },
-            _ => tree.push(token.clone())
-        } 
+            dtypes::FormToken::LParen => {
+                self.pos += 1;
+                let node = self.parse_expr()?;
+                match self.peek() {
+                    Some(dtypes::FormToken::RParen) => {
+                        self.pos += 1;
+                        Ok(node)
+                    },
+                    _ => Err(self.error("')'")),
+                }
+            },
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    fn parse_binexpr (&mut self, min_prec: u8) -> Result<dtypes::TknNode, dtypes::ParseError> {
+        // operands separated by binary operators (precedence climbing), only
+        // operators with at least min_prec precedence are taken, the operators 
+        // are left associative so each new operator becomes the root with the 
+        // tree built so far on its left and the right side only takes operators
+        // that bind more tightly than it
+        let mut node = self.parse_term()?;
+        while let Some(dtypes::FormToken::BinOp(op)) = self.peek() {
+            let op = op.clone();
+            let prec = precedence(&op);
+            if prec < min_prec {
+                break;
+            }
+            self.pos += 1;
+            let right = self.parse_binexpr(prec + 1)?;
+            node = dtypes::TknNode {
+                token: dtypes::FormToken::BinOp(op),
+                left: Option::Some(Box::new(node)),
+                right: Option::Some(Box::new(right)),
+                args: Vec::new(),
+            };
+        }
+        Ok(node)
+    }
+
+    fn parse_expr (&mut self) -> Result<dtypes::TknNode, dtypes::ParseError> {
+        self.parse_binexpr(1)
+    }
+}
+
+
+fn precedence (op: &dtypes::Op) -> u8 {
+    // operators with a higher precedence get applied first
+    match op {
+        dtypes::Op::Eq | dtypes::Op::Ne | dtypes::Op::Lt | dtypes::Op::Gt | dtypes::Op::Le | dtypes::Op::Ge => 1,
+        dtypes::Op::Concat => 2,
+        dtypes::Op::Plus | dtypes::Op::Minus => 3,
+        dtypes::Op::Mul | dtypes::Op::Div => 4,
+        dtypes::Op::Pow => 5,
+    }
+}
+
+
+pub fn tokens_to_tree (tokens: &[(dtypes::FormToken, usize)], end: usize) -> Result<TknTree, dtypes::ParseError> {
+    // tokens come with the character offset they start at and end is the 
+    // length of the formula, gives where and why if the tokens do not 
+    // make a valid expression
+    let mut parser = Parser { tokens, pos: 0, end };
+    let root = parser.parse_expr()?;
+    if parser.pos != tokens.len() {
+        // something left over (e.g. an unmatched closing parenthesis)
+        return Err(parser.error("an operator"))
+    }
+    Ok(TknTree { root: dtypes::TknLink::Some(Box::new(root)) })
+}
+
+
+fn op_str (op: &dtypes::Op) -> &'static str {
+    match op {
+        dtypes::Op::Plus => "+",
+        dtypes::Op::Minus => "-",
+        dtypes::Op::Mul => "*",
+        dtypes::Op::Div => "/",
+        dtypes::Op::Pow => "^",
+        dtypes::Op::Concat => "&",
+        dtypes::Op::Eq => "=",
+        dtypes::Op::Ne => "<>",
+        dtypes::Op::Lt => "<",
+        dtypes::Op::Gt => ">",
+        dtypes::Op::Le => "<=",
+        dtypes::Op::Ge => ">=",
+    }
+}
+
+
+fn print_operand (node: &dtypes::TknNode, min_prec: u8, out: &mut String) {
+    // an operand of a binary operator or unary minus only needs parentheses
+    // if it is a binary expression that binds more loosely than min_prec
+    match &node.token {
+        dtypes::FormToken::BinOp(op) if precedence(op) < min_prec => {
+            out.push('(');
+            print_node(node, out);
+            out.push(')');
+        },
+        _ => print_node(node, out),
+    }
+}
+
+
+fn print_node (node: &dtypes::TknNode, out: &mut String) {
+    match &node.token {
+        dtypes::FormToken::Num(CellVal::Real(val)) => {
+            // a whole number needs the point so it comes back as a Real
+            let num = val.to_string();
+            out.push_str(&num);
+            if !num.contains('.') {
+                out.push_str(".0");
+            }
+        },
+        dtypes::FormToken::Num(CellVal::Decimal(val)) => out.push_str(&format!("{}d", val)),
+        dtypes::FormToken::Num(CellVal::Int(val)) => out.push_str(&val.to_string()),
+        // numeric literals are only ever parsed as one of the above
+        dtypes::FormToken::Num(_) => {},
+        dtypes::FormToken::Loc(loc) => out.push_str(&format!("{}{}", loc.col, loc.row)),
+        dtypes::FormToken::Range(first, last) => out.push_str(&format!("{}{}:{}{}", first.col, first.row, last.col, last.row)),
+        dtypes::FormToken::Cols(first, last) => out.push_str(&format!("{}:{}", first, last)),
+        dtypes::FormToken::Rows(first, last) => out.push_str(&format!("{}:{}", first, last)),
+        dtypes::FormToken::SpillRef(loc) => out.push_str(&format!("{}{}#", loc.col, loc.row)),
+        dtypes::FormToken::Bool(val) => out.push_str(if *val { "TRUE" } else { "FALSE" }),
+        dtypes::FormToken::Str(text) => out.push_str(&format!("\"{}\"", text.replace('"', "\"\""))),
+        dtypes::FormToken::Name(name) => out.push_str(name),
+        dtypes::FormToken::Func(name) => {
+            out.push_str(name);
+            out.push('(');
+            for (i, arg) in node.args.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                print_node(arg, out);
+            }
+            out.push(')');
+        },
+        dtypes::FormToken::Neg => {
+            // unary minus applies to the term right after it so anything 
+            // with an operator in it needs parentheses
+            out.push('-');
+            if let Some(operand) = &node.left {
+                print_operand(operand, u8::MAX, out);
+            }
+        },
+        dtypes::FormToken::BinOp(op) => {
+            // operators are left associative, so an operand on the right with 
+            // the same precedence needs parentheses but one on the left does not
+            let prec = precedence(op);
+            if let Some(left) = &node.left {
+                print_operand(left, prec, out);
+            }
+            out.push_str(op_str(op));
+            if let Some(right) = &node.right {
+                print_operand(right, prec + 1, out);
+            }
+        },
+        dtypes::FormToken::LParen | dtypes::FormToken::RParen | dtypes::FormToken::Comma => {},
+    }
+}
+
+
+pub fn print_tree (tree: &TknTree) -> String {
+    // canonical text of a formula: upper case refs, names and functions, no 
+    // spaces and only the parentheses that are needed, parsing it again
+    // gives back the same tree
+    let mut out = String::from("=");
+    if let Some(root) = &tree.root {
+        print_node(root, &mut out);
+    }
+    out
+}
+
+
+pub fn print_expr (node: &dtypes::TknNode) -> String {
+    // canonical text of part of a formula (without the leading =)
+    let mut out = String::new();
+    print_node(node, &mut out);
+    out
+}
+
+
+pub fn node_label (node: &dtypes::TknNode) -> String {
+    // what a single node in the tree is, without its operands or arguments
+    match &node.token {
+        dtypes::FormToken::BinOp(op) => op_str(op).to_string(),
+        dtypes::FormToken::Neg => String::from("-"),
+        dtypes::FormToken::Func(name) => format!("{}()", name),
+        _ => print_expr(node),
+    }
+}
+
+
+// LET and LAMBDA calls can only be nested this deep (e.g. a defined name
+// that calls itself) before giving #NUM!
+pub const MAX_DEPTH: usize = 32;
+
+
+#[derive(Debug)]
+pub struct Lambda {
+    // LAMBDA(params, ..., body) that has been bound to a name
+    pub params: Vec<String>,
+    pub body: dtypes::TknNode,
+    // the names that were bound where the LAMBDA was made
+    pub scope: Scope,
+}
+
+
+#[derive(Debug, Clone)]
+pub enum Binding {
+    Val(EvalVal),
+    Lambda(Rc<Lambda>),
+}
+
+
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    // names bound by LET and LAMBDA parameters, the innermost ones last
+    bindings: Vec<(String, Binding)>,
+    // how many LET/LAMBDA calls deep the evaluation is
+    depth: usize,
+}
+
+
+impl Scope {
+    pub fn get (&self, name: &str) -> Option<&Binding> {
+        self.bindings.iter().rev().find(|(n, _)| n == name).map(|(_, b)| b)
+    }
+
+    pub fn bind (&mut self, name: String, binding: Binding) {
+        // a name bound again hides the earlier one
+        self.bindings.push((name, binding));
+    }
+
+    pub fn called_from (&self, caller: &Scope) -> Result<Scope, EvalErr> {
+        // the names in this scope (e.g. from where a LAMBDA was made)
+        // one level deeper than the scope it is being used from
+        if caller.depth >= MAX_DEPTH {
+            return Err(EvalErr::Num)
+        }
+        Ok(Scope { bindings: self.bindings.clone(), depth: caller.depth + 1 })
+    }
+}
+
+
+pub fn make_lambda (mut args: Vec<dtypes::TknNode>, scope: Scope) -> Result<Lambda, EvalErr> {
+    // LAMBDA(param, ..., body), the parameters have to be names
+    let body = args.pop().ok_or(EvalErr::Value)?;
+    let mut params = Vec::with_capacity(args.len());
+    for arg in args {
+        match arg.token {
+            dtypes::FormToken::Name(name) if !params.contains(&name) => params.push(name),
+            _ => return Err(EvalErr::Value),
+        }
+    }
+    Ok(Lambda { params, body, scope })
+}
+
+
+#[derive(Debug)]
+pub enum BuiltinFn {
+    // either way the function gets the already evaluated arguments (the number
+    // of which is already checked against min_args/max_args) and the sheet
+    // function that gives back a single value
+    Scalar(fn(&[EvalVal], &sheet::Sheet) -> Result<CellVal, EvalErr>),
+    // function that can give back a 2-D block of values that spills into the 
+    // cells below and to the right of the formula
+    Array(fn(&[EvalVal], &sheet::Sheet) -> Result<EvalVal, EvalErr>),
+}
+
+
+#[derive(Debug)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub min_args: usize,
+    pub max_args: usize,
+    pub func: BuiltinFn,
+    // gives a different value every time it is called (e.g. RAND or NOW),
+    // so formulas using it get evaluated again on every recalculation
+    pub volatile: bool,
+    // only goes through the values in its range arguments without caring
+    // where they are or about the empty cells (e.g. SUM), so it gets just 
+    // the cells that have something in them (see Sheet::range_values)
+    pub values_only: bool,
+}
+
+
+impl Builtin {
+    pub fn call (&self, args: &[EvalVal], sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+        match self.func {
+            BuiltinFn::Scalar(func) => Ok(EvalVal::Val(func(args, sheet)?)),
+            BuiltinFn::Array(func) => func(args, sheet),
+        }
+    }
+}
+
+
+pub trait Function: fmt::Debug {
+    // a formula function from outside of the engine, a program using the
+    // engine registers these with Sheet::register_function before evaluating 
+    // and then they can be called from formulas just like the builtins
+    // the name it is called by (case insensitive)
+    fn name (&self) -> &str;
+    // smallest and largest number of arguments it takes
+    fn arity (&self) -> (usize, usize);
+    // gets the already evaluated arguments (the number of which is already
+    // checked against arity) and read-only access to the sheet, it can give
+    // back a single value or a block of values that spills
+    fn evaluate (&self, args: &[EvalVal], sheet: &sheet::SheetView) -> Result<EvalVal, EvalErr>;
+    // whether it can give a different value for the same arguments (see Builtin)
+    fn volatile (&self) -> bool {
+        false
+    }
+}
+
+
+// all of the functions that can be called from formulas
+const BUILTINS: &[Builtin] = &[
+    // dates
+    Builtin { name: "DATE", min_args: 3, max_args: 3, func: BuiltinFn::Scalar(datetime::fn_date), volatile: false, values_only: false },
+    Builtin { name: "YEAR", min_args: 1, max_args: 1, func: BuiltinFn::Scalar(datetime::fn_year), volatile: false, values_only: false },
+    Builtin { name: "MONTH", min_args: 1, max_args: 1, func: BuiltinFn::Scalar(datetime::fn_month), volatile: false, values_only: false },
+    Builtin { name: "DAY", min_args: 1, max_args: 1, func: BuiltinFn::Scalar(datetime::fn_day), volatile: false, values_only: false },
+    Builtin { name: "WEEKDAY", min_args: 1, max_args: 2, func: BuiltinFn::Scalar(datetime::fn_weekday), volatile: false, values_only: false },
+    Builtin { name: "EDATE", min_args: 2, max_args: 2, func: BuiltinFn::Scalar(datetime::fn_edate), volatile: false, values_only: false },
+    Builtin { name: "EOMONTH", min_args: 2, max_args: 2, func: BuiltinFn::Scalar(datetime::fn_eomonth), volatile: false, values_only: false },
+    Builtin { name: "DATEDIF", min_args: 3, max_args: 3, func: BuiltinFn::Scalar(datetime::fn_datedif), volatile: false, values_only: false },
+    Builtin { name: "NETWORKDAYS", min_args: 2, max_args: usize::MAX, func: BuiltinFn::Scalar(datetime::fn_networkdays), volatile: false, values_only: false },
+    Builtin { name: "TODAY", min_args: 0, max_args: 0, func: BuiltinFn::Scalar(datetime::fn_today), volatile: true, values_only: false },
+    Builtin { name: "NOW", min_args: 0, max_args: 0, func: BuiltinFn::Scalar(datetime::fn_now), volatile: true, values_only: false },
+    // finance
+    Builtin { name: "PMT", min_args: 3, max_args: 5, func: BuiltinFn::Scalar(finance::fn_pmt), volatile: false, values_only: false },
+    Builtin { name: "PV", min_args: 3, max_args: 5, func: BuiltinFn::Scalar(finance::fn_pv), volatile: false, values_only: false },
+    Builtin { name: "FV", min_args: 3, max_args: 5, func: BuiltinFn::Scalar(finance::fn_fv), volatile: false, values_only: false },
+    Builtin { name: "NPER", min_args: 3, max_args: 5, func: BuiltinFn::Scalar(finance::fn_nper), volatile: false, values_only: false },
+    Builtin { name: "RATE", min_args: 3, max_args: 6, func: BuiltinFn::Scalar(finance::fn_rate), volatile: false, values_only: false },
+    Builtin { name: "NPV", min_args: 2, max_args: usize::MAX, func: BuiltinFn::Scalar(finance::fn_npv), volatile: false, values_only: true },
+    Builtin { name: "IRR", min_args: 1, max_args: 2, func: BuiltinFn::Scalar(finance::fn_irr), volatile: false, values_only: true },
+    Builtin { name: "XNPV", min_args: 3, max_args: 3, func: BuiltinFn::Scalar(finance::fn_xnpv), volatile: false, values_only: false },
+    Builtin { name: "XIRR", min_args: 2, max_args: 3, func: BuiltinFn::Scalar(finance::fn_xirr), volatile: false, values_only: false },
+    // dynamic arrays
+    Builtin { name: "SEQUENCE", min_args: 1, max_args: 4, func: BuiltinFn::Array(arrays::fn_sequence), volatile: false, values_only: false },
+    Builtin { name: "SORT", min_args: 1, max_args: 4, func: BuiltinFn::Array(arrays::fn_sort), volatile: false, values_only: false },
+    Builtin { name: "FILTER", min_args: 2, max_args: 3, func: BuiltinFn::Array(arrays::fn_filter), volatile: false, values_only: false },
+    Builtin { name: "UNIQUE", min_args: 1, max_args: 3, func: BuiltinFn::Array(arrays::fn_unique), volatile: false, values_only: false },
+    Builtin { name: "TRANSPOSE", min_args: 1, max_args: 1, func: BuiltinFn::Array(arrays::fn_transpose), volatile: false, values_only: false },
+    // math
+    Builtin { name: "SUM", min_args: 1, max_args: 255, func: BuiltinFn::Scalar(math::fn_sum), volatile: false, values_only: true },
+    Builtin { name: "MMULT", min_args: 2, max_args: 2, func: BuiltinFn::Array(math::fn_mmult), volatile: false, values_only: false },
+    Builtin { name: "MINVERSE", min_args: 1, max_args: 1, func: BuiltinFn::Array(math::fn_minverse), volatile: false, values_only: false },
+    Builtin { name: "MDETERM", min_args: 1, max_args: 1, func: BuiltinFn::Scalar(math::fn_mdeterm), volatile: false, values_only: false },
+    // text patterns
+    Builtin { name: "REGEXMATCH", min_args: 2, max_args: 2, func: BuiltinFn::Scalar(text::fn_regexmatch), volatile: false, values_only: false },
+    Builtin { name: "REGEXEXTRACT", min_args: 2, max_args: 2, func: BuiltinFn::Array(text::fn_regexextract), volatile: false, values_only: false },
+    Builtin { name: "REGEXREPLACE", min_args: 3, max_args: 3, func: BuiltinFn::Scalar(text::fn_regexreplace), volatile: false, values_only: false },
+    Builtin { name: "SEARCH", min_args: 2, max_args: 3, func: BuiltinFn::Scalar(text::fn_search), volatile: false, values_only: false },
+    // random numbers
+    Builtin { name: "RAND", min_args: 0, max_args: 0, func: BuiltinFn::Scalar(random::fn_rand), volatile: true, values_only: false },
+    Builtin { name: "RANDBETWEEN", min_args: 2, max_args: 2, func: BuiltinFn::Scalar(random::fn_randbetween), volatile: true, values_only: false },
+    Builtin { name: "RANDARRAY", min_args: 0, max_args: 5, func: BuiltinFn::Array(random::fn_randarray), volatile: true, values_only: false },
+];
+
+
+pub fn find_builtin (name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+
+pub fn scalar (val: &EvalVal) -> Result<&CellVal, EvalErr> {
+    // single value out of an evaluated argument/operand,
+    // a range is only ok if it is a single (non-empty) cell
+    match val {
+        EvalVal::Val(v) => Ok(v),
+        EvalVal::Grid(grid) => {
+            match (grid.n_rows, grid.n_cols, grid.vals.first()) {
+                (1, 1, Some(Some(v))) => Ok(v),
+                _ => Err(EvalErr::Value),
+            }
+        },
+    }
+}
+
+
+pub fn arg_int (val: &EvalVal) -> Result<i64, EvalErr> {
+    // integer function argument, non-integer numbers are truncated
+    match scalar(val)? {
+        CellVal::Int(v) => Ok(*v),
+        CellVal::Real(v) => Ok(v.trunc() as i64),
+        CellVal::Decimal(v) => Ok(v.to_f64().trunc() as i64),
+        _ => Err(EvalErr::Value),
+    }
+}
+
+
+pub fn arg_num (val: &EvalVal) -> Result<f64, EvalErr> {
+    // numeric function argument
+    match scalar(val)? {
+        CellVal::Int(v) => Ok(*v as f64),
+        CellVal::Real(v) => Ok(*v),
+        CellVal::Decimal(v) => Ok(v.to_f64()),
+        _ => Err(EvalErr::Value),
+    }
+}
+
+
+pub fn arg_text (val: &EvalVal) -> Result<&str, EvalErr> {
+    // text function argument
+    match scalar(val)? {
+        CellVal::Text(t) => Ok(t),
+        _ => Err(EvalErr::Value),
+    }
+}
+
+
+pub fn arg_bool (val: &EvalVal) -> Result<bool, EvalErr> {
+    // TRUE/FALSE function argument, numbers are true unless they are zero
+    match scalar(val)? {
+        CellVal::Bool(v) => Ok(*v),
+        _ => Ok(arg_num(val)? != 0.0),
+    }
+}
+
+
+pub fn arg_nums (val: &EvalVal) -> Result<Vec<f64>, EvalErr> {
+    // all of the numbers in a function argument, for a range anything that
+    // is not a number (empty cells, text, ...) is skipped but a single 
+    // value has to be a number
+    match val {
+        EvalVal::Val(_) => Ok(vec![arg_num(val)?]),
+        EvalVal::Grid(grid) => {
+            let mut nums = Vec::new();
+            for v in grid.vals.iter().flatten() {
+                match v {
+                    CellVal::Int(v) => nums.push(*v as f64),
+                    CellVal::Real(v) => nums.push(*v),
+                    CellVal::Decimal(v) => nums.push(v.to_f64()),
+                    _ => {},
+                }
+            }
+            Ok(nums)
+        },
+    }
+}
+
+
+fn apply_date_binop (op: &dtypes::Op, left: &CellVal, right: &CellVal) -> Option<CellVal> {
+    // arithmetic involving dates, date-times and durations
+    // Ints added to or subtracted from a date are a number of days
+    // Date - Date (or DateTime) gives the Duration between them
+    // adding a Duration to a Date gives a DateTime unless it is a whole number of days
+    use dtypes::Op::{Plus, Minus};
+    let sign = |op: &dtypes::Op| if let Minus = op { -1 } else { 1 };
+    match (op, left, right) {
+        (Plus | Minus, CellVal::Date(d), CellVal::Int(n)) => d.add_days(sign(op) * n).map(CellVal::Date),
+        (Plus, CellVal::Int(n), CellVal::Date(d)) => d.add_days(*n).map(CellVal::Date),
+        (Plus | Minus, CellVal::DateTime(dt), CellVal::Int(n)) => dt.add_secs(sign(op) * n.checked_mul(86_400)?).map(CellVal::DateTime),
+        (Plus, CellVal::Int(n), CellVal::DateTime(dt)) => dt.add_secs(n.checked_mul(86_400)?).map(CellVal::DateTime),
+        (Plus | Minus, CellVal::Date(d), CellVal::Duration(dur)) => {
+            match dur.whole_days() {
+                Some(n) => d.add_days(sign(op) * n).map(CellVal::Date),
+                None => d.at_midnight().add_secs(sign(op) * dur.secs()).map(CellVal::DateTime),
+            }
+        },
+        (Plus, CellVal::Duration(_), CellVal::Date(_)) => apply_date_binop(op, right, left),
+        (Plus | Minus, CellVal::DateTime(dt), CellVal::Duration(dur)) => dt.add_secs(sign(op) * dur.secs()).map(CellVal::DateTime),
+        (Plus, CellVal::Duration(_), CellVal::DateTime(_)) => apply_date_binop(op, right, left),
+        (Minus, CellVal::Date(l), CellVal::Date(r)) => Option::Some(CellVal::Duration(datetime::Duration::from_secs(r.days_until(l) * 86_400))),
+        (Minus, CellVal::DateTime(l), CellVal::DateTime(r)) => Option::Some(CellVal::Duration(datetime::Duration::from_secs(r.secs_until(l)))),
+        (Minus, CellVal::Date(l), CellVal::DateTime(_)) => apply_date_binop(op, &CellVal::DateTime(l.at_midnight()), right),
+        (Minus, CellVal::DateTime(_), CellVal::Date(r)) => apply_date_binop(op, left, &CellVal::DateTime(r.at_midnight())),
+        (Plus | Minus, CellVal::Duration(l), CellVal::Duration(r)) => {
+            let secs = if let Minus = op { l.secs().checked_sub(r.secs()) } else { l.secs().checked_add(r.secs()) };
+            Option::Some(CellVal::Duration(datetime::Duration::from_secs(secs?)))
+        },
+        _ => Option::None,
+    }
+}
+
+
+fn apply_real_binop (op: &dtypes::Op, l: f64, r: f64) -> Result<CellVal, EvalErr> {
+    let res = match op {
+        dtypes::Op::Plus => l + r,
+        dtypes::Op::Minus => l - r,
+        dtypes::Op::Mul => l * r,
+        dtypes::Op::Div => {
+            if r == 0.0 {
+                return Err(EvalErr::Div0)
+            }
+            l / r
+        },
+        dtypes::Op::Pow => l.powf(r),
+        // comparisons are handled in apply_binop
+        _ => return Err(EvalErr::Value),
+    };
+    if res.is_finite() {
+        Ok(CellVal::Real(res))
+    } else {
+        // e.g. overflow or a fractional power of a negative number
+        Err(EvalErr::Num)
+    }
+}
+
+
+fn apply_int_binop (op: &dtypes::Op, l: i64, r: i64) -> Result<CellVal, EvalErr> {
+    // Int (op) Int stays an Int as long as the result fits in an i64 (and
+    // for division only if it comes out even), otherwise falls back to a Real
+    let res = match op {
+        dtypes::Op::Plus => l.checked_add(r),
+        dtypes::Op::Minus => l.checked_sub(r),
+        dtypes::Op::Mul => l.checked_mul(r),
+        dtypes::Op::Div => {
+            if r == 0 {
+                return Err(EvalErr::Div0)
+            }
+            match l.checked_rem(r) { Some(0) => l.checked_div(r), _ => Option::None }
+        },
+        dtypes::Op::Pow => u32::try_from(r).ok().and_then(|r| l.checked_pow(r)),
+        _ => Option::None,
+    };
+    match res {
+        Some(v) => Ok(CellVal::Int(v)),
+        None => apply_real_binop(op, l as f64, r as f64),
+    }
+}
+
+
+fn apply_decimal_binop (op: &dtypes::Op, l: decimal::Decimal, r: decimal::Decimal) -> Result<CellVal, EvalErr> {
+    // Decimal (op) Decimal stays an exact Decimal, falling back to a Real 
+    // if the result does not fit, powers are always computed as Reals
+    let res = match op {
+        dtypes::Op::Plus => l.checked_add(&r),
+        dtypes::Op::Minus => l.checked_sub(&r),
+        dtypes::Op::Mul => l.checked_mul(&r),
+        dtypes::Op::Div => {
+            if r.to_f64() == 0.0 {
+                return Err(EvalErr::Div0)
+            }
+            l.checked_div(&r)
+        },
+        _ => Option::None,
+    };
+    match res {
+        Some(v) => Ok(CellVal::Decimal(v)),
+        None => apply_real_binop(op, l.to_f64(), r.to_f64()),
+    }
+}
+
+
+pub fn negate (val: CellVal) -> Option<CellVal> {
+    // unary minus, same types as apply_binop (Int overflow falls back to Real)
+    match val {
+        CellVal::Bool(v) => Option::Some(CellVal::Int(-(v as i64))),
+        CellVal::Int(v) => match v.checked_neg() {
+            Some(v) => Option::Some(CellVal::Int(v)),
+            None => Option::Some(CellVal::Real(-(v as f64))),
+        },
+        CellVal::Real(v) => Option::Some(CellVal::Real(-v)),
+        CellVal::Decimal(v) => apply_binop(&dtypes::Op::Minus, CellVal::Int(0), CellVal::Decimal(v)).ok(),
+        CellVal::Duration(v) => Option::Some(CellVal::Duration(datetime::Duration::from_secs(v.secs().checked_neg()?))),
+        _ => Option::None,
+    }
+}
+
+
+fn concat_text (val: &CellVal) -> Option<String> {
+    // what a value looks like when it is joined with & (dates and 
+    // durations have no text form)
+    match val {
+        CellVal::Text(text) => Option::Some(text.clone()),
+        CellVal::Int(v) => Option::Some(v.to_string()),
+        CellVal::Real(v) => Option::Some(v.to_string()),
+        CellVal::Decimal(v) => Option::Some(v.to_string()),
+        CellVal::Bool(v) => Option::Some(String::from(if *v { "TRUE" } else { "FALSE" })),
+        _ => Option::None,
+    }
+}
+
+
+pub fn apply_binop (op: &dtypes::Op, left: CellVal, right: CellVal) -> Result<CellVal, EvalErr> {
+    // apply a binary operator to two evaluated operands
+    // Int and Decimal stay exact (see apply_int_binop/apply_decimal_binop),
+    // an Int mixed with a Decimal is treated as a Decimal
+    // anything mixed with a Real is computed as a Real
+    // dates, date-times and durations are handled by apply_date_binop
+    // comparisons give a Bool (see compare_op), in arithmetic TRUE and
+    // FALSE are just 1 and 0, & joins the text of both sides
+    // anything else is the wrong type of value
+    let ord = || compare_op(&left, &right);
+    let test = match op {
+        dtypes::Op::Eq => Option::Some(ord().is_some_and(Ordering::is_eq)),
+        dtypes::Op::Ne => Option::Some(!ord().is_some_and(Ordering::is_eq)),
+        dtypes::Op::Lt => Option::Some(ord().is_some_and(Ordering::is_lt)),
+        dtypes::Op::Gt => Option::Some(ord().is_some_and(Ordering::is_gt)),
+        dtypes::Op::Le => Option::Some(ord().is_some_and(Ordering::is_le)),
+        dtypes::Op::Ge => Option::Some(ord().is_some_and(Ordering::is_ge)),
+        _ => Option::None,
+    };
+    if let Some(res) = test {
+        return Ok(CellVal::Bool(res))
+    }
+    if let dtypes::Op::Concat = op {
+        let (left, right) = (concat_text(&left).ok_or(EvalErr::Value)?, concat_text(&right).ok_or(EvalErr::Value)?);
+        return Ok(CellVal::Text(left + &right))
+    }
+    let bool_to_int = |val| match val {
+        CellVal::Bool(v) => CellVal::Int(v as i64),
+        val => val,
+    };
+    match (bool_to_int(left), bool_to_int(right)) {
+        (CellVal::Int(l), CellVal::Int(r)) => apply_int_binop(op, l, r),
+        (CellVal::Decimal(l), CellVal::Decimal(r)) => apply_decimal_binop(op, l, r),
+        (CellVal::Decimal(l), CellVal::Int(r)) => {
+            match decimal::Decimal::from_i64(r) {
+                Some(r) => apply_decimal_binop(op, l, r),
+                None => apply_real_binop(op, l.to_f64(), r as f64),
+            }
+        },
+        (CellVal::Int(l), CellVal::Decimal(r)) => {
+            match decimal::Decimal::from_i64(l) {
+                Some(l) => apply_decimal_binop(op, l, r),
+                None => apply_real_binop(op, l as f64, r.to_f64()),
+            }
+        },
+        (CellVal::Int(l), CellVal::Real(r)) => apply_real_binop(op, l as f64, r),
+        (CellVal::Real(l), CellVal::Int(r)) => apply_real_binop(op, l, r as f64),
+        (CellVal::Decimal(l), CellVal::Real(r)) => apply_real_binop(op, l.to_f64(), r),
+        (CellVal::Real(l), CellVal::Decimal(r)) => apply_real_binop(op, l, r.to_f64()),
+        (CellVal::Real(l), CellVal::Real(r)) => apply_real_binop(op, l, r),
+        (left, right) => apply_date_binop(op, &left, &right).ok_or(EvalErr::Value),
+    }
+}
+
+
+
+fn num_f64 (val: &CellVal) -> Option<f64> {
+    match val {
+        CellVal::Int(v) => Option::Some(*v as f64),
+        CellVal::Real(v) => Option::Some(*v),
+        CellVal::Decimal(v) => Option::Some(v.to_f64()),
+        _ => Option::None,
+    }
+}
+
+
+fn kind_rank (val: &CellVal) -> u8 {
+    // order of the different kinds of values relative to each other
+    match val {
+        CellVal::Int(_) | CellVal::Real(_) | CellVal::Decimal(_) => 0,
+        CellVal::Date(_) | CellVal::DateTime(_) => 1,
+        CellVal::Duration(_) => 2,
+        CellVal::Text(_) | CellVal::Formula(_) => 3,
+        CellVal::Bool(_) => 4,
+    }
+}
+
+
+pub fn compare_vals (left: &CellVal, right: &CellVal) -> Ordering {
+    // ordering used by the comparison operators and for sorting
+    // numbers are compared by value (exactly for Int and Decimal), dates and 
+    // date-times by when they are, text ignoring case and FALSE < TRUE
+    // different kinds of values are never equal, numbers come before 
+    // dates, then durations, then text, then TRUE/FALSE
+    match (left, right) {
+        (CellVal::Int(l), CellVal::Int(r)) => l.cmp(r),
+        (CellVal::Decimal(l), CellVal::Decimal(r)) => l.compare(r),
+        (CellVal::Decimal(l), CellVal::Int(r)) => {
+            match decimal::Decimal::from_i64(*r) {
+                Some(r) => l.compare(&r),
+                None => l.to_f64().total_cmp(&(*r as f64)),
+            }
+        },
+        (CellVal::Int(_), CellVal::Decimal(_)) => compare_vals(right, left).reverse(),
+        (CellVal::Date(l), CellVal::Date(r)) => 0.cmp(&l.days_until(r)),
+        (CellVal::DateTime(l), CellVal::DateTime(r)) => 0.cmp(&l.secs_until(r)),
+        (CellVal::Date(l), CellVal::DateTime(_)) => compare_vals(&CellVal::DateTime(l.at_midnight()), right),
+        (CellVal::DateTime(_), CellVal::Date(r)) => compare_vals(left, &CellVal::DateTime(r.at_midnight())),
+        (CellVal::Duration(l), CellVal::Duration(r)) => l.secs().cmp(&r.secs()),
+        (CellVal::Text(l), CellVal::Text(r)) => l.to_lowercase().cmp(&r.to_lowercase()),
+        (CellVal::Bool(l), CellVal::Bool(r)) => l.cmp(r),
+        _ => match (num_f64(left), num_f64(right)) {
+            (Some(l), Some(r)) => l.total_cmp(&r),
+            _ => kind_rank(left).cmp(&kind_rank(right)),
+        },
+    }
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Coercion {
+    // what operators do with values that are not numbers (see coerce)
+    // text that looks like a number is that number, TRUE/FALSE are 1/0 
+    // and empty cells 0 in arithmetic
+    #[default]
+    Lenient,
+    // anything but numbers, dates and durations in arithmetic is #VALUE!
+    Strict,
+}
+
+
+fn compare_op (left: &CellVal, right: &CellVal) -> Option<Ordering> {
+    // compare_vals for the comparison operators, where a Real is compared
+    // as a number should be: -0.0 is equal to 0 and NaN is not equal to 
+    // (or less or more than) anything, None in that case
+    // (compare_vals has to give every pair an order for sorting)
+    let is_real = |v: &CellVal| matches!(v, CellVal::Real(_));
+    match (num_f64(left), num_f64(right)) {
+        (Some(l), Some(r)) if is_real(left) || is_real(right) => l.partial_cmp(&r),
+        _ => Option::Some(compare_vals(left, right)),
+    }
+}
+
+
+fn empty_like (other: Option<&CellVal>) -> CellVal {
+    // what an empty cell counts as when it is compared to something
+    match other {
+        Some(CellVal::Text(_)) => CellVal::Text(String::new()),
+        Some(CellVal::Bool(_)) => CellVal::Bool(false),
+        _ => CellVal::Int(0),
+    }
+}
+
+
+fn text_to_number (text: &str) -> Option<CellVal> {
+    // text that would be a number (or date, date-time or duration) if it 
+    // was typed into a cell, spaces around it are ignored
+    match parsing::parse_val(text.trim()) {
+        val @ (CellVal::Int(_) | CellVal::Real(_) | CellVal::Decimal(_) 
+            | CellVal::Date(_) | CellVal::DateTime(_) | CellVal::Duration(_)) => Option::Some(val),
+        _ => Option::None,
+    }
+}
+
+
+pub fn coerce (op: &dtypes::Op, val: Option<&CellVal>, other: Option<&CellVal>, coercion: Coercion) -> Result<CellVal, EvalErr> {
+    // the value an operand (None for an empty cell) counts as for an operator,
+    // other is the operand on the other side, negation is arithmetic
+    // in comparisons an empty cell is 0, "" or FALSE (whichever matches the
+    // other side) and everything else stays as it is, joined with & an empty
+    // cell is "", in arithmetic see Coercion
+    let is_comparison = matches!(op, dtypes::Op::Eq | dtypes::Op::Ne | dtypes::Op::Lt | dtypes::Op::Gt | dtypes::Op::Le | dtypes::Op::Ge);
+    if is_comparison {
+        return Ok(val.cloned().unwrap_or_else(|| empty_like(other)))
+    }
+    if let dtypes::Op::Concat = op {
+        return Ok(val.cloned().unwrap_or_else(|| CellVal::Text(String::new())))
+    }
+    match (val, coercion) {
+        (None | Some(CellVal::Text(_) | CellVal::Bool(_)), Coercion::Strict) => Err(EvalErr::Value),
+        (None, Coercion::Lenient) => Ok(CellVal::Int(0)),
+        (Some(CellVal::Bool(v)), Coercion::Lenient) => Ok(CellVal::Int(*v as i64)),
+        (Some(CellVal::Text(text)), Coercion::Lenient) => text_to_number(text).ok_or(EvalErr::Value),
+        (Some(val), _) => Ok(val.clone()),
+    }
+}
+
+
+fn grid_elem (val: Option<&EvalVal>, row: usize, col: usize) -> Option<&CellVal> {
+    // element of a value that is being broadcast to a bigger shape, a single 
+    // value is the same everywhere and a single row/column gets repeated
+    match val? {
+        EvalVal::Val(v) => Option::Some(v),
+        EvalVal::Grid(grid) => {
+            let row = if grid.n_rows == 1 { 0 } else { row };
+            let col = if grid.n_cols == 1 { 0 } else { col };
+            grid.vals[row * grid.n_cols + col].as_ref()
+        },
+    }
+}
+
+
+fn grid_dims (val: Option<&EvalVal>) -> (usize, usize) {
+    match val {
+        Some(EvalVal::Grid(grid)) => (grid.n_rows, grid.n_cols),
+        _ => (1, 1),
+    }
+}
+
+
+pub fn apply_binop_vals (op: &dtypes::Op, left: Option<EvalVal>, right: Option<EvalVal>, coercion: Coercion) -> Result<EvalVal, EvalErr> {
+    // apply_binop but for operands that may be blocks of values (or None for
+    // a single empty cell), the operator is applied element by element and 
+    // the result is a block of values too
+    // the shapes have to match except that a single value, row or column is 
+    // repeated to fill out the other one
+    // every pair of values goes through coerce first
+    let (left, right) = (left.as_ref(), right.as_ref());
+    let apply = |l: Option<&CellVal>, r: Option<&CellVal>| apply_binop(op, coerce(op, l, r, coercion)?, coerce(op, r, l, coercion)?);
+    if !matches!(left, Some(EvalVal::Grid(_))) && !matches!(right, Some(EvalVal::Grid(_))) {
+        return Ok(EvalVal::Val(apply(grid_elem(left, 0, 0), grid_elem(right, 0, 0))?))
+    }
+    let fit = |l: usize, r: usize| match (l, r) {
+        (l, r) if l == r => Ok(l),
+        (1, n) | (n, 1) => Ok(n),
+        _ => Err(EvalErr::Value),
+    };
+    let ((l_rows, l_cols), (r_rows, r_cols)) = (grid_dims(left), grid_dims(right));
+    let n_rows = fit(l_rows, r_rows)?;
+    let n_cols = fit(l_cols, r_cols)?;
+    let mut vals = Vec::with_capacity(n_rows * n_cols);
+    for i in 0..n_rows {
+        for j in 0..n_cols {
+            vals.push(Option::Some(apply(grid_elem(left, i, j), grid_elem(right, i, j))?));
+        }
+    }
+    Ok(EvalVal::Grid(dtypes::CellGrid { n_rows, n_cols, vals }))
+}
+
+
+pub fn negate_vals (val: Option<EvalVal>, coercion: Coercion) -> Result<EvalVal, EvalErr> {
+    // negate but for a value that may be a block of values (or None for a 
+    // single empty cell), each value goes through coerce first
+    let neg = |v: Option<&CellVal>| negate(coerce(&dtypes::Op::Minus, v, Option::None, coercion)?).ok_or(EvalErr::Value);
+    match val {
+        Some(EvalVal::Grid(mut grid)) => {
+            for v in grid.vals.iter_mut() {
+                *v = Option::Some(neg(v.as_ref())?);
+            }
+            Ok(EvalVal::Grid(grid))
+        },
+        Some(EvalVal::Val(v)) => Ok(EvalVal::Val(neg(Option::Some(&v))?)),
+        None => Ok(EvalVal::Val(neg(Option::None)?)),
     }
-    tree
 }
 
 
-/* 
 #[cfg(test)]
 mod tests {
     use super::*;
-    
 
-    
+    #[test]
+    fn apply_binop_int_stays_int () {
+        let res = apply_binop(&dtypes::Op::Plus, CellVal::Int(3_000_000_000), CellVal::Int(1));
+        assert!(matches!(res, Ok(CellVal::Int(3_000_000_001))), "Int + Int should stay an Int");
+        let res = apply_binop(&dtypes::Op::Minus, CellVal::Int(1), CellVal::Int(3));
+        assert!(matches!(res, Ok(CellVal::Int(-2))), "Int - Int should stay an Int");
+    }
+
+    #[test]
+    fn apply_binop_int_overflow_falls_back_to_real () {
+        let res = apply_binop(&dtypes::Op::Plus, CellVal::Int(i64::MAX), CellVal::Int(1));
+        assert!(matches!(res, Ok(CellVal::Real(_))), "overflowing Int + Int should become a Real");
+    }
+
+    #[test]
+    fn apply_binop_decimal_exact () {
+        let l = CellVal::Decimal(decimal::Decimal::parse("0.1").unwrap());
+        let r = CellVal::Decimal(decimal::Decimal::parse("0.2").unwrap());
+        let res = apply_binop(&dtypes::Op::Plus, l.clone(), r);
+        assert!(matches!(&res, Ok(CellVal::Decimal(d)) if d.to_string() == "0.3"), "0.1 + 0.2 should be exactly 0.3");
+        let res = apply_binop(&dtypes::Op::Minus, l.clone(), CellVal::Int(2));
+        assert!(matches!(&res, Ok(CellVal::Decimal(d)) if d.to_string() == "-1.9"), "Decimal - Int should stay a Decimal");
+        let res = apply_binop(&dtypes::Op::Plus, l, CellVal::Real(0.5));
+        assert!(matches!(res, Ok(CellVal::Real(_))), "Decimal + Real should be a Real");
+    }
+
+    #[test]
+    fn apply_binop_mixed_and_text () {
+        let res = apply_binop(&dtypes::Op::Plus, CellVal::Int(1), CellVal::Real(0.5));
+        assert!(matches!(res, Ok(CellVal::Real(v)) if v == 1.5), "Int + Real should be a Real");
+        let res = apply_binop(&dtypes::Op::Plus, CellVal::Int(1), CellVal::Text(String::from("a")));
+        assert!(matches!(res, Err(EvalErr::Value)), "Int + Text should be the wrong type of value");
+    }
+
+    #[test]
+    fn apply_binop_concat () {
+        let res = apply_binop(&dtypes::Op::Concat, CellVal::Text(String::from("B")), CellVal::Int(7));
+        assert!(matches!(res, Ok(CellVal::Text(t)) if t == "B7"), "Text & Int should be joined text");
+        let res = apply_binop(&dtypes::Op::Concat, CellVal::Real(1.5), CellVal::Bool(true));
+        assert!(matches!(res, Ok(CellVal::Text(t)) if t == "1.5TRUE"), "Real & Bool should be joined text");
+    }
+
+    #[test]
+    fn apply_binop_mul_div_pow () {
+        let res = apply_binop(&dtypes::Op::Mul, CellVal::Int(6), CellVal::Int(7));
+        assert!(matches!(res, Ok(CellVal::Int(42))), "Int * Int should stay an Int");
+        let res = apply_binop(&dtypes::Op::Div, CellVal::Int(42), CellVal::Int(7));
+        assert!(matches!(res, Ok(CellVal::Int(6))), "even Int / Int should stay an Int");
+        let res = apply_binop(&dtypes::Op::Div, CellVal::Int(7), CellVal::Int(2));
+        assert!(matches!(res, Ok(CellVal::Real(v)) if v == 3.5), "uneven Int / Int should be a Real");
+        let res = apply_binop(&dtypes::Op::Div, CellVal::Int(7), CellVal::Int(0));
+        assert!(matches!(res, Err(EvalErr::Div0)), "dividing by zero should be an error");
+        let res = apply_binop(&dtypes::Op::Pow, CellVal::Int(2), CellVal::Int(62));
+        assert!(matches!(res, Ok(CellVal::Int(4_611_686_018_427_387_904))), "Int ^ Int should stay an Int");
+        let res = apply_binop(&dtypes::Op::Pow, CellVal::Int(2), CellVal::Int(-1));
+        assert!(matches!(res, Ok(CellVal::Real(v)) if v == 0.5), "Int ^ negative Int should be a Real");
+        let res = apply_binop(&dtypes::Op::Pow, CellVal::Real(-8.0), CellVal::Real(0.5));
+        assert!(matches!(res, Err(EvalErr::Num)), "fractional power of a negative number should be an error");
+        let l = CellVal::Decimal(decimal::Decimal::parse("10.00").unwrap());
+        let res = apply_binop(&dtypes::Op::Div, l.clone(), CellVal::Int(4));
+        assert!(matches!(&res, Ok(CellVal::Decimal(d)) if d.to_string() == "2.50"), "Decimal / Int should stay a Decimal");
+        let res = apply_binop(&dtypes::Op::Mul, l, CellVal::Decimal(decimal::Decimal::parse("0.07").unwrap()));
+        assert!(matches!(&res, Ok(CellVal::Decimal(d)) if d.to_string() == "0.7000"), "Decimal * Decimal should stay a Decimal");
+    }
+
+    #[test]
+    fn apply_binop_compare () {
+        let res = apply_binop(&dtypes::Op::Lt, CellVal::Int(1), CellVal::Real(1.5));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "1 < 1.5");
+        let l = CellVal::Decimal(decimal::Decimal::parse("0.10").unwrap());
+        let res = apply_binop(&dtypes::Op::Eq, l, CellVal::Decimal(decimal::Decimal::parse("0.1").unwrap()));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "0.10 = 0.1 exactly");
+        let res = apply_binop(&dtypes::Op::Eq, CellVal::Text(String::from("abc")), CellVal::Text(String::from("ABC")));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "text compares ignoring case");
+        let res = apply_binop(&dtypes::Op::Ge, CellVal::Text(String::from("1")), CellVal::Int(5));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "text comes after numbers");
+        let res = apply_binop(&dtypes::Op::Ne, CellVal::Int(0), CellVal::Bool(false));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "numbers and bools are never equal");
+        let res = apply_binop(&dtypes::Op::Mul, CellVal::Bool(true), CellVal::Int(3));
+        assert!(matches!(res, Ok(CellVal::Int(3))), "TRUE is 1 in arithmetic");
+        let res = apply_binop(&dtypes::Op::Eq, CellVal::Real(-0.0), CellVal::Int(0));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "-0.0 = 0");
+        let res = apply_binop(&dtypes::Op::Le, CellVal::Real(-0.0), CellVal::Real(0.0));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "-0.0 <= 0.0");
+        for op in [dtypes::Op::Eq, dtypes::Op::Lt, dtypes::Op::Ge] {
+            let res = apply_binop(&op, CellVal::Real(f64::NAN), CellVal::Real(f64::NAN));
+            assert!(matches!(res, Ok(CellVal::Bool(false))), "NaN {:?} NaN", op);
+        }
+        let res = apply_binop(&dtypes::Op::Ne, CellVal::Real(f64::NAN), CellVal::Int(1));
+        assert!(matches!(res, Ok(CellVal::Bool(true))), "NaN <> 1");
+    }
+
+    #[test]
+    fn apply_binop_vals_broadcast () {
+        let col = EvalVal::Grid(dtypes::CellGrid { n_rows: 2, n_cols: 1, vals: vec![Some(CellVal::Int(1)), Some(CellVal::Int(2))] });
+        let row = EvalVal::Grid(dtypes::CellGrid { n_rows: 1, n_cols: 3, vals: vec![Some(CellVal::Int(10)), Some(CellVal::Int(20)), None] });
+        let res = apply_binop_vals(&dtypes::Op::Plus, Some(col.clone()), Some(EvalVal::Val(CellVal::Int(1))), Coercion::Lenient).unwrap();
+        assert!(matches!(&res, EvalVal::Grid(g) if g.n_rows == 2 && g.n_cols == 1 && matches!(g.vals[1], Some(CellVal::Int(3)))), "{:?}", res);
+        let res = apply_binop_vals(&dtypes::Op::Gt, Some(col.clone()), Some(row.clone()), Coercion::Lenient).unwrap();
+        assert!(matches!(&res, EvalVal::Grid(g) if g.n_rows == 2 && g.n_cols == 3 && matches!(g.vals[5], Some(CellVal::Bool(true)))), "{:?}", res);
+        let res = apply_binop_vals(&dtypes::Op::Plus, Some(col.clone()), Some(row.clone()), Coercion::Strict);
+        assert!(matches!(res, Err(EvalErr::Value)), "empty cells cannot be used in strict arithmetic");
+        let res = apply_binop_vals(&dtypes::Op::Plus, Some(col.clone()), Some(row), Coercion::Lenient).unwrap();
+        assert!(matches!(&res, EvalVal::Grid(g) if matches!(g.vals[5], Some(CellVal::Int(2)))), "empty cells are 0 in arithmetic");
+        let tall = EvalVal::Grid(dtypes::CellGrid { n_rows: 3, n_cols: 1, vals: vec![Some(CellVal::Int(1)); 3] });
+        let res = apply_binop_vals(&dtypes::Op::Plus, Some(col), Some(tall), Coercion::Lenient);
+        assert!(matches!(res, Err(EvalErr::Value)), "mismatched shapes");
+    }
+
+    #[test]
+    fn coerce_operands () {
+        let text = |s: &str| CellVal::Text(String::from(s));
+        let lenient = |op, l: Option<CellVal>, r: Option<CellVal>| apply_binop_vals(op, l.map(EvalVal::Val), r.map(EvalVal::Val), Coercion::Lenient);
+        let strict = |op, l: Option<CellVal>, r: Option<CellVal>| apply_binop_vals(op, l.map(EvalVal::Val), r.map(EvalVal::Val), Coercion::Strict);
+        assert!(matches!(lenient(&dtypes::Op::Plus, Some(text(" 42 ")), Some(CellVal::Int(1))), Ok(EvalVal::Val(CellVal::Int(43)))));
+        assert!(matches!(lenient(&dtypes::Op::Mul, Some(text("1.5")), Some(CellVal::Bool(true))), Ok(EvalVal::Val(CellVal::Real(v))) if v == 1.5));
+        assert!(matches!(lenient(&dtypes::Op::Minus, Some(text("2024-03-01")), Some(text("2024-02-01"))), Ok(EvalVal::Val(CellVal::Duration(_)))));
+        assert!(matches!(lenient(&dtypes::Op::Plus, None, Some(CellVal::Int(5))), Ok(EvalVal::Val(CellVal::Int(5)))));
+        assert!(matches!(lenient(&dtypes::Op::Concat, None, Some(CellVal::Int(5))), Ok(EvalVal::Val(CellVal::Text(t))) if t == "5"));
+        assert!(matches!(lenient(&dtypes::Op::Plus, Some(text("abc")), Some(CellVal::Int(1))), Err(EvalErr::Value)));
+        assert!(matches!(lenient(&dtypes::Op::Plus, Some(text("")), Some(CellVal::Int(1))), Err(EvalErr::Value)));
+        // comparisons do not turn text into numbers
+        assert!(matches!(lenient(&dtypes::Op::Eq, Some(text("1")), Some(CellVal::Int(1))), Ok(EvalVal::Val(CellVal::Bool(false)))));
+        assert!(matches!(strict(&dtypes::Op::Plus, Some(text("42")), Some(CellVal::Int(1))), Err(EvalErr::Value)));
+        assert!(matches!(strict(&dtypes::Op::Plus, Some(CellVal::Bool(true)), Some(CellVal::Int(1))), Err(EvalErr::Value)));
+        assert!(matches!(strict(&dtypes::Op::Plus, None, Some(CellVal::Int(1))), Err(EvalErr::Value)));
+        assert!(matches!(strict(&dtypes::Op::Concat, None, Some(text("a"))), Ok(EvalVal::Val(CellVal::Text(t))) if t == "a"));
+        assert!(matches!(negate_vals(Some(EvalVal::Val(text("7"))), Coercion::Lenient), Ok(EvalVal::Val(CellVal::Int(-7)))));
+        assert!(matches!(negate_vals(None, Coercion::Strict), Err(EvalErr::Value)));
+    }
 }
-*/
@@ -0,0 +1,21 @@
+/*
+    The spreadsheet engine as a library so that other programs can use it
+    (e.g. to add their own formula functions, see formulas::Function),
+    the rust_sheet command line program in main.rs is built on top of it
+*/
+
+
+pub mod dtypes;
+pub mod decimal;
+pub mod datetime;
+pub mod finance;
+pub mod arrays;
+pub mod math;
+pub mod text;
+pub mod sheet;
+pub mod parsing;
+pub mod formulas;
+pub mod explain;
+pub mod random;
+pub mod regex;
+pub mod locale;
@@ -0,0 +1,168 @@
+/*
+    Module for writing numbers the way people in different places do
+    (e.g. 1,234.56 or 1.234,56), a sheet can have a locale that values
+    typed into cells are parsed with and that read_sheet --localized
+    shows numbers with, sheet.txt and formulas always use 1234.56
+*/
+
+
+use crate::dtypes::CellVal;
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locale {
+    pub name: &'static str,
+    // between the whole number and the fraction
+    pub decimal_sep: char,
+    // between groups of three digits in the whole number
+    pub group_sep: char,
+}
+
+
+// the locales that can be picked by name
+const LOCALES: &[Locale] = &[
+    Locale { name: "en", decimal_sep: '.', group_sep: ',' },
+    Locale { name: "de", decimal_sep: ',', group_sep: '.' },
+    Locale { name: "fr", decimal_sep: ',', group_sep: ' ' },
+    Locale { name: "ch", decimal_sep: '.', group_sep: '\'' },
+];
+
+
+pub fn find_locale (name: &str) -> Option<Locale> {
+    LOCALES.iter().find(|l| l.name.eq_ignore_ascii_case(name)).copied()
+}
+
+
+fn all_digits (s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_digit())
+}
+
+
+fn group_digits (digits: &str, sep: char) -> String {
+    // 1234567 -> 1,234,567
+    let mut out = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(sep);
+        }
+        out.push(c);
+    }
+    out
+}
+
+
+impl Locale {
+    pub fn normalize (&self, num: &str) -> Option<String> {
+        // a number written in this locale (group separators are optional
+        // but have to be between every 3 digits if there are any, there can
+        // be an exponent and a d suffix for Decimals) written the way
+        // parsing::parse_val takes it, None if it is not a number
+        let (num, suffix) = match num.strip_suffix(['d', 'D']) {
+            Some(num) => (num, "d"),
+            None => (num, ""),
+        };
+        let (sign, num) = match num.strip_prefix(['-', '+']) {
+            Some(rest) => (&num[..1], rest),
+            None => ("", num),
+        };
+        let (mantissa, exponent) = match num.find(['e', 'E']) {
+            Some(i) => {
+                let exp = &num[i + 1..];
+                if !all_digits(exp.strip_prefix(['-', '+']).unwrap_or(exp)) {
+                    return Option::None
+                }
+                (&num[..i], &num[i..])
+            },
+            None => (num, ""),
+        };
+        let (whole, frac) = match mantissa.split_once(self.decimal_sep) {
+            Some((whole, frac)) => {
+                if !all_digits(frac) {
+                    return Option::None
+                }
+                (whole, Option::Some(frac))
+            },
+            None => (mantissa, Option::None),
+        };
+        let mut groups = whole.split(self.group_sep);
+        let first = groups.next().unwrap_or("");
+        let rest: Vec<&str> = groups.collect();
+        let grouped = rest.iter().all(|g| g.len() == 3 && all_digits(g)) && (1..=3).contains(&first.len());
+        let whole_ok = (first.is_empty() && rest.is_empty() && frac.is_some()) || (all_digits(first) && (rest.is_empty() || grouped));
+        if !whole_ok {
+            return Option::None
+        }
+        let mut out = format!("{}{}", sign, whole.replace(self.group_sep, ""));
+        if let Some(frac) = frac {
+            out.push_str(&format!("{}.{}", if whole.is_empty() { "0" } else { "" }, frac));
+        }
+        out.push_str(exponent);
+        out.push_str(suffix);
+        Option::Some(out)
+    }
+
+    fn localize (&self, num: &str) -> String {
+        // a number as rust writes it (e.g. -1234.5e-7) in this locale
+        let (sign, num) = match num.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", num),
+        };
+        let (mantissa, exponent) = match num.find('e') {
+            Some(i) => (&num[..i], &num[i..]),
+            None => (num, ""),
+        };
+        // inf and NaN stay as they are
+        if !mantissa.starts_with(|c: char| c.is_ascii_digit()) {
+            return format!("{}{}", sign, num)
+        }
+        let (whole, frac) = match mantissa.split_once('.') {
+            Some((whole, frac)) => (whole, Option::Some(frac)),
+            None => (mantissa, Option::None),
+        };
+        let mut out = format!("{}{}", sign, group_digits(whole, self.group_sep));
+        if let Some(frac) = frac {
+            out.push(self.decimal_sep);
+            out.push_str(frac);
+        }
+        out.push_str(exponent);
+        out
+    }
+
+    pub fn show (&self, val: &CellVal) -> String {
+        // a value the way read_sheet prints it (e.g. Real(1234.5)) but
+        // with numbers in this locale (e.g. Real(1.234,5))
+        match val {
+            CellVal::Int(v) => format!("Int({})", self.localize(&v.to_string())),
+            CellVal::Real(v) => format!("Real({})", self.localize(&format!("{:?}", v))),
+            CellVal::Decimal(v) => format!("Decimal({})", self.localize(&v.to_string())),
+            val => format!("{:?}", val),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn normalize_and_show () {
+        let (en, de, fr) = (find_locale("en").unwrap(), find_locale("DE").unwrap(), find_locale("fr").unwrap());
+        assert_eq!(en.normalize("1,234.56"), Option::Some(String::from("1234.56")));
+        assert_eq!(de.normalize("1.234,56"), Option::Some(String::from("1234.56")));
+        assert_eq!(de.normalize("-1234,5e3"), Option::Some(String::from("-1234.5e3")));
+        assert_eq!(de.normalize(",5d"), Option::Some(String::from("0.5d")));
+        assert_eq!(fr.normalize("12 345 678"), Option::Some(String::from("12345678")));
+        // groups have to be three digits
+        for bad in ["1.23,4", "1234.567", "1.2", ",", "1,", "12,3.4", "1.234,5e", "abc", ""] {
+            assert_eq!(de.normalize(bad), Option::None, "{}", bad);
+        }
+        assert_eq!(en.normalize("1,234,5"), Option::None);
+        assert_eq!(de.show(&CellVal::Int(-1234567)), "Int(-1.234.567)");
+        assert_eq!(de.show(&CellVal::Real(1234.5)), "Real(1.234,5)");
+        assert_eq!(en.show(&CellVal::Real(1e300)), "Real(1e300)");
+        assert_eq!(fr.show(&CellVal::Real(-0.25)), "Real(-0,25)");
+        assert_eq!(en.show(&CellVal::Text(String::from("1234"))), "Text(\"1234\")");
+    }
+}
@@ -5,10 +5,47 @@ use std::env;
 use std::process;
 
 
-mod dtypes;
-mod sheet;
-mod parsing;
-mod formulas;
+use rust_sheet::{sheet, parsing, datetime, dtypes, explain, formulas, locale};
+
+
+fn parse_loc_arg (loc_arg: &str) -> dtypes::CellLoc {
+    // cell location from the command line, exits if it is not one
+    match parsing::parse_loc(loc_arg) {
+        Some(loc) => loc,
+        None => {
+            eprintln!("bad cell location: {}", loc_arg);
+            process::exit(1);
+        },
+    }
+}
+
+
+fn parse_seed (seed_arg: &str) -> u64 {
+    // random number seed from the command line, exits if it is not one
+    match seed_arg.parse::<u64>() {
+        Ok(seed) => seed,
+        Err(_) => {
+            eprintln!("bad seed: {}", seed_arg);
+            process::exit(1);
+        },
+    }
+}
+
+
+fn report_parse_error (formula: &str) -> bool {
+    // prints the formula with a caret under where it stopped making
+    // sense, returns false if it does not parse
+    let val = dtypes::CellVal::Formula(formula.to_string());
+    match parsing::parse_formula_expr(&val) {
+        Ok(_) => true,
+        Err(err) => {
+            eprintln!("{}", formula);
+            eprintln!("{}^", " ".repeat(err.offset));
+            eprintln!("{}", err);
+            false
+        },
+    }
+}
 
 
 fn handle_subcommand (subcommand: &String, other_args: &[String], sheet: &mut sheet::Sheet) -> bool {
@@ -16,8 +53,18 @@ fn handle_subcommand (subcommand: &String, other_args: &[String], sheet: &mut sh
     let mut modified = false;
     match subcommand.as_str() {
         "read_sheet" => {
+            // read_sheet [--localized]
+            let localized = n_other_args == 1 && other_args[0] == "--localized";
+            if n_other_args > 1 || (n_other_args == 1 && !localized) {
+                eprintln!("read_sheet subcommand takes no args (or optionally --localized)");
+                process::exit(1);
+            }
             eprintln!("subcommand: {}", subcommand);
-            sheet.read_sheet();
+            if localized {
+                sheet.read_sheet_localized();
+            } else {
+                sheet.read_sheet();
+            }
         },
         "write_cell" => {
             if n_other_args != 2 {
@@ -25,10 +72,14 @@ fn handle_subcommand (subcommand: &String, other_args: &[String], sheet: &mut sh
                 process::exit(1);
             }
             eprintln!("subcommand: {}", subcommand);
-            let loc = parsing::parse_loc(&other_args[0]);
+            let loc = parse_loc_arg(&other_args[0]);
             eprintln!("parsed cell location: {:?}", loc);
-            let val = parsing::parse_val(&other_args[1]);
+            let val = parsing::parse_val_in(&other_args[1], sheet.locale().as_ref());
             eprintln!("parsed cell value: {:?}", val);
+            // a formula that does not parse is still written, it shows up as #PARSE!
+            if let dtypes::CellVal::Formula(formula) = &val {
+                report_parse_error(formula);
+            }
             sheet.write_cell(loc, val);
             modified = true;
         },
@@ -38,20 +89,260 @@ fn handle_subcommand (subcommand: &String, other_args: &[String], sheet: &mut sh
                 process::exit(1);
             }
             eprintln!("subcommand: {}", subcommand);
-            let loc = parsing::parse_loc(&other_args[0]);
+            let loc = parse_loc_arg(&other_args[0]);
             eprintln!("parsed cell location: {:?}", loc);
             sheet.read_cell(loc);
         },
+        "explain" => {
+            // explain <loc> [--json]
+            let json = n_other_args == 2 && other_args[1] == "--json";
+            if n_other_args != 1 && !json {
+                eprintln!("explain subcommand takes 1 arg: <loc> (and optionally --json)");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let loc = parse_loc_arg(&other_args[0]);
+            match sheet.explain(&loc) {
+                Some(explanation) if json => println!("{}", explain::to_json(&explanation)),
+                Some(explanation) => print!("{}", explain::to_text(&explanation)),
+                None => {
+                    eprintln!("no formula at loc: {:?}", loc);
+                    process::exit(1);
+                },
+            }
+        },
+        "precedents" | "dependents" => {
+            // precedents <loc> [--recursive], same for dependents
+            let recursive = n_other_args == 2 && other_args[1] == "--recursive";
+            if n_other_args != 1 && !recursive {
+                eprintln!("{} subcommand takes 1 arg: <loc> (and optionally --recursive)", subcommand);
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let loc = parse_loc_arg(&other_args[0]);
+            let locs = match subcommand.as_str() {
+                "precedents" => sheet.precedents(&loc, recursive),
+                _ => sheet.dependents(&loc, recursive),
+            };
+            // one cell per line, ordered by column, row
+            for loc in locs {
+                println!("{}{}", loc.col, loc.row);
+            }
+        },
         "delete_cell" => {
             if n_other_args != 1 {
                 eprintln!("delete_cell subcommand takes 1 arg: <loc>");
                 process::exit(1);
             }
             eprintln!("subcommand: {}", subcommand);
-            let loc = parsing::parse_loc(&other_args[0]);
+            let loc = parse_loc_arg(&other_args[0]);
             eprintln!("parsed cell location: {:?}", loc);
             modified = sheet.delete_cell(loc);
         },
+        "define_name" => {
+            if n_other_args != 2 {
+                eprintln!("define_name subcommand takes 2 args: <name> <formula>");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            if !parsing::is_valid_name(&other_args[0]) {
+                eprintln!("bad name: {}", other_args[0]);
+                process::exit(1);
+            }
+            if !other_args[1].starts_with('=') {
+                eprintln!("a name has to be defined as a formula (starting with =): {}", other_args[1]);
+                process::exit(1);
+            }
+            if !report_parse_error(&other_args[1]) {
+                process::exit(1);
+            }
+            sheet.define_name(&other_args[0], &other_args[1]);
+            modified = true;
+        },
+        "delete_name" => {
+            if n_other_args != 1 {
+                eprintln!("delete_name subcommand takes 1 arg: <name>");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            modified = sheet.delete_name(&other_args[0]);
+        },
+        "set_seed" => {
+            if n_other_args != 1 {
+                eprintln!("set_seed subcommand takes 1 arg: <seed> (a whole number or none)");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let seed = match other_args[0].as_str() {
+                "none" => Option::None,
+                arg => Option::Some(parse_seed(arg)),
+            };
+            sheet.set_seed(seed);
+            modified = true;
+        },
+        "goal_seek" => {
+            if n_other_args != 3 {
+                eprintln!("goal_seek subcommand takes 3 args: <set_cell> <target_value> <changing_cell>");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let set = parse_loc_arg(&other_args[0]);
+            // written the way the sheet's locale does, like write_cell
+            let target = match other_args[1].trim().parse::<f64>().ok().or_else(|| {
+                sheet.locale()?.normalize(other_args[1].trim())?.parse::<f64>().ok()
+            }) {
+                Some(target) if target.is_finite() => target,
+                _ => {
+                    eprintln!("bad target value: {}", other_args[1]);
+                    process::exit(1);
+                },
+            };
+            let changing = parse_loc_arg(&other_args[2]);
+            match sheet.goal_seek(&set, target, &changing) {
+                Ok(val) => {
+                    // the value that was written into the changing cell
+                    println!("{}{} {:?}", changing.col, changing.row, val);
+                    modified = true;
+                },
+                Err(err) => {
+                    eprintln!("goal seek failed: {}", err);
+                    process::exit(1);
+                },
+            }
+        },
+        "data_table" => {
+            if n_other_args != 4 && n_other_args != 6 {
+                eprintln!("data_table subcommand takes 4 or 6 args: <result_cell> <input_cell> <values> [<input_cell> <values>] <target_cell> (values separated by ;)");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let result = parse_loc_arg(&other_args[0]);
+            // values are separated by semicolons (e.g. 8;10;12) since
+            // commas can be part of a number in some locales (e.g. 1,5)
+            let locale = sheet.locale();
+            let inputs: Vec<(dtypes::CellLoc, Vec<dtypes::CellVal>)> = other_args[1..n_other_args - 1].chunks(2).map(|pair| {
+                (parse_loc_arg(&pair[0]), pair[1].split(';').map(|val| parsing::parse_val_in(val, locale.as_ref())).collect())
+            }).collect();
+            let target = parse_loc_arg(&other_args[n_other_args - 1]);
+            match sheet.data_table(&result, &inputs) {
+                Some(grid) => sheet.write_grid(&target, grid),
+                None => {
+                    eprintln!("there is no formula in {}", other_args[0]);
+                    process::exit(1);
+                },
+            }
+            modified = true;
+        },
+        "scenario" => {
+            let usage = "scenario subcommand takes: save <name> <loc>..., show <name>, delete <name>, list or summary <loc>...";
+            if n_other_args == 0 {
+                eprintln!("{}", usage);
+                process::exit(1);
+            }
+            eprintln!("subcommand: {} {}", subcommand, other_args[0]);
+            match (other_args[0].as_str(), n_other_args) {
+                ("save", 3..) => {
+                    let locs: Vec<dtypes::CellLoc> = other_args[2..].iter().map(|arg| parse_loc_arg(arg)).collect();
+                    sheet.save_scenario(&other_args[1], &locs);
+                    modified = true;
+                },
+                ("show", 2) => {
+                    // written the usual way so everything that uses the cells changes too
+                    modified = sheet.show_scenario(&other_args[1]);
+                    if !modified {
+                        eprintln!("there is no scenario named {}", other_args[1]);
+                    }
+                },
+                ("delete", 2) => {
+                    modified = sheet.delete_scenario(&other_args[1]);
+                },
+                ("list", 1) => {
+                    // one line for each scenario: "<name> <loc> <value> <loc> <value> ..."
+                    for (name, cells) in sheet.scenarios() {
+                        let cells: Vec<String> = cells.iter().map(|(loc, val)| match val {
+                            Some(val) => format!("{}{} {:?}", loc.col, loc.row, val),
+                            None => format!("{}{} Empty", loc.col, loc.row),
+                        }).collect();
+                        println!("{} {}", name, cells.join(" "));
+                    }
+                },
+                ("summary", 2..) => {
+                    // a header line with the result cells then a line for what they 
+                    // show now and one for each scenario
+                    let locs: Vec<dtypes::CellLoc> = other_args[1..].iter().map(|arg| parse_loc_arg(arg)).collect();
+                    let show = |vals: Vec<Option<dtypes::CellVal>>| vals.iter().map(|val| match val {
+                        Some(val) => format!("{:?}", val),
+                        None => String::from("Empty"),
+                    }).collect::<Vec<String>>().join(" ");
+                    let header: Vec<String> = locs.iter().map(|loc| format!("{}{}", loc.col, loc.row)).collect();
+                    println!("scenario {}", header.join(" "));
+                    sheet.recalculate();
+                    println!("current {}", show(locs.iter().map(|loc| sheet.shown_at(loc)).collect()));
+                    for (name, vals) in sheet.scenario_summary(&locs) {
+                        println!("{} {}", name, show(vals));
+                    }
+                },
+                _ => {
+                    eprintln!("{}", usage);
+                    process::exit(1);
+                },
+            }
+        },
+        "set_iteration" => {
+            if n_other_args != 1 && n_other_args != 2 {
+                eprintln!("set_iteration subcommand takes 2 args: <max_iterations> <max_change> (or 1 arg: off)");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let iteration = match other_args[0].as_str() {
+                "off" if n_other_args == 1 => Option::None,
+                _ => match parsing::parse_iteration(&other_args.join(" ")) {
+                    Some((max_iterations, max_change)) => Option::Some(sheet::Iteration { max_iterations, max_change }),
+                    None => {
+                        eprintln!("bad iteration settings: {}", other_args.join(" "));
+                        process::exit(1);
+                    },
+                },
+            };
+            sheet.set_iteration(iteration);
+            modified = true;
+        },
+        "set_locale" => {
+            if n_other_args != 1 {
+                eprintln!("set_locale subcommand takes 1 arg: <locale> (en, de, fr, ch or none)");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let locale = match other_args[0].as_str() {
+                "none" => Option::None,
+                name => match locale::find_locale(name) {
+                    Some(locale) => Option::Some(locale),
+                    None => {
+                        eprintln!("unknown locale: {} (en, de, fr, ch or none)", name);
+                        process::exit(1);
+                    },
+                },
+            };
+            sheet.set_locale(locale);
+            modified = true;
+        },
+        "set_coercion" => {
+            if n_other_args != 1 {
+                eprintln!("set_coercion subcommand takes 1 arg: lenient or strict");
+                process::exit(1);
+            }
+            eprintln!("subcommand: {}", subcommand);
+            let coercion = match other_args[0].as_str() {
+                "lenient" => formulas::Coercion::Lenient,
+                "strict" => formulas::Coercion::Strict,
+                arg => {
+                    eprintln!("bad coercion mode: {} (lenient or strict)", arg);
+                    process::exit(1);
+                },
+            };
+            sheet.set_coercion(coercion);
+            modified = true;
+        },
         "count_rows" => {
             eprintln!("subcommand: {}", subcommand);
             // print the number of rows in the sheet to stdout
@@ -98,11 +389,29 @@ fn main () {
     // init the sheet
     let mut sheet = sheet::Sheet::new();
 
+    // TODAY() and NOW() can be pinned to a fixed time by setting
+    // RUST_SHEET_NOW to an ISO-8601 date-time (e.g. 2024-01-31T12:00:00)
+    if let Ok(now) = env::var("RUST_SHEET_NOW") {
+        match datetime::DateTime::parse(&now) {
+            Some(now) => sheet.set_clock(Box::new(datetime::FixedClock(now))),
+            None => {
+                eprintln!("bad RUST_SHEET_NOW date-time: {}", now);
+                process::exit(1);
+            },
+        }
+    }
+
     // load sheet state from file
     sheet.load_sheet();
 
     // parse arguments
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    // --seed <n> before the subcommand fixes the random numbers for just this run
+    if args.len() > 2 && args[1] == "--seed" {
+        let seed = parse_seed(&args[2]);
+        sheet.seed_rng(Option::Some(seed));
+        args.drain(1..3);
+    }
     if args.len() < 2 {  // ensure there is a subcommand
         eprintln!("no subcommand");
         process::exit(1);
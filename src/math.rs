@@ -0,0 +1,194 @@
+/*
+    Module with the math formula functions, including the matrix ones
+    which take 2-D blocks of numbers and give back blocks that spill
+*/
+
+
+use crate::dtypes::{CellGrid, CellVal, EvalErr, EvalVal, Op};
+use crate::formulas;
+use crate::sheet;
+
+
+pub fn fn_sum (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // SUM(value1, [value2], ...)
+    // adds up the numbers the same way + does (so Ints and Decimals stay
+    // exact), in ranges anything that is not a number is skipped but
+    // a single value has to be a number (or TRUE/FALSE)
+    let mut total = CellVal::Int(0);
+    for arg in args {
+        match arg {
+            EvalVal::Val(val @ (CellVal::Int(_) | CellVal::Real(_) | CellVal::Decimal(_) | CellVal::Bool(_))) => {
+                total = formulas::apply_binop(&Op::Plus, total, val.clone())?;
+            },
+            EvalVal::Val(_) => return Err(EvalErr::Value),
+            EvalVal::Grid(grid) => {
+                for val in grid.vals.iter().flatten() {
+                    if let CellVal::Int(_) | CellVal::Real(_) | CellVal::Decimal(_) = val {
+                        total = formulas::apply_binop(&Op::Plus, total, val.clone())?;
+                    }
+                }
+            },
+        }
+    }
+    Ok(total)
+}
+
+
+struct Matrix {
+    // numbers row by row
+    n_rows: usize,
+    n_cols: usize,
+    vals: Vec<f64>,
+}
+
+
+impl Matrix {
+    fn from_arg (val: &EvalVal) -> Result<Matrix, EvalErr> {
+        // every value has to be a number (a single number is 1x1)
+        let (n_rows, n_cols, vals) = match val {
+            EvalVal::Val(v) => (1, 1, vec![Some(v.clone())]),
+            EvalVal::Grid(grid) => (grid.n_rows, grid.n_cols, grid.vals.clone()),
+        };
+        let mut nums = Vec::with_capacity(vals.len());
+        for val in vals {
+            match val {
+                Some(CellVal::Int(v)) => nums.push(v as f64),
+                Some(CellVal::Real(v)) => nums.push(v),
+                Some(CellVal::Decimal(v)) => nums.push(v.to_f64()),
+                _ => return Err(EvalErr::Value),
+            }
+        }
+        Ok(Matrix { n_rows, n_cols, vals: nums })
+    }
+
+    fn square (val: &EvalVal) -> Result<Matrix, EvalErr> {
+        let matrix = Matrix::from_arg(val)?;
+        if matrix.n_rows != matrix.n_cols {
+            return Err(EvalErr::Value)
+        }
+        Ok(matrix)
+    }
+
+    fn into_val (self) -> Result<EvalVal, EvalErr> {
+        if self.vals.iter().any(|v| !v.is_finite()) {
+            return Err(EvalErr::Num)
+        }
+        let vals = self.vals.into_iter().map(|v| Some(CellVal::Real(v))).collect();
+        Ok(EvalVal::Grid(CellGrid { n_rows: self.n_rows, n_cols: self.n_cols, vals }))
+    }
+
+    fn eliminate (&mut self, other: &mut Matrix) -> Option<f64> {
+        // Gauss-Jordan elimination with partial pivoting, turns a square 
+        // matrix into the identity doing the same row operations to other 
+        // (so an identity matrix there ends up as the inverse), gives back
+        // the determinant or None if the matrix is singular
+        let n = self.n_rows;
+        let scale = self.vals.iter().fold(0.0_f64, |m, v| m.max(v.abs()));
+        let mut det = 1.0;
+        for col in 0..n {
+            let pivot = (col..n).max_by(|a, b| self.vals[a * n + col].abs().total_cmp(&self.vals[b * n + col].abs()))?;
+            let p = self.vals[pivot * n + col];
+            if p.abs() <= scale * 1e-12 {
+                return None
+            }
+            if pivot != col {
+                self.swap_rows(pivot, col);
+                other.swap_rows(pivot, col);
+                det = -det;
+            }
+            det *= p;
+            self.scale_row(col, 1.0 / p);
+            other.scale_row(col, 1.0 / p);
+            for row in (0..n).filter(|row| *row != col) {
+                let factor = self.vals[row * n + col];
+                if factor != 0.0 {
+                    self.sub_row(row, col, factor);
+                    other.sub_row(row, col, factor);
+                }
+            }
+        }
+        Some(det)
+    }
+
+    fn swap_rows (&mut self, a: usize, b: usize) {
+        for j in 0..self.n_cols {
+            self.vals.swap(a * self.n_cols + j, b * self.n_cols + j);
+        }
+    }
+
+    fn scale_row (&mut self, row: usize, by: f64) {
+        for v in &mut self.vals[row * self.n_cols..(row + 1) * self.n_cols] {
+            *v *= by;
+        }
+    }
+
+    fn sub_row (&mut self, row: usize, from: usize, factor: f64) {
+        // row -= factor * from
+        for j in 0..self.n_cols {
+            self.vals[row * self.n_cols + j] -= factor * self.vals[from * self.n_cols + j];
+        }
+    }
+}
+
+
+pub fn fn_mmult (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // MMULT(array1, array2)
+    // matrix product, array1 needs as many columns as array2 has rows
+    let (a, b) = (Matrix::from_arg(&args[0])?, Matrix::from_arg(&args[1])?);
+    if a.n_cols != b.n_rows {
+        return Err(EvalErr::Value)
+    }
+    let mut vals = vec![0.0; a.n_rows * b.n_cols];
+    for i in 0..a.n_rows {
+        for j in 0..b.n_cols {
+            vals[i * b.n_cols + j] = (0..a.n_cols).map(|k| a.vals[i * a.n_cols + k] * b.vals[k * b.n_cols + j]).sum();
+        }
+    }
+    Matrix { n_rows: a.n_rows, n_cols: b.n_cols, vals }.into_val()
+}
+
+
+pub fn fn_minverse (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // MINVERSE(array)
+    // inverse of a square matrix, #NUM! if it does not have one
+    let mut matrix = Matrix::square(&args[0])?;
+    let n = matrix.n_rows;
+    let mut inverse = Matrix { n_rows: n, n_cols: n, vals: (0..n * n).map(|i| if i % (n + 1) == 0 { 1.0 } else { 0.0 }).collect() };
+    matrix.eliminate(&mut inverse).ok_or(EvalErr::Num)?;
+    inverse.into_val()
+}
+
+
+pub fn fn_mdeterm (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // MDETERM(array)
+    // determinant of a square matrix (0 if it is singular)
+    let mut matrix = Matrix::square(&args[0])?;
+    let mut none = Matrix { n_rows: matrix.n_rows, n_cols: 0, vals: Vec::new() };
+    Ok(CellVal::Real(matrix.eliminate(&mut none).unwrap_or(0.0)))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsing;
+
+    fn eval (formula: &str) -> CellVal {
+        let sheet = sheet::Sheet::new();
+        sheet.eval_formula_cell(&parsing::parse_val(formula))
+    }
+
+    #[test]
+    fn matrix_functions () {
+        // results are checked through SUM since they spill
+        assert!(matches!(eval("=MDETERM(SEQUENCE(2,2))"), CellVal::Real(v) if (v + 2.0).abs() < 1e-12));
+        assert!(matches!(eval("=MDETERM(SEQUENCE(3,3))"), CellVal::Real(v) if v == 0.0));
+        assert!(matches!(eval("=SUM(MMULT(SEQUENCE(2,3),SEQUENCE(3,2)))"), CellVal::Real(v) if v == 22.0 + 28.0 + 49.0 + 64.0));
+        assert!(matches!(eval("=SUM(MMULT(MINVERSE(SEQUENCE(2,2,2)),SEQUENCE(2,2,2))*SEQUENCE(2,2))"), CellVal::Real(v) if (v - 5.0).abs() < 1e-12));
+        assert!(matches!(eval("=MINVERSE(SEQUENCE(2,2,0,0))"), CellVal::Text(t) if t == "#NUM!"));
+        assert!(matches!(eval("=MMULT(SEQUENCE(2,3),SEQUENCE(2,3))"), CellVal::Text(t) if t == "#VALUE!"));
+        assert!(matches!(eval("=MDETERM(SEQUENCE(2,3))"), CellVal::Text(t) if t == "#VALUE!"));
+        assert!(matches!(eval("=MDETERM(\"x\")"), CellVal::Text(t) if t == "#VALUE!"));
+    }
+}
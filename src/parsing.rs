@@ -3,67 +3,77 @@
 */
 
 
-use std::process;
-
-
 use crate::dtypes;
 use crate::dtypes::CellVal;
 use crate::formulas;
-
-
-pub fn parse_loc (loc_arg: &String) -> dtypes::CellLoc {
-    let mut buf_col = String::new();
-    let mut buf_row = String::new();
-    let mut number_flag = false;
-    let mut letter_flag = false;
-    for c in loc_arg.chars() {
-        if c.is_alphabetic() {
-            letter_flag = true;
-            buf_col.push(c.to_ascii_uppercase());  // convert to uppercase letter if not already
-            // simple check to make sure the location is in a usable form
-            // just make sure that is is composed of letters then numbers
-            if number_flag {
-                eprintln!("bad cell location: {}", loc_arg);
-                process::exit(1);
-            }
-        }
-        else if c.is_numeric() {
-            // another simple check to make sure there were actually letters first
-            if !letter_flag {
-                eprintln!("bad cell location: {}", loc_arg);
-                process::exit(1);
-            }
-            number_flag = true;
-            buf_row.push(c);
-        }
-    }
-    // another simple check to make sure there were numbers
-    if !number_flag {
-        eprintln!("bad cell location: {}", loc_arg);
-        process::exit(1);
+use crate::decimal;
+use crate::datetime;
+use crate::locale;
+
+
+pub fn parse_loc (loc_arg: &str) -> Option<dtypes::CellLoc> {
+    // cell location: column letters then row number (e.g. A1 or ab12),
+    // returns None for anything else
+    let digits = loc_arg.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    let letters = &loc_arg[..loc_arg.len() - digits.len()];
+    // more letters than this could not be turned into a column index
+    if letters.is_empty() || letters.len() > 6 || digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Option::None
     }
-    dtypes::CellLoc {
-        col: buf_col,
-        row: buf_row.parse::<usize>().unwrap(),
+    // rows start at 1
+    match digits.parse::<usize>() {
+        Ok(row) if row > 0 => Option::Some(dtypes::CellLoc { col: letters.to_ascii_uppercase(), row }),
+        _ => Option::None,
     }
 }
 
 
-pub fn parse_val (val_arg: &String) -> dtypes::CellVal {
-    match val_arg.parse::<i32>() {  // try parse as int first
+fn parse_decimal_literal (val_arg: &str) -> Option<decimal::Decimal> {
+    // exact decimal values are written as a number with a "d" suffix (e.g. "0.10d")
+    let num = val_arg.strip_suffix(['d', 'D'])?;
+    decimal::Decimal::parse(num)
+}
+
+
+pub fn parse_val (val_arg: &str) -> dtypes::CellVal {
+    // surrounding whitespace does not change what kind of value it is,
+    // only text keeps it
+    // a leading ' makes the rest text as it is, even if it looks like a 
+    // number, date or formula (e.g. '007 or '=not a formula)
+    if let Some(text) = val_arg.trim_start().strip_prefix('\'') {
+        return dtypes::CellVal::Text(text.to_string())
+    }
+    let raw = val_arg;
+    let val_arg = val_arg.trim();
+    if let Some(val) = parse_decimal_literal(val_arg) {
+        return dtypes::CellVal::Decimal(val)
+    }
+    // integers that do not fit in an i64 fail to parse here and
+    // fall through to being parsed as a real instead of overflowing
+    match val_arg.parse::<i64>() {  // try parse as int first
         Ok(val) => dtypes::CellVal::Int(val),
         _ => match val_arg.parse::<f64>() {  // try parse as real next
             Ok(val) => dtypes::CellVal::Real(val),
             _ => {
-                match val_arg.chars().nth(0) {
+                // then try the ISO-8601 date, date-time and duration forms
+                if let Some(val) = datetime::Date::parse(val_arg) {
+                    return dtypes::CellVal::Date(val)
+                }
+                if let Some(val) = datetime::DateTime::parse(val_arg) {
+                    return dtypes::CellVal::DateTime(val)
+                }
+                if let Some(val) = datetime::Duration::parse(val_arg) {
+                    return dtypes::CellVal::Duration(val)
+                }
+                match val_arg.chars().next() {
                     Some(c) => {
                         if c == '=' {
-                            dtypes::CellVal::Formula(val_arg.clone())  // formula
+                            dtypes::CellVal::Formula(val_arg.to_string())  // formula
                         } else {
-                            dtypes::CellVal::Text(val_arg.clone())  // otherwise parse as text
+                            dtypes::CellVal::Text(raw.to_string())  // otherwise parse as text
                         }
                     }
-                    _ => dtypes::CellVal::Text(val_arg.clone())  // otherwise parse as text
+                    _ => dtypes::CellVal::Text(raw.to_string())  // otherwise parse as text
                 }
             } 
         }
@@ -71,7 +81,42 @@ pub fn parse_val (val_arg: &String) -> dtypes::CellVal {
 }
 
 
-pub fn parse_first_line (line: &String) -> (usize, usize) {
+pub fn parse_val_in (val_arg: &str, locale: Option<&locale::Locale>) -> dtypes::CellVal {
+    // parse_val for what gets typed into a sheet with a locale, numbers have
+    // to be written that way (e.g. 1.234,56) and ones that are not are text
+    // (in the same example 1.5 is too), everything else is the same
+    let Some(locale) = locale else { return parse_val(val_arg) };
+    let val = parse_val(val_arg);
+    match locale.normalize(val_arg.trim()) {
+        Some(num) => parse_val(&num),
+        None => match val {
+            dtypes::CellVal::Int(_) | dtypes::CellVal::Real(_) | dtypes::CellVal::Decimal(_) => dtypes::CellVal::Text(val_arg.to_string()),
+            val => val,
+        },
+    }
+}
+
+
+pub fn migrate_val (val: dtypes::CellVal, version: usize) -> dtypes::CellVal {
+    // bring a cell value loaded from an older sheet file format up to date
+    // format version 1 stored Int as i32, so integers that did not fit were
+    // silently parsed as Real instead, a whole-number Real outside of the 
+    // i32 range could only have gotten there that way so turn it back into 
+    // an Int (as long as it actually fits in an i64)
+    if version < 2 {
+        if let dtypes::CellVal::Real(v) = val {
+            let outside_i32 = v > i32::MAX as f64 || v < i32::MIN as f64;
+            let fits_i64 = v >= i64::MIN as f64 && v < i64::MAX as f64;
+            if v.fract() == 0.0 && outside_i32 && fits_i64 {
+                return dtypes::CellVal::Int(v as i64)
+            }
+        }
+    }
+    val
+}
+
+
+pub fn parse_first_line (line: &str) -> (usize, usize) {
     // first line gets parsed as "<n_cols> <n_rows>"
     let mut buf_cols = String::new();
     let mut buf_rows = String::new();
@@ -91,9 +136,52 @@ pub fn parse_first_line (line: &String) -> (usize, usize) {
 }
 
 
-pub fn parse_line (line: &String) -> (dtypes::CellLoc, dtypes::CellVal) {
+pub fn parse_iteration (settings: &str) -> Option<(usize, f64)> {
+    // iterative calculation settings as "<max_iterations> <max_change>",
+    // at least one iteration and a change that is not negative
+    let (max_iterations, max_change) = settings.trim().split_once(' ')?;
+    let max_iterations = max_iterations.parse::<usize>().ok().filter(|n| *n > 0)?;
+    let max_change = max_change.trim().parse::<f64>().ok().filter(|c| c.is_finite() && *c >= 0.0)?;
+    Option::Some((max_iterations, max_change))
+}
+
+
+fn unescape_str (quoted: &str) -> String {
+    // undo the quoting/escaping that {:?} does to a String
+    let inner = quoted.strip_prefix('"').and_then(|q| q.strip_suffix('"')).unwrap_or(quoted);
+    let mut out = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some('0') => out.push('\0'),
+            Some('u') => {
+                // \u{...} with the code point in hex
+                let code: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                if let Some(c) = u32::from_str_radix(&code, 16).ok().and_then(char::from_u32) {
+                    out.push(c);
+                }
+            },
+            // \" \' and \\ are just the character itself
+            Some(c) => out.push(c),
+            None => {},
+        }
+    }
+    out
+}
+
+
+pub fn parse_line (line: &str) -> Option<(dtypes::CellLoc, dtypes::CellVal)> {
     // all other lines after the first are parsed as "<loc> <val>"
+    // returns None if there is not a valid loc
     let mut buf_loc = String::new();
+    let mut buf_tag = String::new();
     let mut buf_val = String::new();
     let mut space_flag = false;
     let mut paren_flag = false;
@@ -101,17 +189,14 @@ pub fn parse_line (line: &String) -> (dtypes::CellLoc, dtypes::CellVal) {
         if c != ' ' || space_flag {
             if space_flag {
                 if paren_flag {
-                    if c == ')' {
-                        paren_flag = false;
-                    } else {
-                        if c != '"' {  // ignore quotes from Text(...) values 
-                            buf_val.push(c);
-                        }
-                    }
+                    // everything up to the last ) since the value itself
+                    // can have parentheses in it (e.g. formulas)
+                    buf_val.push(c);
+                } else if c == '(' {
+                    paren_flag = true;
                 } else {
-                    if c == '(' {
-                        paren_flag = true;
-                    }
+                    // the value type, e.g. the "Int" in Int(...)
+                    buf_tag.push(c);
                 }
             } else {
                 buf_loc.push(c);
@@ -124,79 +209,274 @@ pub fn parse_line (line: &String) -> (dtypes::CellLoc, dtypes::CellVal) {
             space_flag = true;
         }
     }
-    (parse_loc(&buf_loc), parse_val(&buf_val))
+    if buf_val.ends_with(')') {
+        buf_val.pop();
+    }
+    // Text(...) and Formula(...) values are quoted and escaped like rust strings
+    if buf_tag == "Text" || buf_tag == "Formula" {
+        buf_val = unescape_str(&buf_val);
+    }
+    // text stays text even if it looks like something else (it was entered
+    // with a leading ', see parse_val)
+    if buf_tag == "Text" {
+        return Option::Some((parse_loc(&buf_loc)?, dtypes::CellVal::Text(buf_val)))
+    }
+    // Decimal values are stored without their "d" suffix so they need to be
+    // parsed explicitly otherwise they would come back as Real
+    let val = match (buf_tag.as_str(), decimal::Decimal::parse(&buf_val)) {
+        ("Decimal", Some(d)) => dtypes::CellVal::Decimal(d),
+        _ => parse_val(&buf_val),
+    };
+    Option::Some((parse_loc(&buf_loc)?, val))
+}
+
+
+fn is_loc (buf: &str) -> bool {
+    // letters followed by digits (e.g. A1 or ab12)
+    let digits = buf.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+    digits.len() < buf.len() && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+
+pub fn is_valid_name (name: &str) -> bool {
+    // names for LET, LAMBDA parameters and defined names start with a letter
+    // or _ followed by letters, digits, _ or . and cannot look like a cell 
+    // location or be TRUE/FALSE
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_alphabetic() || c == '_' => {},
+        _ => return false,
+    }
+    let upper = name.to_ascii_uppercase();
+    chars.all(|c| c.is_alphanumeric() || c == '_' || c == '.')
+        && !is_loc(name) && upper != "TRUE" && upper != "FALSE"
+}
+
+
+fn expected (offset: usize, expected: &'static str) -> dtypes::ParseError {
+    dtypes::ParseError { offset, expected }
+}
+
+
+fn range_token (first: &str, last: &str, start: usize) -> Result<dtypes::FormToken, dtypes::ParseError> {
+    // the two sides of a colon: two locs (A1:B5), two columns (B:D) or two
+    // rows (3:5), anything else is an error on whichever side is wrong
+    let last_start = start + first.chars().count() + 1;
+    let is_col = |s: &str| !s.is_empty() && s.len() <= 6 && s.chars().all(|c| c.is_ascii_alphabetic());
+    let as_row = |s: &str| match s.parse::<usize>() {
+        Ok(row) if row > 0 && s.chars().all(|c| c.is_ascii_digit()) => Option::Some(row),
+        _ => Option::None,
+    };
+    if let Some(first) = parse_loc(first) {
+        let last = parse_loc(last).ok_or(expected(last_start, "a cell location"))?;
+        return Ok(dtypes::FormToken::Range(first, last))
+    }
+    if is_col(first) {
+        if !is_col(last) {
+            return Err(expected(last_start, "a column"))
+        }
+        return Ok(dtypes::FormToken::Cols(first.to_ascii_uppercase(), last.to_ascii_uppercase()))
+    }
+    if let Some(first) = as_row(first) {
+        let last = as_row(last).ok_or(expected(last_start, "a row"))?;
+        return Ok(dtypes::FormToken::Rows(first, last))
+    }
+    Err(expected(start, "a cell location"))
+}
+
+
+pub fn parse_ref (text: &str) -> Option<dtypes::FormToken> {
+    // a reference written out as text (e.g. for INDIRECT): a loc, a range
+    // or whole columns/rows, None for anything else
+    let text = text.trim();
+    match text.split_once(':') {
+        Some((first, last)) => range_token(first.trim(), last.trim(), 0).ok(),
+        None => parse_loc(text).map(dtypes::FormToken::Loc),
+    }
 }
 
 
-fn buf_to_loc_or_num_token (buf: &String, alpha_flag: bool) -> dtypes::FormToken {
+fn buf_to_loc_or_num_token (buf: &str, alpha_flag: bool, start: usize) -> Result<dtypes::FormToken, dtypes::ParseError> {
     // take a buffer with either a loc or num and return the corresponding FormToken
+    // start is the character offset of the buffer in the formula, for errors
+    if let Some((first, last)) = buf.split_once(':') {
+        return range_token(first, last, start)
+    }
     if alpha_flag {
-        // its a loc
-        dtypes::FormToken::Loc(parse_loc(&buf))
+        // its a decimal literal (e.g. 1.50d) if it starts like a number
+        // otherwise its a loc or a spill reference if it ends with #
+        if buf.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+            if let Some(val) = parse_decimal_literal(buf) {
+                return Ok(dtypes::FormToken::Num(dtypes::CellVal::Decimal(val)))
+            }
+        }
+        if let Some(anchor) = buf.strip_suffix('#') {
+            let anchor = parse_loc(anchor).ok_or(expected(start, "a cell location"))?;
+            return Ok(dtypes::FormToken::SpillRef(anchor))
+        }
+        // TRUE and FALSE are not locs either
+        match buf.to_ascii_uppercase().as_str() {
+            "TRUE" => return Ok(dtypes::FormToken::Bool(true)),
+            "FALSE" => return Ok(dtypes::FormToken::Bool(false)),
+            _ => {},
+        }
+        // and anything else that is not a loc is a name
+        if !is_loc(buf) && is_valid_name(buf) {
+            return Ok(dtypes::FormToken::Name(buf.to_ascii_uppercase()))
+        }
+        parse_loc(buf).map(dtypes::FormToken::Loc).ok_or(expected(start, "a cell location or name"))
     } else {
-        // its a num
-        let num: f64;
-        match buf.parse::<i32>() {
-            Ok(val) => num = val as f64,
-            _ => num = buf.parse::<f64>().unwrap(),
+        // its a num, keep integer literals as integers
+        let num = match buf.parse::<i64>() {
+            Ok(val) => dtypes::CellVal::Int(val),
+            _ => dtypes::CellVal::Real(buf.parse::<f64>().map_err(|_| expected(start, "a number"))?),
         };
-        dtypes::FormToken::Num(num)
+        Ok(dtypes::FormToken::Num(num))
     }
 }
 
 
-fn tokenize_expr (expr: &String) -> Option<Vec<dtypes::FormToken>> {
+fn tokenize_expr (expr: &str) -> Result<Vec<(dtypes::FormToken, usize)>, dtypes::ParseError> {
     // create a vector of tokens in the order they were parsed from an expression
+    // along with the character offset that each one starts at
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens: Vec<(dtypes::FormToken, usize)> = Vec::new();
     let mut buf = String::new();
-    let mut tokens: Vec<dtypes::FormToken> = Vec::new();
+    let mut buf_start: usize = 0;
     let mut alpha_flag = false;
-    for c in expr.chars() {
-        if c == '+' || c == '-' {
-            if buf.len() > 0 {
+    // set when whitespace ends the buffer, it still gets to be a function 
+    // name if a ( comes next (e.g. "SUM (A1:A3)") or a range if a : does
+    let mut buf_ended = false;
+    // the = at the start just marks it as a formula, any others are comparisons
+    let mut i: usize = if chars.first() == Some(&'=') { 1 } else { 0 };
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            // whitespace outside of string literals only separates things
+            // (the two sides of a range stay together, e.g. "A1 : B2")
+            buf_ended = !buf.is_empty() && !buf.ends_with(':');
+            i += 1;
+            continue;
+        }
+        if buf_ended && c != '(' && c != ':' {
+            tokens.push((buf_to_loc_or_num_token(&buf, alpha_flag, buf_start)?, buf_start));
+            buf.clear();
+            alpha_flag = false;
+        }
+        buf_ended = false;
+        if "+-*/^&),<>=".contains(c) {
+            if !buf.is_empty() {
                 // if there is anything in the buffer, make a token from it 
                 // and push it before pushing the operator
-                tokens.push(buf_to_loc_or_num_token(&buf, alpha_flag));
+                tokens.push((buf_to_loc_or_num_token(&buf, alpha_flag, buf_start)?, buf_start));
                 buf.clear();
                 alpha_flag = false;
             }
-            match c {
-                '+' => tokens.push(dtypes::FormToken::BinOp(dtypes::Op::Plus)),
-                '-' => tokens.push(dtypes::FormToken::BinOp(dtypes::Op::Minus)),
+            let token = match c {
+                '+' => dtypes::FormToken::BinOp(dtypes::Op::Plus),
+                '-' => dtypes::FormToken::BinOp(dtypes::Op::Minus),
+                '*' => dtypes::FormToken::BinOp(dtypes::Op::Mul),
+                '/' => dtypes::FormToken::BinOp(dtypes::Op::Div),
+                '^' => dtypes::FormToken::BinOp(dtypes::Op::Pow),
+                '&' => dtypes::FormToken::BinOp(dtypes::Op::Concat),
+                ')' => dtypes::FormToken::RParen,
+                ',' => dtypes::FormToken::Comma,
+                '=' => dtypes::FormToken::BinOp(dtypes::Op::Eq),
                 _ => {
-                    eprintln!("unreachable");
-                    return Option::None
+                    // < or > could be the first character of <=, >= or <>
+                    let op = match (c, chars.get(i + 1)) {
+                        ('<', Some('=')) => dtypes::Op::Le,
+                        ('<', Some('>')) => dtypes::Op::Ne,
+                        ('>', Some('=')) => dtypes::Op::Ge,
+                        ('<', _) => dtypes::Op::Lt,
+                        _ => dtypes::Op::Gt,
+                    };
+                    if let dtypes::Op::Le | dtypes::Op::Ne | dtypes::Op::Ge = op {
+                        tokens.push((dtypes::FormToken::BinOp(op), i));
+                        i += 2;
+                        continue;
+                    }
+                    dtypes::FormToken::BinOp(op)
                 },
             };
-        } else if c != '=' {
+            tokens.push((token, i));
+        } else if c == '(' {
+            // whatever is in the buffer right before an opening parenthesis
+            // is the name of a function, otherwise it is just for grouping
+            if buf.is_empty() {
+                tokens.push((dtypes::FormToken::LParen, i));
+            } else {
+                let name_ok = buf.starts_with(char::is_alphabetic) 
+                    && buf.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.');
+                if !name_ok {
+                    return Err(expected(buf_start, "a function name"))
+                }
+                tokens.push((dtypes::FormToken::Func(buf.to_ascii_uppercase()), buf_start));
+                buf.clear();
+                alpha_flag = false;
+            }
+        } else if c == '"' {
+            // string literal, runs until the closing quote and 
+            // a doubled quote ("") inside of it is a literal quote
+            if !buf.is_empty() {
+                return Err(expected(i, "an operator"))
+            }
+            let start = i;
+            let mut text = String::new();
+            let mut closed = false;
+            i += 1;
+            while i < chars.len() {
+                if chars[i] == '"' {
+                    if chars.get(i + 1) == Some(&'"') {
+                        text.push('"');
+                        i += 1;
+                    } else {
+                        closed = true;
+                        break;
+                    }
+                } else {
+                    text.push(chars[i]);
+                }
+                i += 1;
+            }
+            if !closed {
+                return Err(expected(chars.len(), "a closing \""))
+            }
+            tokens.push((dtypes::FormToken::Str(text), start));
+        } else {
+            if buf.is_empty() {
+                buf_start = i;
+            }
             if c.is_alphabetic() {
                 alpha_flag = true;
             }
             buf.push(c);
         }
+        i += 1;
     }
     // add whatever is in the buffer to tokens
-    tokens.push(buf_to_loc_or_num_token(&buf, alpha_flag));
+    if !buf.is_empty() {
+        tokens.push((buf_to_loc_or_num_token(&buf, alpha_flag, buf_start)?, buf_start));
+    }
     // return the vector of tokens
-    Option::Some(tokens)
+    Ok(tokens)
 }
 
 
-pub fn parse_formula_expr (cell_val: &dtypes::CellVal) -> Option<formulas::TknTree> {
-    if let CellVal::Formula(expr) = cell_val {
-        if let Some(mut tokens) = tokenize_expr(&expr) {
-            let tree = formulas::tokens_to_tree(&mut tokens);
-            Option::Some(tree)
-        } else {
-            Option::None
-        }
-    } else { 
-        Option::None
+pub fn parse_formula_expr (cell_val: &dtypes::CellVal) -> Result<formulas::TknTree, dtypes::ParseError> {
+    // the token tree of a formula, or where and why it could not be parsed
+    match cell_val {
+        CellVal::Formula(expr) => {
+            let tokens = tokenize_expr(expr)?;
+            formulas::tokens_to_tree(&tokens, expr.chars().count())
+        },
+        _ => Err(expected(0, "a formula")),
     }
 }
 
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
 
     use super::*;
 
@@ -204,14 +484,38 @@ mod tests {
     fn parse_val_int () {
         let cv = parse_val(&String::from("1"));
         assert!(matches!(cv, dtypes::CellVal::Int(_)), "failed to parse cell value as Int");
-        // would need to modify parser to handle stripping whitespace for
-        // the rest of these test cases to work
-        /*let cv = parse_val(&String::from("1 "));
-        assert!(matches!(cv, CellVal::Int(_)), "failed to parse cell value as Int");
+        let cv = parse_val(&String::from("1 "));
+        assert!(matches!(cv, dtypes::CellVal::Int(_)), "failed to parse cell value as Int");
         let cv = parse_val(&String::from(" 1"));
-        assert!(matches!(cv, CellVal::Int(_)), "failed to parse cell value as Int");
+        assert!(matches!(cv, dtypes::CellVal::Int(_)), "failed to parse cell value as Int");
         let cv = parse_val(&String::from(" 1 "));
-        assert!(matches!(cv, CellVal::Int(_)), "failed to parse cell value as Int");*/
+        assert!(matches!(cv, dtypes::CellVal::Int(_)), "failed to parse cell value as Int");
+    }
+
+    #[test]
+    fn parse_val_int_64bit () {
+        let cv = parse_val(&String::from("3000000000"));
+        assert!(matches!(cv, dtypes::CellVal::Int(3_000_000_000)), "failed to parse cell value beyond i32 as Int");
+        let cv = parse_val(&String::from("-9223372036854775808"));
+        assert!(matches!(cv, dtypes::CellVal::Int(i64::MIN)), "failed to parse i64::MIN as Int");
+        // too big for an i64, should not overflow but fall back to Real
+        let cv = parse_val(&String::from("9223372036854775808"));
+        assert!(matches!(cv, dtypes::CellVal::Real(_)), "failed to parse cell value beyond i64 as Real");
+    }
+
+    #[test]
+    fn migrate_val_v1 () {
+        // whole-number Reals outside of the i32 range in version 1 files become Ints
+        let cv = migrate_val(dtypes::CellVal::Real(3000000000.0), 1);
+        assert!(matches!(cv, dtypes::CellVal::Int(3_000_000_000)), "failed to migrate overflowed Int");
+        // Reals inside the i32 range or with a fractional part are left alone
+        let cv = migrate_val(dtypes::CellVal::Real(3.0), 1);
+        assert!(matches!(cv, dtypes::CellVal::Real(_)), "should not migrate Real inside i32 range");
+        let cv = migrate_val(dtypes::CellVal::Real(3000000000.5), 1);
+        assert!(matches!(cv, dtypes::CellVal::Real(_)), "should not migrate Real with fractional part");
+        // nothing to migrate in current version files
+        let cv = migrate_val(dtypes::CellVal::Real(3000000000.0), 2);
+        assert!(matches!(cv, dtypes::CellVal::Real(_)), "should not migrate current version");
     }
 
     #[test]
@@ -220,14 +524,84 @@ mod tests {
         assert!(matches!(cv, dtypes::CellVal::Real(_)), "failed to parse cell value as Real");
         let cv = parse_val(&String::from("1.234"));
         assert!(matches!(cv, dtypes::CellVal::Real(_)), "failed to parse cell value as Real");
-        // would need to modify parser to handle stripping whitespace for
-        // the rest of these test cases to work
-        /*let cv = parse_val(&String::from("1.234 "));
+        let cv = parse_val(&String::from("1.234 "));
         assert!(matches!(cv, dtypes::CellVal::Real(_)), "failed to parse cell value as Real");
         let cv = parse_val(&String::from(" 1.234"));
-        assert!(matches!(cv, CellVal::Int(_)), "failed to parse cell value as int");
+        assert!(matches!(cv, dtypes::CellVal::Real(_)), "failed to parse cell value as Real");
         let cv = parse_val(&String::from(" 1.234 "));
-        assert!(matches!(cv, CellVal::Int(_)), "failed to parse cell value as int");*/
+        assert!(matches!(cv, dtypes::CellVal::Real(_)), "failed to parse cell value as Real");
+    }
+
+    #[test]
+    fn parse_val_decimal () {
+        let cv = parse_val(&String::from("0.10d"));
+        assert!(matches!(&cv, dtypes::CellVal::Decimal(d) if d.to_string() == "0.10"), "failed to parse cell value as Decimal");
+        let cv = parse_val(&String::from("-12D"));
+        assert!(matches!(&cv, dtypes::CellVal::Decimal(d) if d.to_string() == "-12"), "failed to parse cell value as Decimal");
+        let cv = parse_val(&String::from("d"));
+        assert!(matches!(cv, dtypes::CellVal::Text(_)), "failed to parse cell value as Text");
+    }
+
+    #[test]
+    fn parse_line_decimal_round_trip () {
+        // values are written to sheet.txt with {:?} and need to be read back losslessly
+        let val = parse_val(&String::from("1234.5600d"));
+        let line = format!("B7 {:?}", val);
+        assert_eq!(line, "B7 Decimal(1234.5600)");
+        let (loc, val) = parse_line(&line).unwrap();
+        assert_eq!((loc.col.as_str(), loc.row), ("B", 7));
+        assert!(matches!(&val, dtypes::CellVal::Decimal(d) if d.to_string() == "1234.5600"), "failed to read back Decimal");
+    }
+
+    #[test]
+    fn parse_line_formula_round_trip () {
+        // formulas with parentheses and quotes in them and text with quotes in it
+        for (loc, raw) in [("A1", "=DATEDIF(A1,DATE(2024,1,31),\"D\")+(1-2)"), ("C3", "say \"hi\" (twice)\ttab")] {
+            let val = parse_val(raw);
+            let (_, back) = parse_line(&format!("{} {:?}", loc, val)).unwrap();
+            assert_eq!(format!("{:?}", back), format!("{:?}", val), "failed to read back {}", raw);
+        }
+    }
+
+    #[test]
+    fn parse_val_forced_text () {
+        // the ' is not part of the value but the value is still text after a round trip
+        for (raw, text) in [("'007", "007"), ("'=not a formula", "=not a formula"), ("  '2024-01-31", "2024-01-31"), ("''quoted", "'quoted"), ("' TRUE ", " TRUE ")] {
+            let val = parse_val(raw);
+            assert!(matches!(&val, dtypes::CellVal::Text(t) if t == text), "failed to parse {} as Text {:?}", raw, val);
+            let (_, back) = parse_line(&format!("A1 {:?}", val)).unwrap();
+            assert!(matches!(&back, dtypes::CellVal::Text(t) if t == text), "failed to read back {} {:?}", raw, back);
+        }
+        assert!(matches!(parse_val("007"), dtypes::CellVal::Int(7)));
+    }
+
+    #[test]
+    fn parse_val_locale () {
+        let de = locale::find_locale("de");
+        assert!(matches!(parse_val_in("1.234,56", de.as_ref()), dtypes::CellVal::Real(v) if v == 1234.56));
+        assert!(matches!(parse_val_in(" -1.234 ", de.as_ref()), dtypes::CellVal::Int(-1234)));
+        assert!(matches!(parse_val_in("0,10d", de.as_ref()), dtypes::CellVal::Decimal(d) if d.to_string() == "0.10"));
+        assert!(matches!(parse_val_in("1.5", de.as_ref()), dtypes::CellVal::Text(t) if t == "1.5"));
+        assert!(matches!(parse_val_in("'1,5", de.as_ref()), dtypes::CellVal::Text(t) if t == "1,5"));
+        assert!(matches!(parse_val_in("2024-01-31", de.as_ref()), dtypes::CellVal::Date(_)));
+        assert!(matches!(parse_val_in("=A1*1.5", de.as_ref()), dtypes::CellVal::Formula(_)));
+        assert!(matches!(parse_val_in("1,234.5", locale::find_locale("en").as_ref()), dtypes::CellVal::Real(v) if v == 1234.5));
+        assert!(matches!(parse_val_in("1,5", Option::None), dtypes::CellVal::Text(_)));
+    }
+
+    #[test]
+    fn parse_val_date () {
+        let cv = parse_val(&String::from("2024-01-31"));
+        assert!(matches!(cv, dtypes::CellVal::Date(_)), "failed to parse cell value as Date");
+        let cv = parse_val(&String::from("2024-01-31T08:30:00"));
+        assert!(matches!(cv, dtypes::CellVal::DateTime(_)), "failed to parse cell value as DateTime");
+        let cv = parse_val(&String::from("P1DT2H"));
+        assert!(matches!(cv, dtypes::CellVal::Duration(_)), "failed to parse cell value as Duration");
+        let cv = parse_val(&String::from("2024-02-30"));
+        assert!(matches!(cv, dtypes::CellVal::Text(_)), "failed to parse invalid date as Text");
+        // they get written to sheet.txt in ISO-8601 form and read back in
+        let (_, cv) = parse_line(&format!("A1 {:?}", parse_val(&String::from("2024-01-31T08:30")))).unwrap();
+        assert!(matches!(cv, dtypes::CellVal::DateTime(_)), "failed to read back DateTime");
     }
 
     #[test]
@@ -244,12 +618,24 @@ mod tests {
 
     #[test]
     fn buf_to_loc_or_num_token_correct_values () {
-        let token = buf_to_loc_or_num_token(&String::from("A1"), true);
+        let token = buf_to_loc_or_num_token(&String::from("A1"), true, 0).unwrap();
         assert!(matches!(token, dtypes::FormToken::Loc(_)), "failed to parse 'A1' as a FormToken::Loc");
-        let token = buf_to_loc_or_num_token(&String::from("1"), false);
+        let token = buf_to_loc_or_num_token(&String::from("1"), false, 0).unwrap();
         assert!(matches!(token, dtypes::FormToken::Num(_)), "failed to parse 1 as a FormToken::Num");
-        let token = buf_to_loc_or_num_token(&String::from("1.234"), false);
+        let token = buf_to_loc_or_num_token(&String::from("1.234"), false, 0).unwrap();
         assert!(matches!(token, dtypes::FormToken::Num(_)), "failed to parse 1 as a FormToken::Num");
+        let token = buf_to_loc_or_num_token(&String::from("1.50d"), true, 0).unwrap();
+        assert!(matches!(token, dtypes::FormToken::Num(dtypes::CellVal::Decimal(_))), "failed to parse 1.50d as a Decimal FormToken::Num");
+        let token = buf_to_loc_or_num_token(&String::from("A1:b5"), true, 0).unwrap();
+        assert!(matches!(token, dtypes::FormToken::Range(_, _)), "failed to parse 'A1:b5' as a FormToken::Range");
+        let token = buf_to_loc_or_num_token(&String::from("b:d"), true, 0).unwrap();
+        assert!(matches!(&token, dtypes::FormToken::Cols(a, b) if a == "B" && b == "D"), "failed to parse 'b:d' as a FormToken::Cols");
+        let token = buf_to_loc_or_num_token(&String::from("3:3"), false, 0).unwrap();
+        assert!(matches!(token, dtypes::FormToken::Rows(3, 3)), "failed to parse '3:3' as a FormToken::Rows");
+        let token = buf_to_loc_or_num_token(&String::from("net_margin"), true, 0).unwrap();
+        assert!(matches!(&token, dtypes::FormToken::Name(name) if name == "NET_MARGIN"), "failed to parse 'net_margin' as a FormToken::Name");
+        let token = buf_to_loc_or_num_token(&String::from("3000000000"), false, 0).unwrap();
+        assert!(matches!(token, dtypes::FormToken::Num(dtypes::CellVal::Int(3_000_000_000))), "failed to parse 3000000000 as an Int FormToken::Num");
     }
 
     #[test]
@@ -273,6 +659,173 @@ mod tests {
         assert!(matches!(tree.root.unwrap().token, dtypes::FormToken::Loc(_)), "");
     }
 
+    #[test]
+    fn parse_formula_expr_func () {
+        // parse a formula with function calls, parentheses and string literals
+        let cell_val = dtypes::CellVal::Formula(String::from("=DATEDIF(A1,DATE(2024,1,31),\"D\")+(1-2)"));
+        let tree = parse_formula_expr(&cell_val).unwrap();
+        let root = tree.root.unwrap();
+        assert!(matches!(root.token, dtypes::FormToken::BinOp(dtypes::Op::Plus)), "");
+        let func = root.left.unwrap();
+        assert!(matches!(&func.token, dtypes::FormToken::Func(name) if name == "DATEDIF"), "");
+        assert_eq!(func.args.len(), 3);
+        assert!(matches!(&func.args[1].token, dtypes::FormToken::Func(name) if name == "DATE"), "");
+        assert!(matches!(&func.args[2].token, dtypes::FormToken::Str(s) if s == "D"), "");
+        assert!(matches!(root.right.unwrap().token, dtypes::FormToken::BinOp(dtypes::Op::Minus)), "");
+        // function names are case insensitive, no arguments is fine
+        let cell_val = dtypes::CellVal::Formula(String::from("=today()"));
+        let tree = parse_formula_expr(&cell_val).unwrap();
+        assert!(matches!(&tree.root.unwrap().token, dtypes::FormToken::Func(name) if name == "TODAY"), "");
+    }
+
+    #[test]
+    fn parse_formula_expr_compare_and_spill () {
+        // comparisons bind more loosely than arithmetic, only the leading = marks a formula
+        let cell_val = dtypes::CellVal::Formula(String::from("=A1#<=1+2"));
+        let root = parse_formula_expr(&cell_val).unwrap().root.unwrap();
+        assert!(matches!(root.token, dtypes::FormToken::BinOp(dtypes::Op::Le)), "");
+        assert!(matches!(&root.left.unwrap().token, dtypes::FormToken::SpillRef(loc) if loc.col == "A" && loc.row == 1), "");
+        assert!(matches!(root.right.unwrap().token, dtypes::FormToken::BinOp(dtypes::Op::Plus)), "");
+        let cell_val = dtypes::CellVal::Formula(String::from("=B2<>true"));
+        let root = parse_formula_expr(&cell_val).unwrap().root.unwrap();
+        assert!(matches!(root.token, dtypes::FormToken::BinOp(dtypes::Op::Ne)), "");
+        assert!(matches!(root.right.unwrap().token, dtypes::FormToken::Bool(true)), "");
+        let cell_val = dtypes::CellVal::Formula(String::from("=1=1"));
+        let root = parse_formula_expr(&cell_val).unwrap().root.unwrap();
+        assert!(matches!(root.token, dtypes::FormToken::BinOp(dtypes::Op::Eq)), "");
+    }
+
+    #[test]
+    fn parse_formula_expr_whitespace_and_case () {
+        // spaces only separate things and locs, ranges and functions can be lower case
+        let cell_val = dtypes::CellVal::Formula(String::from("= sum ( a1 : b2 ) * 2 >= c3 "));
+        let root = parse_formula_expr(&cell_val).unwrap().root.unwrap();
+        assert!(matches!(root.token, dtypes::FormToken::BinOp(dtypes::Op::Ge)), "");
+        assert!(matches!(&root.right.unwrap().token, dtypes::FormToken::Loc(loc) if loc.col == "C" && loc.row == 3), "");
+        let mul = root.left.unwrap();
+        let func = mul.left.unwrap();
+        assert!(matches!(&func.token, dtypes::FormToken::Func(name) if name == "SUM"), "");
+        assert!(matches!(&func.args[0].token, dtypes::FormToken::Range(a, b) if a.col == "A" && b.col == "B" && b.row == 2), "");
+        // but not inside string literals
+        let cell_val = dtypes::CellVal::Formula(String::from("=\t\" a  b \" "));
+        let root = parse_formula_expr(&cell_val).unwrap().root.unwrap();
+        assert!(matches!(&root.token, dtypes::FormToken::Str(s) if s == " a  b "), "");
+        let cell_val = dtypes::CellVal::Formula(String::from("=A 1"));
+        assert_eq!(parse_formula_expr(&cell_val).unwrap_err().offset, 3);
+    }
+
+    pub(crate) struct Rng(pub(crate) u64);
+
+    impl Rng {
+        // xorshift, good enough to make up formulas
+        fn next (&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+
+        fn pick<'a> (&mut self, choices: &[&'a str]) -> &'a str {
+            choices[self.next(choices.len())]
+        }
+    }
+
+    pub(crate) fn random_formula (rng: &mut Rng, depth: usize) -> String {
+        // random expression with random spacing, case and extra parentheses
+        let space = |rng: &mut Rng| rng.pick(&["", "", " ", "  "]).to_string();
+        let expr = match if depth == 0 { rng.next(4) } else { rng.next(9) } {
+            0 => rng.pick(&["0", "7", "42", "1.5", "2.", "0.25", "1.50d", "3d", "9000000000"]).to_string(),
+            1 => rng.pick(&["A1", "b2", "zz10", "a1:B3", "c2 : c9", "A1#", "true", "False"]).to_string(),
+            2 => rng.pick(&["\"\"", "\"abc\"", "\"say \"\"hi\"\"\"", "\" a, (b) \""]).to_string(),
+            3 => rng.pick(&["x", "Rate", "net.margin", "_tmp"]).to_string(),
+            4 | 5 => {
+                let op = rng.pick(&["+", "-", "*", "/", "^", "&", "=", "<>", "<", ">", "<=", ">="]);
+                format!("{}{}{}{}{}", random_formula(rng, depth - 1), space(rng), op, space(rng), random_formula(rng, depth - 1))
+            },
+            6 => format!("{}{}", rng.pick(&["-", "+", "- "]), random_formula(rng, depth - 1)),
+            7 => format!("({}{}{})", space(rng), random_formula(rng, depth - 1), space(rng)),
+            _ => {
+                let n_args = rng.next(4);
+                let args: Vec<String> = (0..n_args).map(|_| random_formula(rng, depth - 1)).collect();
+                format!("{}({})", rng.pick(&["sum", "IF", "Date", "let"]), args.join(&format!(",{}", space(rng))))
+            },
+        };
+        format!("{}{}{}", space(rng), expr, space(rng))
+    }
+
+    #[test]
+    fn print_tree_round_trip () {
+        // parse -> print -> parse gives the same tree and printing that gives the same text
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        for _ in 0..2000 {
+            let formula = format!("={}", random_formula(&mut rng, 4));
+            let tree = parse_formula_expr(&dtypes::CellVal::Formula(formula.clone()))
+                .unwrap_or_else(|err| panic!("{} should parse: {}", formula, err));
+            let printed = formulas::print_tree(&tree);
+            let again = parse_formula_expr(&dtypes::CellVal::Formula(printed.clone()))
+                .unwrap_or_else(|err| panic!("{} (from {}) should parse: {}", printed, formula, err));
+            assert_eq!(format!("{:?}", again), format!("{:?}", tree), "{} printed as {}", formula, printed);
+            assert_eq!(formulas::print_tree(&again), printed, "{} printed as {}", formula, printed);
+            assert!(!printed.contains(' ') || printed.contains('"'), "{} printed with spaces", printed);
+        }
+    }
+
+    #[test]
+    fn print_tree_canonical () {
+        for (formula, printed) in [("= sum( a1 : b3 ) * 2", "=SUM(A1:B3)*2"), ("=(1+2)+3", "=1+2+3"), ("=1+(2+3)", "=1+(2+3)"),
+                                   ("=(1*2)+(3*4)", "=1*2+3*4"), ("=(1+2)*3", "=(1+2)*3"), ("=-(1+2)", "=-(1+2)"), ("=+-a1#", "=-A1#"),
+                                   ("=(-2)^2", "=-2^2"), ("=2^(3^2)", "=2^(3^2)"), ("=1.", "=1.0"), ("=0.50D", "=0.50d"),
+                                   ("= \"a\"\"b \" ", "=\"a\"\"b \""), ("=let(x, 1, x+true)", "=LET(X,1,X+TRUE)"), ("=(a1 = b1) <> false", "=A1=B1<>FALSE"),
+                                   ("=sum(b : d, 3:3)", "=SUM(B:D,3:3)"), ("=(\"a\"&1)&(1+2)=\"a3\"", "=\"a\"&1&1+2=\"a3\"")] {
+            let tree = parse_formula_expr(&dtypes::CellVal::Formula(String::from(formula))).unwrap();
+            assert_eq!(formulas::print_tree(&tree), printed, "wrong canonical form for {}", formula);
+        }
+    }
+
+    #[test]
+    fn parse_iteration_settings () {
+        assert_eq!(parse_iteration("100 0.001"), Option::Some((100, 0.001)));
+        for settings in ["", "100", "0 0.1", "10 -1", "10 x", "x 0.1"] {
+            assert!(parse_iteration(settings).is_none(), "{} should not be iteration settings", settings);
+        }
+    }
+
+    #[test]
+    fn valid_names () {
+        for name in ["x", "rate", "_tmp", "net.margin", "A1B", "Q1_2024"] {
+            assert!(is_valid_name(name), "{} should be a valid name", name);
+        }
+        for name in ["A1", "xfd100", "1x", "true", "a b", "", "a-b"] {
+            assert!(!is_valid_name(name), "{} should not be a valid name", name);
+        }
+    }
+
+    #[test]
+    fn parse_formula_expr_malformed () {
+        // malformed formulas have no tree, just where and why they went wrong
+        for (formula, offset, expected) in [("=1+", 3, "a value"), ("=(1+2", 5, "')'"), ("=1+2)", 4, "an operator"), 
+                                            ("=DATE(1,", 8, "a value"), ("=\"abc", 5, "a closing \""), ("=DATE(1,,2)", 8, "a value"),
+                                            ("=A1+1.2.3", 4, "a number"), ("=A0+1", 1, "a cell location or name"),
+                                            ("=SUM(A1:B)", 8, "a cell location"), ("=1 2", 3, "an operator"),
+                                            ("=A1\"x\"", 3, "an operator"), ("=1(2)", 1, "a function name"),
+                                            ("=SUM(A:B1)", 7, "a column"), ("=SUM(3:C)", 7, "a row"), ("=SUM(0:3)", 5, "a cell location")] {
+            let cell_val = dtypes::CellVal::Formula(String::from(formula));
+            let err = parse_formula_expr(&cell_val).unwrap_err();
+            assert_eq!((err.offset, err.expected), (offset, expected), "wrong error for {}", formula);
+        }
+    }
+
+    #[test]
+    fn parse_loc_bad () {
+        // bad locations give None instead of exiting
+        let loc = parse_loc("ab12").unwrap();
+        assert_eq!((loc.col.as_str(), loc.row), ("AB", 12));
+        for loc in ["", "A", "12", "A0", "1A", "A-1", "A1B", "ABCDEFGH1", "A99999999999999999999999"] {
+            assert!(parse_loc(loc).is_none(), "{} should not be a loc", loc);
+        }
+        assert!(parse_line("?? Int(1)").is_none(), "line with a bad loc should not parse");
+    }
+
     #[test]
     fn parse_formula_expr_many () {
         // parse a formula with a bunch of stuff
@@ -0,0 +1,168 @@
+/*
+    Module with the random number formula functions and the random source
+    behind them, these are volatile so they get a new value every time the
+    sheet is recalculated (see Sheet::recalculate)
+*/
+
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::dtypes::{CellGrid, CellVal, EvalErr, EvalVal};
+use crate::formulas;
+use crate::sheet;
+
+
+// arrays bigger than this many values give #NUM! instead of trying to spill
+const MAX_CELLS: usize = 1_000_000;
+
+
+#[derive(Debug)]
+pub struct Rng {
+    // splitmix64, the same seed always gives the same numbers
+    state: u64,
+}
+
+
+impl Rng {
+    pub fn new (seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn from_time () -> Rng {
+        // seeded from the current time when there is no seed to use
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+        Rng::new(nanos as u64)
+    }
+
+    pub fn next_u64 (&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    pub fn next_f64 (&mut self) -> f64 {
+        // in [0, 1), using the top 53 bits so every value is equally likely
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn below (&mut self, n: u64) -> u64 {
+        // in [0, n) without favouring the small numbers, n has to be above 0
+        let zone = u64::MAX - u64::MAX % n;
+        loop {
+            let x = self.next_u64();
+            if x < zone {
+                return x % n
+            }
+        }
+    }
+
+    pub fn between (&mut self, low: i64, high: i64) -> i64 {
+        // in [low, high], low can not be more than high
+        let span = (high as i128 - low as i128 + 1) as u128;
+        if span > u64::MAX as u128 {
+            return self.next_u64() as i64
+        }
+        (low as i128 + self.below(span as u64) as i128) as i64
+    }
+}
+
+
+pub fn fn_rand (_args: &[EvalVal], sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // RAND()
+    Ok(CellVal::Real(sheet.random(|rng| rng.next_f64())))
+}
+
+
+fn bounds (low: f64, high: f64) -> Result<(i64, i64), EvalErr> {
+    // whole numbers between low and high (both included)
+    let (low, high) = (low.ceil(), high.floor());
+    if low > high {
+        return Err(EvalErr::Num)
+    }
+    if low < i64::MIN as f64 || high > i64::MAX as f64 {
+        return Err(EvalErr::Num)
+    }
+    Ok((low as i64, high as i64))
+}
+
+
+pub fn fn_randbetween (args: &[EvalVal], sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // RANDBETWEEN(bottom, top)
+    let (low, high) = bounds(formulas::arg_num(&args[0])?, formulas::arg_num(&args[1])?)?;
+    Ok(CellVal::Int(sheet.random(|rng| rng.between(low, high))))
+}
+
+
+fn opt_num (args: &[EvalVal], i: usize, default: f64) -> Result<f64, EvalErr> {
+    match args.get(i) {
+        Some(val) => formulas::arg_num(val),
+        None => Ok(default),
+    }
+}
+
+
+pub fn fn_randarray (args: &[EvalVal], sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // RANDARRAY([rows], [cols], [min], [max], [whole_number])
+    // a block of random numbers between min (default 0) and max (default 1),
+    // whole numbers between them (both included) if whole_number is TRUE
+    let n_rows = opt_num(args, 0, 1.0)?;
+    let n_cols = opt_num(args, 1, 1.0)?;
+    if n_rows < 0.0 || n_cols < 0.0 {
+        return Err(EvalErr::Value)
+    }
+    let (n_rows, n_cols) = (n_rows as usize, n_cols as usize);
+    if n_rows == 0 || n_cols == 0 {
+        return Err(EvalErr::Calc)
+    }
+    if n_rows.saturating_mul(n_cols) > MAX_CELLS {
+        return Err(EvalErr::Num)
+    }
+    let (low, high) = (opt_num(args, 2, 0.0)?, opt_num(args, 3, 1.0)?);
+    if low > high {
+        return Err(EvalErr::Value)
+    }
+    let whole = match args.get(4) {
+        Some(val) => formulas::arg_bool(val)?,
+        None => false,
+    };
+    let vals = if whole {
+        let (low, high) = bounds(low, high)?;
+        sheet.random(|rng| (0..n_rows * n_cols).map(|_| Some(CellVal::Int(rng.between(low, high)))).collect())
+    } else {
+        sheet.random(|rng| (0..n_rows * n_cols).map(|_| Some(CellVal::Real(low + rng.next_f64() * (high - low)))).collect())
+    };
+    Ok(EvalVal::Grid(CellGrid { n_rows, n_cols, vals }))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn rng_seeded () {
+        // the same seed gives the same numbers and they stay in range
+        let (mut a, mut b) = (Rng::new(42), Rng::new(42));
+        for _ in 0..1000 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        for _ in 0..1000 {
+            let x = a.next_f64();
+            assert!((0.0..1.0).contains(&x), "{} out of range", x);
+            let n = a.between(-3, 3);
+            assert!((-3..=3).contains(&n), "{} out of range", n);
+        }
+        assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+        // every value shows up about as often as the others
+        let mut counts = [0; 6];
+        for _ in 0..60000 {
+            counts[a.below(6) as usize] += 1;
+        }
+        assert!(counts.iter().all(|n| (9000..11000).contains(n)), "uneven counts {:?}", counts);
+        // the widest range does not overflow
+        a.between(i64::MIN, i64::MAX);
+    }
+}
@@ -0,0 +1,556 @@
+/*
+    Module with a small regular expression engine for the REGEX* formula
+    functions (and the wildcards in SEARCH)
+
+    supports literals, ., character classes ([a-z], [^0-9], \d \w \s and
+    the upper case opposites), anchors (^ $ \b \B), groups (capturing and
+    (?:...)), alternation (|), the quantifiers * + ? {n} {n,} {n,m} (lazy
+    with a ? after them) and (?i) at the start for ignoring case
+
+    patterns are compiled to a list of instructions that is run on all of
+    the ways it could be matching at once (a Pike VM) instead of trying
+    them one after the other, so matching takes time proportional to the
+    length of the text times the length of the program and never recurses
+*/
+
+
+// longest program a pattern can compile to (e.g. for a{1000}{1000})
+const MAX_PROGRAM: usize = 10_000;
+// most instructions find_at follows before giving up
+const MAX_STEPS: usize = 10_000_000;
+
+
+#[derive(Debug, Clone, Copy)]
+enum Perl {
+    // \d, \w and \s
+    Digit,
+    Word,
+    Space,
+}
+
+
+impl Perl {
+    fn matches (&self, c: char) -> bool {
+        match self {
+            Perl::Digit => c.is_ascii_digit(),
+            Perl::Word => c.is_alphanumeric() || c == '_',
+            Perl::Space => c.is_whitespace(),
+        }
+    }
+}
+
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Range(char, char),
+    // \d and friends inside of [], with whether it is the opposite (e.g. \D)
+    Perl(Perl, bool),
+}
+
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    // . (anything but a new line)
+    Any,
+    Class { items: Vec<ClassItem>, negated: bool },
+    Start,
+    End,
+    // \b, or \B when false
+    WordBoundary(bool),
+    // with the capture group number, None for (?:...)
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize>, greedy: bool },
+}
+
+
+#[derive(Debug, Clone)]
+enum Inst {
+    // the ones that take a character
+    Char(char),
+    Any,
+    Class { items: Vec<ClassItem>, negated: bool },
+    // the ones that take nothing
+    Start,
+    End,
+    WordBoundary(bool),
+    // go on at both, the first one first
+    Split(usize, usize),
+    Jump(usize),
+    // where a capture group starts (even slots) or ends (odd slots)
+    Save(usize),
+    Match,
+}
+
+
+// find_at gave up (see MAX_STEPS)
+#[derive(Debug)]
+pub struct TooManySteps;
+
+
+// where each capture group matched (start and end character offsets),
+// the whole match is group 0
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+
+#[derive(Debug)]
+pub struct Regex {
+    prog: Vec<Inst>,
+    n_groups: usize,
+    ignore_case: bool,
+}
+
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    n_groups: usize,
+}
+
+
+impl Parser {
+    fn peek (&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat (&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true
+        }
+        false
+    }
+
+    fn parse_alt (&mut self) -> Option<Node> {
+        // branches separated by |
+        let mut branches = vec![self.parse_concat()?];
+        while self.eat('|') {
+            branches.push(self.parse_concat()?);
+        }
+        if branches.len() == 1 {
+            return branches.pop()
+        }
+        Option::Some(Node::Alt(branches))
+    }
+
+    fn parse_concat (&mut self) -> Option<Node> {
+        let mut nodes = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.parse_atom()?;
+            nodes.push(self.parse_repeat(atom)?);
+        }
+        Option::Some(Node::Concat(nodes))
+    }
+
+    fn parse_number (&mut self) -> Option<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect::<String>().parse::<usize>().ok()
+    }
+
+    fn parse_repeat (&mut self, atom: Node) -> Option<Node> {
+        // a quantifier after an atom, if there is one
+        let (min, max) = match self.peek() {
+            Some('*') => (0, Option::None),
+            Some('+') => (1, Option::None),
+            Some('?') => (0, Option::Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.parse_number()?;
+                let max = if self.eat(',') {
+                    if self.peek() == Some('}') { Option::None } else { Option::Some(self.parse_number()?) }
+                } else {
+                    Option::Some(min)
+                };
+                if self.peek() != Some('}') || max.is_some_and(|max| max < min) {
+                    return Option::None
+                }
+                (min, max)
+            },
+            _ => return Option::Some(atom),
+        };
+        self.pos += 1;
+        if matches!(atom, Node::Start | Node::End | Node::WordBoundary(_)) {
+            return Option::None
+        }
+        let greedy = !self.eat('?');
+        if matches!(self.peek(), Some('*' | '+' | '?' | '{')) {
+            return Option::None
+        }
+        Option::Some(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+
+    fn parse_escape (&mut self) -> Option<Node> {
+        // whatever comes after a \ outside of []
+        let c = self.peek()?;
+        self.pos += 1;
+        let class = |perl, negated| Node::Class { items: vec![ClassItem::Perl(perl, false)], negated };
+        Option::Some(match c {
+            'd' => class(Perl::Digit, false),
+            'D' => class(Perl::Digit, true),
+            'w' => class(Perl::Word, false),
+            'W' => class(Perl::Word, true),
+            's' => class(Perl::Space, false),
+            'S' => class(Perl::Space, true),
+            'b' => Node::WordBoundary(true),
+            'B' => Node::WordBoundary(false),
+            c => Node::Char(Parser::escaped_char(c)?),
+        })
+    }
+
+    fn escaped_char (c: char) -> Option<char> {
+        // \n, \t, \r or punctuation standing for itself, letters and
+        // digits with no special meaning are mistakes
+        match c {
+            'n' => Option::Some('\n'),
+            't' => Option::Some('\t'),
+            'r' => Option::Some('\r'),
+            c if c.is_alphanumeric() => Option::None,
+            c => Option::Some(c),
+        }
+    }
+
+    fn parse_class (&mut self) -> Option<Node> {
+        // [...] after the [
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        let mut first = true;
+        loop {
+            let c = self.peek()?;
+            self.pos += 1;
+            if c == ']' && !first {
+                break;
+            }
+            first = false;
+            let low = if c == '\\' {
+                let e = self.peek()?;
+                self.pos += 1;
+                match e {
+                    'd' | 'D' => { items.push(ClassItem::Perl(Perl::Digit, e == 'D')); continue; },
+                    'w' | 'W' => { items.push(ClassItem::Perl(Perl::Word, e == 'W')); continue; },
+                    's' | 'S' => { items.push(ClassItem::Perl(Perl::Space, e == 'S')); continue; },
+                    e => Parser::escaped_char(e)?,
+                }
+            } else {
+                c
+            };
+            // a - between two characters is a range, anywhere else it is just a -
+            if self.peek() == Some('-') && self.chars.get(self.pos + 1).is_some_and(|c| *c != ']') {
+                self.pos += 1;
+                let mut high = self.peek()?;
+                self.pos += 1;
+                if high == '\\' {
+                    high = Parser::escaped_char(self.peek()?)?;
+                    self.pos += 1;
+                }
+                if high < low {
+                    return Option::None
+                }
+                items.push(ClassItem::Range(low, high));
+            } else {
+                items.push(ClassItem::Range(low, low));
+            }
+        }
+        Option::Some(Node::Class { items, negated })
+    }
+
+    fn parse_atom (&mut self) -> Option<Node> {
+        let c = self.peek()?;
+        self.pos += 1;
+        match c {
+            '.' => Option::Some(Node::Any),
+            '^' => Option::Some(Node::Start),
+            '$' => Option::Some(Node::End),
+            '\\' => self.parse_escape(),
+            '[' => self.parse_class(),
+            '(' => {
+                let group = if self.eat('?') {
+                    if !self.eat(':') {
+                        return Option::None
+                    }
+                    Option::None
+                } else {
+                    self.n_groups += 1;
+                    Option::Some(self.n_groups)
+                };
+                let inner = self.parse_alt()?;
+                if !self.eat(')') {
+                    return Option::None
+                }
+                Option::Some(Node::Group(Box::new(inner), group))
+            },
+            // a quantifier with nothing to repeat or a ) without a (
+            '*' | '+' | '?' | '{' | ')' => Option::None,
+            c => Option::Some(Node::Char(c)),
+        }
+    }
+}
+
+
+impl Regex {
+    pub fn new (pattern: &str) -> Option<Regex> {
+        // None if the pattern is not a valid regular expression
+        let (pattern, ignore_case) = match pattern.strip_prefix("(?i)") {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false),
+        };
+        Regex::with_case(pattern, ignore_case)
+    }
+
+    pub fn with_case (pattern: &str, ignore_case: bool) -> Option<Regex> {
+        let mut parser = Parser { chars: pattern.chars().collect(), pos: 0, n_groups: 0 };
+        let root = parser.parse_alt()?;
+        // anything left over is an unmatched )
+        if parser.pos < parser.chars.len() {
+            return Option::None
+        }
+        let mut prog = Vec::new();
+        compile(&root, &mut prog)?;
+        prog.push(Inst::Match);
+        Option::Some(Regex { prog, n_groups: parser.n_groups, ignore_case })
+    }
+
+    pub fn n_groups (&self) -> usize {
+        // not counting the whole match
+        self.n_groups
+    }
+
+    pub fn find_at (&self, text: &[char], start: usize) -> Result<Option<Captures>, TooManySteps> {
+        // the first (leftmost) match starting at or after start, the threads 
+        // are kept in the order a backtracker would have tried them, so the 
+        // first one to get to Match is the one it would have found
+        let mut threads: Vec<(usize, Vec<Option<usize>>)> = Vec::new();
+        let mut next = Vec::new();
+        // the position each instruction was last added to threads at
+        let mut seen = vec![usize::MAX; self.prog.len()];
+        let mut steps = 0;
+        let mut found = Option::None;
+        for pos in start..=text.len() {
+            // one more thread starting here, after the ones that started 
+            // earlier, until something has matched
+            if found.is_none() {
+                let mut slots = vec![Option::None; 2 * (self.n_groups + 1)];
+                slots[0] = Option::Some(pos);
+                self.add_thread(&mut threads, &mut seen, 0, slots, text, pos, &mut steps)?;
+            }
+            if threads.is_empty() && found.is_some() {
+                break;
+            }
+            for (pc, mut slots) in threads.drain(..) {
+                match &self.prog[pc] {
+                    Inst::Match => {
+                        // the threads after this one would only be worse matches
+                        slots[1] = Option::Some(pos);
+                        found = Option::Some(slots);
+                        break;
+                    },
+                    inst => {
+                        if pos < text.len() && self.takes(inst, text[pos]) {
+                            self.add_thread(&mut next, &mut seen, pc + 1, slots, text, pos + 1, &mut steps)?;
+                        }
+                    },
+                }
+            }
+            std::mem::swap(&mut threads, &mut next);
+        }
+        Ok(found.map(|slots| slots.chunks(2).map(|pair| match pair {
+            [Some(s), Some(e)] => Option::Some((*s, *e)),
+            _ => Option::None,
+        }).collect()))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn add_thread (&self, threads: &mut Vec<(usize, Vec<Option<usize>>)>, seen: &mut [usize], pc: usize, mut slots: Vec<Option<usize>>,
+                   text: &[char], pos: usize, steps: &mut usize) -> Result<(), TooManySteps> {
+        // follows the instructions that take nothing from pc (with a stack 
+        // of what is left to do instead of recursing) and adds a thread for 
+        // each one that takes a character, an instruction that already has 
+        // a thread at pos had it added by a thread that comes first
+        enum Todo {
+            Visit(usize),
+            // put a slot back the way it was before going down the other 
+            // side of a Split
+            Restore(usize, Option<usize>),
+        }
+        let mut todo = vec![Todo::Visit(pc)];
+        while let Some(item) = todo.pop() {
+            let pc = match item {
+                Todo::Visit(pc) => pc,
+                Todo::Restore(slot, val) => { slots[slot] = val; continue; },
+            };
+            if seen[pc] == pos {
+                continue;
+            }
+            seen[pc] = pos;
+            *steps += 1;
+            if *steps > MAX_STEPS {
+                return Err(TooManySteps)
+            }
+            match &self.prog[pc] {
+                Inst::Jump(to) => todo.push(Todo::Visit(*to)),
+                Inst::Split(first, second) => {
+                    todo.push(Todo::Visit(*second));
+                    todo.push(Todo::Visit(*first));
+                },
+                Inst::Save(slot) => {
+                    todo.push(Todo::Restore(*slot, slots[*slot]));
+                    slots[*slot] = Option::Some(pos);
+                    todo.push(Todo::Visit(pc + 1));
+                },
+                Inst::Start => if pos == 0 { todo.push(Todo::Visit(pc + 1)) },
+                Inst::End => if pos == text.len() { todo.push(Todo::Visit(pc + 1)) },
+                Inst::WordBoundary(want) => {
+                    let word = |i: Option<&char>| i.is_some_and(|c| Perl::Word.matches(*c));
+                    let boundary = word(pos.checked_sub(1).and_then(|i| text.get(i))) != word(text.get(pos));
+                    if boundary == *want {
+                        todo.push(Todo::Visit(pc + 1));
+                    }
+                },
+                _ => threads.push((pc, slots.clone())),
+            }
+        }
+        Ok(())
+    }
+
+    fn takes (&self, inst: &Inst, c: char) -> bool {
+        // whether an instruction that takes a character takes c
+        match inst {
+            Inst::Char(want) => self.same_char(c, *want),
+            Inst::Any => c != '\n',
+            Inst::Class { items, negated } => self.class_matches(items, *negated, c),
+            _ => false,
+        }
+    }
+
+    fn same_char (&self, a: char, b: char) -> bool {
+        a == b || (self.ignore_case && a.to_lowercase().eq(b.to_lowercase()))
+    }
+
+    fn class_matches (&self, items: &[ClassItem], negated: bool, c: char) -> bool {
+        let in_class = |c: char| items.iter().any(|item| match item {
+            ClassItem::Range(low, high) => *low <= c && c <= *high,
+            ClassItem::Perl(perl, negated) => perl.matches(c) != *negated,
+        });
+        let found = in_class(c) || (self.ignore_case && (c.to_lowercase().any(in_class) || c.to_uppercase().any(in_class)));
+        found != negated
+    }
+}
+
+
+fn compile (node: &Node, prog: &mut Vec<Inst>) -> Option<()> {
+    // appends the instructions for node, None if there would be too many
+    if prog.len() > MAX_PROGRAM {
+        return Option::None
+    }
+    // a Split to fill in once where it goes on to is known
+    let split = |greedy: bool, body: usize, out: usize| if greedy { Inst::Split(body, out) } else { Inst::Split(out, body) };
+    match node {
+        Node::Char(c) => prog.push(Inst::Char(*c)),
+        Node::Any => prog.push(Inst::Any),
+        Node::Class { items, negated } => prog.push(Inst::Class { items: items.clone(), negated: *negated }),
+        Node::Start => prog.push(Inst::Start),
+        Node::End => prog.push(Inst::End),
+        Node::WordBoundary(want) => prog.push(Inst::WordBoundary(*want)),
+        Node::Group(inner, group) => {
+            if let Some(group) = group {
+                prog.push(Inst::Save(2 * group));
+            }
+            compile(inner, prog)?;
+            if let Some(group) = group {
+                prog.push(Inst::Save(2 * group + 1));
+            }
+        },
+        Node::Concat(nodes) => {
+            for node in nodes {
+                compile(node, prog)?;
+            }
+        },
+        Node::Alt(branches) => {
+            // each branch but the last is Split(branch, next branch) and
+            // jumps past the others when it is done
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 == branches.len() {
+                    compile(branch, prog)?;
+                    break;
+                }
+                let at = prog.len();
+                prog.push(Inst::Jump(0));
+                compile(branch, prog)?;
+                jumps.push(prog.len());
+                prog.push(Inst::Jump(0));
+                prog[at] = Inst::Split(at + 1, prog.len());
+            }
+            for at in jumps {
+                prog[at] = Inst::Jump(prog.len());
+            }
+        },
+        Node::Repeat { node, min, max, greedy } => {
+            for _ in 0..*min {
+                compile(node, prog)?;
+            }
+            match max {
+                None => {
+                    let at = prog.len();
+                    prog.push(Inst::Jump(0));
+                    compile(node, prog)?;
+                    prog.push(Inst::Jump(at));
+                    prog[at] = split(*greedy, at + 1, prog.len());
+                },
+                Some(max) => {
+                    // each one after min can be left out, along with the rest
+                    let mut ats = Vec::new();
+                    for _ in *min..*max {
+                        ats.push(prog.len());
+                        prog.push(Inst::Jump(0));
+                        compile(node, prog)?;
+                    }
+                    for at in ats {
+                        prog[at] = split(*greedy, at + 1, prog.len());
+                    }
+                },
+            }
+        },
+    }
+    Option::Some(())
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn find (pattern: &str, text: &str) -> Option<Vec<Option<String>>> {
+        let text: Vec<char> = text.chars().collect();
+        let caps = Regex::new(pattern).unwrap().find_at(&text, 0).unwrap()?;
+        Option::Some(caps.iter().map(|cap| cap.map(|(s, e)| text[s..e].iter().collect())).collect())
+    }
+
+    #[test]
+    fn regex_matching () {
+        let s = |s: &str| Option::Some(String::from(s));
+        assert_eq!(find("b+", "abbbc"), Option::Some(vec![s("bbb")]));
+        assert_eq!(find("b+?", "abbbc"), Option::Some(vec![s("b")]));
+        assert_eq!(find(r"#(\d{3,5})-([A-Z]+)?", "order #12345-x"), Option::Some(vec![s("#12345-"), s("12345"), Option::None]));
+        assert_eq!(find(r"(?i)ORD-(\w+)", "see ord-A1 today"), Option::Some(vec![s("ord-A1"), s("A1")]));
+        assert_eq!(find(r"^(cat|dog)s?$", "dogs"), Option::Some(vec![s("dogs"), s("dog")]));
+        assert_eq!(find(r"\bis\b", "this is"), Option::Some(vec![s("is")]));
+        assert_eq!(find("[^a-c]+", "abcxyz"), Option::Some(vec![s("xyz")]));
+        assert_eq!(find("[a-]+", "b-a-c"), Option::Some(vec![s("-a-")]));
+        assert_eq!(find(r"a.c", "a\nc"), Option::None);
+        assert_eq!(find("(a*)*b", "aaac"), Option::None);
+        assert_eq!(find("x*", "abc"), Option::Some(vec![s("")]));
+        assert_eq!(find(r"(?:ab){2}", "ababab"), Option::Some(vec![s("abab")]));
+        for pattern in ["(", "a)", "*a", "a**", "[a", "[z-a]", r"\q", "a{2,1}", "a{x}", "(?x)", "^*", "a{100000}", "(?:a{1000}){1000}"] {
+            assert!(Regex::new(pattern).is_none(), "{} should not be a valid pattern", pattern);
+        }
+    }
+}
@@ -4,12 +4,40 @@
 
 
 use std::io::{self, BufRead, Write};
+use std::fmt;
 use std::fs;
 use std::path;
 use std::mem;
 use std::cmp;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
 
-use crate::{dtypes, parsing};
+use crate::{dtypes, parsing, formulas, datetime, random, locale, decimal};
+
+
+// the last column a reference from OFFSET can reach (ZZZZZZ)
+const MAX_COL_INDEX: i64 = 321_272_405;
+// the most cells a range can cover, all of them have to be held at once
+const MAX_REF_CELLS: i64 = 1_000_000;
+
+// goal seek is done once the formula is this close to the target (scaled
+// up for big targets) and gives up on a method after this many tries
+const GOAL_SEEK_TOLERANCE: f64 = 1e-9;
+const GOAL_SEEK_ITERATIONS: usize = 100;
+
+
+// version of the sheet.txt format written by save_sheet
+// 1: Int stored as i32 (files without a #version line)
+// 2: Int stored as i64
+// 3: defined names ("#name <name> <formula>" lines)
+// 4: random number seed ("#seed <n>" line)
+// 5: iterative calculation settings ("#iterate <max_iterations> <max_change>" line)
+// 6: scenarios ("#scenario <name> <loc> [<val>]" lines)
+// 7: strict coercion ("#strict" line)
+// 8: locale ("#locale <name>" line)
+pub const FORMAT_VERSION: usize = 8;
 
 
 fn read_lines<P> (filename: P) -> io::Result<io::Lines<io::BufReader<fs::File>>>
@@ -24,18 +52,315 @@ where P: AsRef<path::Path>, {
 }
 
 
+#[derive(Debug, Default)]
+struct CellHasher(u64);
+
+
+impl Hasher for CellHasher {
+    // the maps keyed by (column index, row) get looked in for every formula
+    // that is evaluated, a multiply is plenty for those (the keys are not
+    // picked to collide) and a lot quicker than the default SipHash
+    fn finish (&self) -> u64 {
+        self.0
+    }
+
+    fn write (&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.write_u64(*b as u64);
+        }
+    }
+
+    fn write_u64 (&mut self, n: u64) {
+        self.0 = (self.0.rotate_left(5) ^ n).wrapping_mul(0x517c_c1b7_2722_0a95);
+    }
+
+    fn write_usize (&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+}
+
+
+type CellMap<V> = HashMap<(usize, usize), V, BuildHasherDefault<CellHasher>>;
+type CellSet = HashSet<(usize, usize), BuildHasherDefault<CellHasher>>;
+
+
+// a parsed formula ready to be evaluated or the error it gives instead
+type Compiled = Rc<Result<dtypes::TknNode, dtypes::EvalErr>>;
+
+
+#[derive(Debug)]
+enum Step {
+    // one step of a compiled formula (see Sheet::run), each one leaves 
+    // its value on the stack
+    Val(dtypes::CellVal),
+    Fail(dtypes::EvalErr),
+    // a cell (column index, row), empty is an error unless it is the 
+    // operand of an operator (see Sheet::eval_operand)
+    Cell(usize, usize),
+    Operand(usize, usize),
+    // takes its operands off of the stack
+    BinOp(dtypes::Op),
+    Neg,
+    // a builtin with its arguments on top of the stack, which only 
+    // get evaluated after Volatile for the ones that are
+    Volatile,
+    Call(&'static formulas::Builtin, usize),
+    // anything else (e.g. ranges, LET or defined names) is evaluated the 
+    // way the tree is, as a function argument for values only or not
+    Tree(Box<dtypes::TknNode>, bool),
+}
+
+
+#[derive(Debug)]
+struct Program {
+    // the steps a formula cell compiles to, with the cells it refers to and
+    // the builtins it calls already looked up so that running them is
+    // quicker than evaluating the tree (which is not kept, explain and
+    // precedents parse the formula again)
+    steps: Vec<Step>,
+}
+
+
+#[derive(Debug, Default)]
+struct Results {
+    // formula cells that have already been evaluated
+    vals: CellMap<Result<dtypes::EvalVal, dtypes::EvalErr>>,
+    // the spill index: the formula cells whose results spill (in column-major
+    // order) and for each cell they spill into the one it gets its value
+    // from (the first one if they overlap)
+    anchors: BTreeSet<(usize, usize)>,
+    spilled: CellMap<(usize, usize)>,
+    // for each column, the row every formula at or above has been evaluated
+    // up to (see check_spills) and the first row with a volatile formula
+    checked: Vec<usize>,
+    volatile_rows: Vec<usize>,
+}
+
+
+impl Results {
+    fn get (&self, cell: &(usize, usize)) -> Option<&Result<dtypes::EvalVal, dtypes::EvalErr>> {
+        self.vals.get(cell)
+    }
+
+    fn insert (&mut self, cell: (usize, usize), res: Result<dtypes::EvalVal, dtypes::EvalErr>) {
+        if let Ok(dtypes::EvalVal::Grid(grid)) = &res {
+            self.add_spill(cell, grid);
+        }
+        self.vals.insert(cell, res);
+    }
+
+    fn add_spill (&mut self, (c, r): (usize, usize), grid: &dtypes::CellGrid) {
+        if !self.anchors.insert((c, r)) {
+            return
+        }
+        for row in r..r + grid.n_rows {
+            for col_idx in c..c + grid.n_cols {
+                if (col_idx, row) != (c, r) {
+                    let anchor = self.spilled.entry((col_idx, row)).or_insert((c, r));
+                    *anchor = cmp::min(*anchor, (c, r));
+                }
+            }
+        }
+    }
+
+    fn column (&mut self, col_idx: usize) -> (&mut usize, &mut usize) {
+        // checked and volatile_rows for a column
+        if self.checked.len() <= col_idx {
+            self.checked.resize(col_idx + 1, 0);
+            self.volatile_rows.resize(col_idx + 1, usize::MAX);
+        }
+        (&mut self.checked[col_idx], &mut self.volatile_rows[col_idx])
+    }
+
+    fn check (&mut self, col_idx: usize, row: usize) {
+        // every formula in a column down to row has been evaluated
+        let (checked, _) = self.column(col_idx);
+        *checked = cmp::max(*checked, row);
+    }
+
+    fn check_volatile (&mut self, col_idx: usize, row: usize) {
+        let (_, volatile_row) = self.column(col_idx);
+        *volatile_row = cmp::min(*volatile_row, row);
+    }
+
+    fn remove (&mut self, cell: &(usize, usize)) {
+        // the formula could spill somewhere else (or another one could show 
+        // up where it spilled) so the spill index starts over
+        self.vals.remove(cell);
+        self.anchors.clear();
+        self.spilled.clear();
+        self.checked.clear();
+        self.volatile_rows.clear();
+    }
+
+    fn clear (&mut self) {
+        // keeps the room it has for the next time through
+        self.vals.clear();
+        self.anchors.clear();
+        self.spilled.clear();
+        self.checked.clear();
+        self.volatile_rows.clear();
+    }
+}
+
+
+#[derive(Debug)]
+pub struct TraceStep {
+    // one part of a formula that got evaluated, depth is how far down the 
+    // tree it is (calls to LAMBDAs and defined names go further down)
+    pub depth: usize,
+    pub node: dtypes::TknNode,
+    pub result: Result<dtypes::EvalVal, dtypes::EvalErr>,
+}
+
+
+#[derive(Debug)]
+pub struct Explanation {
+    // how the formula in a cell got its value, see Sheet::explain
+    pub loc: dtypes::CellLoc,
+    pub formula: String,
+    pub tree: Result<formulas::TknTree, dtypes::ParseError>,
+    // in the order they were evaluated, so the operands of anything come before it
+    pub steps: Vec<TraceStep>,
+    pub result: Result<dtypes::EvalVal, dtypes::EvalErr>,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct Iteration {
+    // settings for iterative calculation, see Sheet::calculate
+    pub max_iterations: usize,
+    // it has settled once no formula changes by more than this between iterations
+    pub max_change: f64,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+pub struct Convergence {
+    // how iterative calculation went, max_change is the most any formula 
+    // changed in the last iteration
+    pub iterations: usize,
+    pub converged: bool,
+    pub max_change: f64,
+}
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GoalSeekErr {
+    // why Sheet::goal_seek could not find a value
+    NotAFormula,
+    NotANumber,
+    NoSolution,
+}
+
+
+impl fmt::Display for GoalSeekErr {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            GoalSeekErr::NotAFormula => "the cell to set has to have a formula in it",
+            GoalSeekErr::NotANumber => "the cell to change has to be empty or have a number in it",
+            GoalSeekErr::NoSolution => "could not find a value that gets the formula to the target",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+
 #[derive(Debug)]
 pub struct Sheet {
     cols: Vec<Vec<dtypes::Cell>>,
     pub n_cols: usize,
     pub n_rows: usize,
+    // where TODAY() and NOW() get the current time from
+    clock: Box<dyn datetime::Clock>,
+    // where RAND() and friends get their numbers from and the seed for
+    // it that gets saved with the sheet (if there is one)
+    rng: RefCell<random::Rng>,
+    seed: Option<u64>,
+    // iterative calculation settings (saved with the sheet), with these a 
+    // formula that needs its own value gets the one from the last iteration
+    // instead of #CIRC!, the values of the formulas from the last iteration
+    // and whether a circular reference was hit in this one
+    iteration: Option<Iteration>,
+    iterated: RefCell<HashMap<(usize, usize), dtypes::EvalVal>>,
+    circ_hit: Cell<bool>,
+    // what operators do with text, TRUE/FALSE and empty cells (saved with the sheet)
+    coercion: formulas::Coercion,
+    // how numbers are written when they are typed in and in read_sheet 
+    // --localized (saved with the sheet), sheet.txt does not use it
+    locale: Option<locale::Locale>,
+    // formula cells (column index, row) that are in the middle of being 
+    // evaluated, a formula that needs its own value finds itself in here
+    evaluating: RefCell<Vec<(usize, usize)>>,
+    // formula cells that have already been evaluated and what they spill, 
+    // these get thrown out whenever anything in the sheet changes
+    results: RefCell<Results>,
+    // parsed formulas so each one only gets parsed once, a cell's entry is 
+    // thrown out when the cell is written or deleted (see compiled_at)
+    compiled: RefCell<CellMap<Rc<Program>>>,
+    // same for defined names, thrown out whenever a name changes
+    compiled_names: RefCell<HashMap<String, Compiled>>,
+    // formula cells that use a volatile function (e.g. RAND), directly or
+    // through other cells, their results are thrown out by recalculate, and
+    // whether the formula being evaluated has used one so far
+    volatile: RefCell<CellSet>,
+    volatile_hit: Cell<bool>,
+    // steps recorded while explaining a formula (see explain) and how
+    // deep in the tree the evaluation is
+    trace: RefCell<Option<Vec<TraceStep>>>,
+    trace_depth: Cell<usize>,
+    // functions added with register_function, by upper case name
+    functions: HashMap<String, Box<dyn formulas::Function>>,
+    // defined names (upper case) and the formulas they stand for
+    names: BTreeMap<String, String>,
+    // scenarios, named sets of values for input cells (None for empty) that
+    // can be switched between, see show_scenario
+    scenarios: BTreeMap<String, Vec<(dtypes::CellLoc, Option<dtypes::CellVal>)>>,
+}
+
+
+pub struct SheetView<'a> {
+    // read-only access to a sheet for formulas::Function implementations
+    sheet: &'a Sheet,
+}
+
+
+impl SheetView<'_> {
+    pub fn cell (&self, loc: &dtypes::CellLoc) -> Result<Option<dtypes::CellVal>, dtypes::EvalErr> {
+        // the value that shows up in a cell (formulas are evaluated), None if it is empty
+        self.sheet.cell_val(Sheet::col_to_index(&loc.col), loc.row)
+    }
+
+    pub fn range (&self, start: &dtypes::CellLoc, end: &dtypes::CellLoc) -> Result<dtypes::CellGrid, dtypes::EvalErr> {
+        // the values in the block of cells with start and end at opposite corners
+        self.sheet.get_range(start, end)
+    }
+
+    pub fn now (&self) -> datetime::DateTime {
+        self.sheet.now()
+    }
+
+    pub fn n_cols (&self) -> usize {
+        self.sheet.n_cols
+    }
+
+    pub fn n_rows (&self) -> usize {
+        self.sheet.n_rows
+    }
+}
+
+
+impl Default for Sheet {
+    fn default () -> Self {
+        Sheet::new()
+    }
 }
 
 
 impl Sheet {
     pub fn new () -> Sheet {
         let cols: Vec<Vec<dtypes::Cell>> = Vec::new();
-        let sheet = Sheet {
+        Sheet {
             cols,
             // the data in the sheet are stored sparse so only
             // cells with actual values are explicitly stored
@@ -44,8 +369,195 @@ impl Sheet {
             // all of the cells with explicit values
             n_cols: 0,
             n_rows: 0,
+            clock: Box::new(datetime::SystemClock),
+            rng: RefCell::new(random::Rng::from_time()),
+            seed: Option::None,
+            iteration: Option::None,
+            iterated: RefCell::new(HashMap::new()),
+            circ_hit: Cell::new(false),
+            coercion: formulas::Coercion::Lenient,
+            locale: Option::None,
+            evaluating: RefCell::new(Vec::new()),
+            results: RefCell::new(Results::default()),
+            compiled: RefCell::new(CellMap::default()),
+            compiled_names: RefCell::new(HashMap::new()),
+            volatile: RefCell::new(CellSet::default()),
+            volatile_hit: Cell::new(false),
+            trace: RefCell::new(Option::None),
+            trace_depth: Cell::new(0),
+            functions: HashMap::new(),
+            names: BTreeMap::new(),
+            scenarios: BTreeMap::new(),
+        }
+    }
+
+    pub fn set_clock (&mut self, clock: Box<dyn datetime::Clock>) {
+        // replace the clock used by TODAY() and NOW() (e.g. with a
+        // datetime::FixedClock so that evaluation is deterministic)
+        self.clock = clock;
+        self.results.get_mut().clear();
+    }
+
+    pub fn now (&self) -> datetime::DateTime {
+        self.clock.now()
+    }
+
+    pub fn set_seed (&mut self, seed: Option<u64>) {
+        // fix the seed for RAND() and friends (saved with the sheet) so
+        // that every run gives the same numbers, None goes back to a new
+        // seed every run
+        self.seed = seed;
+        self.seed_rng(seed);
+    }
+
+    pub fn seed_rng (&mut self, seed: Option<u64>) {
+        // start the random numbers over from seed without changing the saved setting
+        *self.rng.get_mut() = match seed {
+            Some(seed) => random::Rng::new(seed),
+            None => random::Rng::from_time(),
         };
-        sheet
+        self.results.get_mut().clear();
+    }
+
+    pub fn random<T> (&self, draw: impl FnOnce(&mut random::Rng) -> T) -> T {
+        // for the random number functions
+        draw(&mut self.rng.borrow_mut())
+    }
+
+    pub fn recalculate (&self) -> Option<Convergence> {
+        // formulas using volatile functions get evaluated again the
+        // next time they are needed, everything else is still current
+        // unless iterative calculation is on (see calculate)
+        {
+            let mut results = self.results.borrow_mut();
+            for cell in self.volatile.borrow_mut().drain() {
+                results.remove(&cell);
+            }
+        }
+        self.calculate()
+    }
+
+    fn calculate (&self) -> Option<Convergence> {
+        // with iterative calculation on, every formula is evaluated over and 
+        // over (in column-major order, each one using the newest values of 
+        // the others) until none of them change by more than max_change or 
+        // there have been max_iterations iterations, None if it is off
+        let iteration = self.iteration?;
+        let mut cells = Vec::new();
+        for (c, col) in self.cols.iter().enumerate() {
+            for cell in col {
+                if let dtypes::CellVal::Formula(_) = cell.val {
+                    cells.push((c, cell.loc.row));
+                }
+            }
+        }
+        self.iterated.borrow_mut().clear();
+        let max_iterations = cmp::max(iteration.max_iterations, 1);
+        let mut max_change = f64::INFINITY;
+        for n in 1..=max_iterations {
+            self.results.borrow_mut().clear();
+            self.circ_hit.set(false);
+            let mut vals = HashMap::new();
+            for &(c, r) in &cells {
+                if let Ok(val) = self.eval_formula_at(c, r) {
+                    vals.insert((c, r), val);
+                }
+            }
+            // without any circular references one go is enough
+            if !self.circ_hit.get() {
+                return Option::Some(Convergence { iterations: n, converged: true, max_change: 0.0 })
+            }
+            if n > 1 {
+                let last = self.iterated.borrow();
+                max_change = vals.iter().map(|(cell, val)| Sheet::change(last.get(cell), val)).fold(0.0, f64::max);
+            }
+            *self.iterated.borrow_mut() = vals;
+            if max_change <= iteration.max_change {
+                return Option::Some(Convergence { iterations: n, converged: true, max_change })
+            }
+        }
+        Option::Some(Convergence { iterations: max_iterations, converged: false, max_change })
+    }
+
+    fn change (last: Option<&dtypes::EvalVal>, val: &dtypes::EvalVal) -> f64 {
+        // how much a formula changed between iterations, anything
+        // that is not a number counts as a change if it is different
+        match last {
+            Some(last) => match (formulas::arg_num(last), formulas::arg_num(val)) {
+                (Ok(a), Ok(b)) => (a - b).abs(),
+                _ if format!("{:?}", last) == format!("{:?}", val) => 0.0,
+                _ => f64::INFINITY,
+            },
+            None => f64::INFINITY,
+        }
+    }
+
+    pub fn view (&self) -> SheetView<'_> {
+        // read-only access to the evaluated values in the sheet
+        SheetView { sheet: self }
+    }
+
+    pub fn register_function (&mut self, func: Box<dyn formulas::Function>) {
+        // make a function callable from formulas in this sheet, it takes the
+        // place of any builtin or already registered function with the same name
+        self.results.get_mut().clear();
+        self.compiled.get_mut().clear();
+        self.functions.insert(func.name().to_ascii_uppercase(), func);
+    }
+
+    pub fn define_name (&mut self, name: &str, formula: &str) {
+        // give a formula a name that other formulas can use (case insensitive),
+        // a LAMBDA can be called like a function e.g. =MARGIN(A2,B2)
+        // the name needs to be checked with parsing::is_valid_name first
+        self.results.get_mut().clear();
+        let formula = match parsing::parse_formula_expr(&dtypes::CellVal::Formula(formula.to_string())) {
+            Ok(tree) => formulas::print_tree(&tree),
+            Err(_) => formula.to_string(),
+        };
+        // a name can take the place of a builtin in compiled formulas
+        self.compiled.get_mut().clear();
+        self.compiled_names.get_mut().clear();
+        self.names.insert(name.to_ascii_uppercase(), formula);
+    }
+
+    pub fn set_iteration (&mut self, iteration: Option<Iteration>) {
+        // turn iterative calculation on (saved with the sheet) or off
+        self.iteration = iteration;
+        self.results.get_mut().clear();
+        self.iterated.get_mut().clear();
+    }
+
+    pub fn iteration (&self) -> Option<Iteration> {
+        self.iteration
+    }
+
+    pub fn set_coercion (&mut self, coercion: formulas::Coercion) {
+        // switch between turning text and TRUE/FALSE into numbers in
+        // arithmetic or giving #VALUE! for them (saved with the sheet)
+        self.coercion = coercion;
+        self.results.get_mut().clear();
+    }
+
+    pub fn coercion (&self) -> formulas::Coercion {
+        self.coercion
+    }
+
+    pub fn set_locale (&mut self, locale: Option<locale::Locale>) {
+        // numbers typed in get parsed with this (see parsing::parse_val_in),
+        // None goes back to just 1234.56
+        self.locale = locale;
+    }
+
+    pub fn locale (&self) -> Option<locale::Locale> {
+        self.locale
+    }
+
+    pub fn delete_name (&mut self, name: &str) -> bool {
+        // returns a bool indicating whether there was a name to delete
+        self.results.get_mut().clear();
+        self.compiled.get_mut().clear();
+        self.compiled_names.get_mut().clear();
+        self.names.remove(&name.to_ascii_uppercase()).is_some()
     }
 
     pub fn load_sheet (&mut self) {
@@ -53,6 +565,9 @@ impl Sheet {
         // File sheet.txt must exist in the current path
         if let Ok(lines) = read_lines("./sheet.txt") {
             let mut n_cells: usize = 0;
+            // files written before the format was versioned do not have a 
+            // #version line, those are treated as version 1
+            let mut version: usize = 1;
             // Consumes the iterator, returns an (Optional) String
             for (i, line) in lines.enumerate() {
                 if i == 0 {
@@ -68,188 +583,1360 @@ impl Sheet {
                     }
                 } else {
                     // parse all of the rest of the lines as "<loc> <val>"
-                    
+                    // except for the "#version <n>", "#seed <n>", "#iterate <max_iterations> <max_change>",
+                    // "#strict", "#locale <name>", "#name <name> <formula>" and "#scenario <name> <loc> [<val>]" lines
                     if let Ok(line) = line {
-                        let (loc, val) = parsing::parse_line(&line);
-                        self.write_cell(loc, val);
-                        n_cells += 1;
+                        if let Some(v) = line.strip_prefix("#version ") {
+                            match v.trim().parse::<usize>() {
+                                Ok(v) => {
+                                    version = v;
+                                    eprintln!("sheet format version: {}", version);
+                                },
+                                Err(_) => eprintln!("skipping bad line: {}", line),
+                            }
+                            continue;
+                        }
+                        if let Some(seed) = line.strip_prefix("#seed ") {
+                            match seed.trim().parse::<u64>() {
+                                Ok(seed) => self.set_seed(Option::Some(seed)),
+                                Err(_) => eprintln!("skipping bad line: {}", line),
+                            }
+                            continue;
+                        }
+                        if let Some(settings) = line.strip_prefix("#iterate ") {
+                            match parsing::parse_iteration(settings) {
+                                Some((max_iterations, max_change)) => self.set_iteration(Option::Some(Iteration { max_iterations, max_change })),
+                                None => eprintln!("skipping bad line: {}", line),
+                            }
+                            continue;
+                        }
+                        if let Some(name) = line.strip_prefix("#locale ") {
+                            match locale::find_locale(name.trim()) {
+                                Some(locale) => self.set_locale(Option::Some(locale)),
+                                None => eprintln!("skipping bad line: {}", line),
+                            }
+                            continue;
+                        }
+                        if line.trim() == "#strict" {
+                            self.set_coercion(formulas::Coercion::Strict);
+                            continue;
+                        }
+                        if let Some(entry) = line.strip_prefix("#scenario ") {
+                            // one line for each cell, without a value if it is empty
+                            let cell = entry.split_once(' ').and_then(|(name, cell)| {
+                                let cell = match cell.split_once(' ') {
+                                    Some(_) => parsing::parse_line(cell).map(|(loc, val)| (loc, Option::Some(parsing::migrate_val(val, version)))),
+                                    None => parsing::parse_loc(cell).map(|loc| (loc, Option::None)),
+                                };
+                                cell.map(|cell| (name, cell))
+                            });
+                            match cell {
+                                Some((name, cell)) => self.scenarios.entry(String::from(name)).or_default().push(cell),
+                                None => eprintln!("skipping bad line: {}", line),
+                            }
+                            continue;
+                        }
+                        if let Some(def) = line.strip_prefix("#name ") {
+                            if let Some((name, formula)) = def.split_once(' ') {
+                                self.define_name(name, formula);
+                            }
+                            continue;
+                        }
+                        // a bad line only loses that one cell
+                        match parsing::parse_line(&line) {
+                            Some((loc, val)) => {
+                                self.write_cell(loc, parsing::migrate_val(val, version));
+                                n_cells += 1;
+                            },
+                            None => eprintln!("skipping bad line: {}", line),
+                        }
+                    }
+                }
+            }
+            eprintln!("loaded {} cells", n_cells);
+            if version < FORMAT_VERSION {
+                eprintln!("migrated sheet from format version {} to {}", version, FORMAT_VERSION);
+            }
+        }
+    }
+
+    pub fn save_sheet (&self) {
+        eprintln!("saving sheet");
+        let file = fs::File::create("./sheet.txt").unwrap();
+        let mut buf = io::BufWriter::new(file);
+        // first print <n_cols> <n_rows>s then the format version
+        buf.write_fmt(format_args!("{} {}\n", self.n_cols, self.n_rows)).unwrap();
+        buf.write_fmt(format_args!("#version {}\n", FORMAT_VERSION)).unwrap();
+        if let Some(seed) = self.seed {
+            buf.write_fmt(format_args!("#seed {}\n", seed)).unwrap();
+        }
+        if let Some(iteration) = self.iteration {
+            buf.write_fmt(format_args!("#iterate {} {:?}\n", iteration.max_iterations, iteration.max_change)).unwrap();
+        }
+        if self.coercion == formulas::Coercion::Strict {
+            buf.write_fmt(format_args!("#strict\n")).unwrap();
+        }
+        if let Some(locale) = self.locale {
+            buf.write_fmt(format_args!("#locale {}\n", locale.name)).unwrap();
+        }
+        for (name, formula) in &self.names {
+            buf.write_fmt(format_args!("#name {} {}\n", name, formula)).unwrap();
+        }
+        for (name, cells) in &self.scenarios {
+            for (loc, val) in cells {
+                match val {
+                    Some(val) => buf.write_fmt(format_args!("#scenario {} {}{} {:?}\n", name, loc.col, loc.row, val)).unwrap(),
+                    None => buf.write_fmt(format_args!("#scenario {} {}{}\n", name, loc.col, loc.row)).unwrap(),
+                }
+            }
+        }
+        for col in &self.cols {
+            for cell in col {
+                buf.write_fmt(format_args!("{}{} {:?}\n", cell.loc.col, cell.loc.row, cell.val)).unwrap();
+            }
+        }
+        buf.flush().unwrap();
+    }
+
+    fn col_to_index (col: &str) -> usize {
+        let mut idx: usize = 0;
+        let base: usize = 26;
+        // iterate index letters from right to left 
+        for (i, c) in col.chars().rev().enumerate() {
+            idx += base.pow(i as u32) * (c as usize - 64);
+        }
+        // 0 indexed
+        idx - 1
+    }
+
+    fn index_to_col (idx: usize) -> String {
+        // inverse of col_to_index
+        let mut col = Vec::new();
+        let mut n = idx + 1;
+        while n > 0 {
+            col.push((b'A' + ((n - 1) % 26) as u8) as char);
+            n = (n - 1) / 26;
+        }
+        col.iter().rev().collect()
+    }
+
+    pub fn add_col (&mut self) {
+        self.cols.push(Vec::new());
+        self.n_cols += 1;
+    }
+
+    pub fn add_row (&mut self) {
+        self.n_rows += 1;
+    }
+
+    pub fn write_cell (&mut self, loc: dtypes::CellLoc, val: dtypes::CellVal) {
+        self.results.get_mut().clear();
+        // formulas are stored in their canonical form (see formulas::print_tree),
+        // ones that do not parse are left as they are
+        let val = match parsing::parse_formula_expr(&val) {
+            Ok(tree) => dtypes::CellVal::Formula(formulas::print_tree(&tree)),
+            Err(_) => val,
+        };
+        // find out the column index, add columns if it is 
+        // beyond the current bounds of the sheet
+        let col_idx = Sheet::col_to_index(&loc.col);
+        self.compiled.get_mut().remove(&(col_idx, loc.row));
+        while col_idx >= self.n_cols {
+            self.add_col();
+        }
+        // now there are for sure enough columns in the sheet
+        // add the cell to the appropriate position in the column
+        // vector (sorted by row number)
+        let col = &mut self.cols[col_idx];
+        let row = loc.row;
+        let new_cell = dtypes::Cell {loc, val};
+        if col.is_empty() || row > col.last().unwrap().loc.row {
+            // add to end if cols is empty or row greater than row of last cell
+            col.push(new_cell);
+        } else {
+            // determine the insertion index of the cell
+            let mut ins_idx: usize = 0;
+            let mut ins_flg = true;
+            for cell in col.iter() {
+                if cell.loc.row == row {
+                    ins_flg = false;
+                    break;
+                }
+                if row < cell.loc.row {
+                    break;
+                } else {
+                    ins_idx += 1;
+                }
+            }
+            //
+            if ins_flg {
+                col.insert(ins_idx, new_cell);
+            } else {
+                // replace returns the element that was replaced
+                // so store that in a throwaway variable
+                let _ = mem::replace(&mut col[ins_idx], new_cell);
+            }
+        }
+        // update Sheet.n_rows if needed
+        if row > self.n_rows {
+            self.n_rows = row;
+        }
+    }
+
+    fn stored_cell (&self, col_idx: usize, row: usize) -> Option<&dtypes::CellVal> {
+        // the value actually stored in a cell (not evaluated)
+        // columns are sorted by row
+        let col = self.cols.get(col_idx)?;
+        let idx = col.binary_search_by_key(&row, |cell| cell.loc.row).ok()?;
+        Option::Some(&col[idx].val)
+    }
+
+    fn compile (formula: &dtypes::CellVal) -> Compiled {
+        // the tree that gets evaluated for a formula
+        let tree = match parsing::parse_formula_expr(formula) {
+            Ok(tree) => tree.root.map(|root| *root).ok_or(dtypes::EvalErr::Err),
+            Err(_) => Err(dtypes::EvalErr::Parse),
+        };
+        Rc::new(tree)
+    }
+
+    fn compiled_at (&self, col_idx: usize, row: usize) -> Rc<Program> {
+        // the compiled formula in a cell, compiled the first time it is needed
+        if let Some(program) = self.compiled.borrow().get(&(col_idx, row)) {
+            return program.clone()
+        }
+        let tree = match self.stored_cell(col_idx, row) {
+            Some(cell_val) => Sheet::compile(cell_val),
+            None => Rc::new(Err(dtypes::EvalErr::Err)),
+        };
+        let mut steps = Vec::new();
+        match tree.as_ref() {
+            Ok(root) => self.compile_steps(root, false, false, &mut steps),
+            Err(err) => steps.push(Step::Fail(*err)),
+        }
+        let program = Rc::new(Program { steps });
+        self.compiled.borrow_mut().insert((col_idx, row), program.clone());
+        program
+    }
+
+    fn compile_steps (&self, node: &dtypes::TknNode, operand: bool, values_only: bool, steps: &mut Vec<Step>) {
+        // the steps for (part of) a formula tree in the order the tree gets 
+        // evaluated in, operands and function arguments first
+        let tree = |steps: &mut Vec<Step>| steps.push(Step::Tree(Box::new(node.clone()), values_only));
+        match &node.token {
+            dtypes::FormToken::Num(num) => steps.push(Step::Val(num.clone())),
+            dtypes::FormToken::Str(text) => steps.push(Step::Val(dtypes::CellVal::Text(text.clone()))),
+            dtypes::FormToken::Bool(b) => steps.push(Step::Val(dtypes::CellVal::Bool(*b))),
+            dtypes::FormToken::Loc(loc) => {
+                let (col_idx, row) = (Sheet::col_to_index(&loc.col), loc.row);
+                steps.push(if operand { Step::Operand(col_idx, row) } else { Step::Cell(col_idx, row) });
+            },
+            dtypes::FormToken::BinOp(op) => match (&node.left, &node.right) {
+                (Some(left), Some(right)) => {
+                    self.compile_steps(left, true, false, steps);
+                    self.compile_steps(right, true, false, steps);
+                    steps.push(Step::BinOp(op.clone()));
+                },
+                _ => tree(steps),
+            },
+            dtypes::FormToken::Neg => match &node.left {
+                Some(left) => {
+                    self.compile_steps(left, true, false, steps);
+                    steps.push(Step::Neg);
+                },
+                None => tree(steps),
+            },
+            dtypes::FormToken::Func(name) => {
+                // defined names and registered functions come before the 
+                // builtins, and these are only compiled with the right 
+                // number of arguments (which are not evaluated otherwise)
+                let builtin = formulas::find_builtin(name).filter(|builtin| {
+                    !self.names.contains_key(name) && !self.functions.contains_key(name)
+                        && (builtin.min_args..=builtin.max_args).contains(&node.args.len())
+                        && !matches!(name.as_str(), "LET" | "LAMBDA")
+                });
+                let Some(builtin) = builtin else { return tree(steps) };
+                if builtin.volatile {
+                    steps.push(Step::Volatile);
+                }
+                for arg in &node.args {
+                    self.compile_steps(arg, false, builtin.values_only, steps);
+                }
+                steps.push(Step::Call(builtin, node.args.len()));
+            },
+            _ => tree(steps),
+        }
+    }
+
+    fn run (&self, steps: &[Step]) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // evaluate a compiled formula, the first error is the result
+        let scope = formulas::Scope::default();
+        let mut stack: Vec<Option<dtypes::EvalVal>> = Vec::new();
+        for step in steps {
+            let val = match step {
+                Step::Val(val) => dtypes::EvalVal::Val(val.clone()),
+                Step::Fail(err) => return Err(*err),
+                Step::Cell(col_idx, row) => dtypes::EvalVal::Val(self.cell_val(*col_idx, *row)?.ok_or(dtypes::EvalErr::Err)?),
+                Step::Operand(col_idx, row) => {
+                    stack.push(self.cell_val(*col_idx, *row)?.map(dtypes::EvalVal::Val));
+                    continue;
+                },
+                Step::BinOp(op) => {
+                    let right = stack.pop().flatten();
+                    let left = stack.pop().flatten();
+                    formulas::apply_binop_vals(op, left, right, self.coercion)?
+                },
+                Step::Neg => formulas::negate_vals(stack.pop().flatten(), self.coercion)?,
+                Step::Volatile => {
+                    self.volatile_hit.set(true);
+                    continue;
+                },
+                Step::Call(builtin, n_args) => {
+                    let args = stack.drain(stack.len() - n_args..)
+                        .map(|arg| arg.ok_or(dtypes::EvalErr::Err))
+                        .collect::<Result<Vec<dtypes::EvalVal>, dtypes::EvalErr>>()?;
+                    builtin.call(&args, self)?
+                },
+                Step::Tree(node, values_only) => self.eval_arg(node, &scope, *values_only)?,
+            };
+            stack.push(Option::Some(val));
+        }
+        stack.pop().flatten().ok_or(dtypes::EvalErr::Err)
+    }
+
+    fn spill (&self, col_idx: usize, row: usize, val: dtypes::EvalVal) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // a block of values from the formula in a cell spills into the cells
+        // below and to the right of it, unless there is anything stored in 
+        // the way, a 1x1 block is just a single value
+        let grid = match val {
+            dtypes::EvalVal::Val(_) => return Ok(val),
+            dtypes::EvalVal::Grid(grid) => grid,
+        };
+        if grid.n_rows == 1 && grid.n_cols == 1 {
+            return grid.vals[0].clone().map(dtypes::EvalVal::Val).ok_or(dtypes::EvalErr::Value)
+        }
+        for c in col_idx..cmp::min(col_idx + grid.n_cols, self.cols.len()) {
+            for cell in self.cells_in_rows(c, row, row + grid.n_rows - 1) {
+                if !(c == col_idx && cell.loc.row == row) {
+                    return Err(dtypes::EvalErr::Spill)
+                }
+            }
+        }
+        Ok(dtypes::EvalVal::Grid(grid))
+    }
+
+    fn eval_formula_at (&self, col_idx: usize, row: usize) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // evaluate the formula stored in a cell, this only happens once and
+        // after that the result is remembered until the sheet changes
+        // a block of values means the formula spills (see spill)
+        if let Some(res) = self.results.borrow().get(&(col_idx, row)) {
+            if self.volatile.borrow().contains(&(col_idx, row)) {
+                self.volatile_hit.set(true);
+            }
+            return res.clone()
+        }
+        if self.evaluating.borrow().contains(&(col_idx, row)) {
+            if self.iteration.is_some() {
+                // the value from the last iteration (0 to start with)
+                self.circ_hit.set(true);
+                let last = self.iterated.borrow().get(&(col_idx, row)).cloned();
+                return Ok(last.unwrap_or(dtypes::EvalVal::Val(dtypes::CellVal::Int(0))))
+            }
+            return Err(dtypes::EvalErr::Circ)
+        }
+        let program = self.compiled_at(col_idx, row);
+        self.evaluating.borrow_mut().push((col_idx, row));
+        // whatever uses this cell is volatile if this one is
+        let outer_hit = self.volatile_hit.replace(false);
+        // the tree when explaining so every step of it gets recorded
+        let tracing = self.trace.borrow().is_some() && self.evaluating.borrow().len() <= 1;
+        let res = match self.stored_cell(col_idx, row) {
+            Some(cell_val) if tracing => match Sheet::compile(cell_val).as_ref() {
+                Ok(root) => self.eval_node(root, &formulas::Scope::default()),
+                Err(err) => Err(*err),
+            },
+            _ => self.run(&program.steps),
+        };
+        if self.volatile_hit.get() {
+            self.volatile.borrow_mut().insert((col_idx, row));
+        }
+        self.volatile_hit.set(outer_hit || self.volatile_hit.get());
+        self.evaluating.borrow_mut().pop();
+        let res = res.and_then(|val| self.spill(col_idx, row, val));
+        // circular references are not remembered because they depend on where
+        // the evaluation started (e.g. when looking for spills, see spills)
+        if !matches!(res, Err(dtypes::EvalErr::Circ)) {
+            let mut results = self.results.borrow_mut();
+            results.insert((col_idx, row), res.clone());
+            if self.volatile.borrow().contains(&(col_idx, row)) {
+                results.check_volatile(col_idx, row);
+            }
+        }
+        res
+    }
+
+    fn check_spills (&self, max_col: usize, max_row: usize) {
+        // evaluate the formula cells at or above/left of max_col/max_row that 
+        // have not been looked at yet so that whatever they spill is in the 
+        // spill index, formulas that are being evaluated right now are skipped 
+        // since whatever they spill cannot be one of their own inputs (they 
+        // get added once they are done), and anything that depends on a 
+        // spill is volatile if a formula that could spill there is
+        for (c, col) in self.cols.iter().enumerate().take(max_col.saturating_add(1)) {
+            let checked = self.results.borrow().checked.get(c).copied().unwrap_or(0);
+            if checked >= max_row || col.is_empty() {
+                continue;
+            }
+            for cell in self.cells_in_rows(c, checked + 1, max_row) {
+                let at = (c, cell.loc.row);
+                if let dtypes::CellVal::Formula(_) = cell.val {
+                    if !self.evaluating.borrow().contains(&at) {
+                        if let Ok(dtypes::EvalVal::Grid(grid)) = self.eval_formula_at(c, cell.loc.row) {
+                            self.results.borrow_mut().add_spill(at, &grid);
+                        }
+                    }
+                }
+                let volatile = self.volatile.borrow().contains(&at);
+                let mut results = self.results.borrow_mut();
+                results.check(c, cell.loc.row);
+                if volatile {
+                    results.check_volatile(c, cell.loc.row);
+                }
+            }
+            self.results.borrow_mut().check(c, max_row);
+        }
+        let results = self.results.borrow();
+        if results.volatile_rows.iter().take(max_col.saturating_add(1)).any(|r| *r <= max_row) {
+            self.volatile_hit.set(true);
+        }
+    }
+
+    fn spills (&self, c0: usize, r0: usize, c1: usize, r1: usize) -> Vec<(usize, usize, dtypes::CellGrid)> {
+        // the blocks of values spilling into the block from c0/r0 to c1/r1,
+        // with the column index and row of the formula, these are in 
+        // column-major order and if they overlap the first one wins
+        self.check_spills(c1, r1);
+        let results = self.results.borrow();
+        let mut spills = Vec::new();
+        for &(c, r) in results.anchors.iter() {
+            if let Some(Ok(dtypes::EvalVal::Grid(grid))) = results.get(&(c, r)) {
+                if c <= c1 && r <= r1 && c + grid.n_cols > c0 && r + grid.n_rows > r0 {
+                    spills.push((c, r, grid.clone()));
+                }
+            }
+        }
+        spills
+    }
+
+    fn spilled_val (&self, col_idx: usize, row: usize) -> Option<dtypes::CellVal> {
+        // the value spilled into an (otherwise empty) cell, if any
+        self.check_spills(col_idx, row);
+        let results = self.results.borrow();
+        let &(c, r) = results.spilled.get(&(col_idx, row))?;
+        match results.get(&(c, r)) {
+            Some(Ok(dtypes::EvalVal::Grid(grid))) => grid.vals[(row - r) * grid.n_cols + col_idx - c].clone(),
+            _ => Option::None,
+        }
+    }
+
+    fn cell_val (&self, col_idx: usize, row: usize) -> Result<Option<dtypes::CellVal>, dtypes::EvalErr> {
+        // the value that shows up in a cell, formulas are evaluated (only the
+        // top left value of a block that spills) and an empty cell can have a 
+        // value spilled into it from a formula above/left of it
+        match self.stored_cell(col_idx, row) {
+            Some(dtypes::CellVal::Formula(_)) => {
+                match self.eval_formula_at(col_idx, row)? {
+                    dtypes::EvalVal::Val(val) => Ok(Option::Some(val)),
+                    dtypes::EvalVal::Grid(grid) => Ok(grid.vals[0].clone()),
+                }
+            },
+            Some(val) => Ok(Option::Some(val.clone())),
+            None => Ok(self.spilled_val(col_idx, row)),
+        }
+    }
+
+    fn cells_in_rows (&self, col_idx: usize, r0: usize, r1: usize) -> &[dtypes::Cell] {
+        // the stored cells in a column from row r0 to r1, column vectors are 
+        // sorted by row so this never looks at the cells outside of them
+        let col = &self.cols[col_idx];
+        let first = col.partition_point(|cell| cell.loc.row < r0);
+        let last = col.partition_point(|cell| cell.loc.row <= r1);
+        &col[first..cmp::max(first, last)]
+    }
+
+    fn range_corners (&self, token: &dtypes::FormToken) -> Option<(dtypes::CellLoc, dtypes::CellLoc)> {
+        // opposite corners of a range, whole columns and rows only go as 
+        // far as the sheet does right now (at least one cell) so that
+        // they grow with it without looking at all of the empty cells
+        match token {
+            dtypes::FormToken::Range(start, end) => Option::Some((start.clone(), end.clone())),
+            dtypes::FormToken::Cols(first, last) => {
+                let last_row = cmp::max(self.n_rows, 1);
+                Option::Some((dtypes::CellLoc { col: first.clone(), row: 1 }, dtypes::CellLoc { col: last.clone(), row: last_row }))
+            },
+            dtypes::FormToken::Rows(first, last) => {
+                let last_col = Sheet::index_to_col(cmp::max(self.n_cols, 1) - 1);
+                Option::Some((dtypes::CellLoc { col: String::from("A"), row: *first }, dtypes::CellLoc { col: last_col, row: *last }))
+            },
+            _ => Option::None,
+        }
+    }
+
+    fn ref_corners (&self, node: &dtypes::TknNode, scope: &formulas::Scope) -> Result<(dtypes::CellLoc, dtypes::CellLoc), dtypes::EvalErr> {
+        // the cells a reference (a loc, a range or INDIRECT/OFFSET) points
+        // at, anything else is not a reference
+        match &node.token {
+            dtypes::FormToken::Loc(loc) => Ok((loc.clone(), loc.clone())),
+            dtypes::FormToken::Func(name) if name == "INDIRECT" || name == "OFFSET" => self.resolve_ref(name, &node.args, scope),
+            token => self.range_corners(token).ok_or(dtypes::EvalErr::Value),
+        }
+    }
+
+    fn resolve_ref (&self, name: &str, args: &[dtypes::TknNode], scope: &formulas::Scope) -> Result<(dtypes::CellLoc, dtypes::CellLoc), dtypes::EvalErr> {
+        // INDIRECT(ref_text) and OFFSET(reference, rows, cols, [height], [width]) 
+        // work out which cells they point at when they are evaluated, so 
+        // they are volatile, and anything off of the sheet is #REF!
+        self.volatile_hit.set(true);
+        if name == "INDIRECT" {
+            if args.len() != 1 {
+                return Err(dtypes::EvalErr::Value)
+            }
+            let text = self.eval_node(&args[0], scope)?;
+            let token = parsing::parse_ref(formulas::arg_text(&text)?).ok_or(dtypes::EvalErr::Ref)?;
+            return match token {
+                dtypes::FormToken::Loc(loc) => Ok((loc.clone(), loc)),
+                token => self.range_corners(&token).ok_or(dtypes::EvalErr::Ref),
+            }
+        }
+        if args.len() < 3 || args.len() > 5 {
+            return Err(dtypes::EvalErr::Value)
+        }
+        let (start, end) = self.ref_corners(&args[0], scope)?;
+        let (c0, c1) = (Sheet::col_to_index(&start.col) as i64, Sheet::col_to_index(&end.col) as i64);
+        let (r0, r1) = (start.row as i64, end.row as i64);
+        let (c0, r0, width, height) = (cmp::min(c0, c1), cmp::min(r0, r1), (c1 - c0).abs() + 1, (r1 - r0).abs() + 1);
+        let mut nums = Vec::with_capacity(4);
+        for arg in &args[1..] {
+            nums.push(formulas::arg_int(&self.eval_node(arg, scope)?)?);
+        }
+        let (rows, cols) = (nums[0], nums[1]);
+        let height = nums.get(2).copied().unwrap_or(height);
+        let width = nums.get(3).copied().unwrap_or(width);
+        if height < 1 || width < 1 || height.saturating_mul(width) > MAX_REF_CELLS {
+            return Err(dtypes::EvalErr::Ref)
+        }
+        let (r0, c0) = (r0.saturating_add(rows), c0.saturating_add(cols));
+        let (r1, c1) = (r0.saturating_add(height - 1), c0.saturating_add(width - 1));
+        if r0 < 1 || c0 < 0 || c1 > MAX_COL_INDEX {
+            return Err(dtypes::EvalErr::Ref)
+        }
+        let loc = |col: i64, row: i64| dtypes::CellLoc { col: Sheet::index_to_col(col as usize), row: row as usize };
+        Ok((loc(c0, r0), loc(c1, r1)))
+    }
+
+    fn ref_val (&self, start: &dtypes::CellLoc, end: &dtypes::CellLoc) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // a single cell is a value like a loc, anything bigger a block like a range
+        if start.col == end.col && start.row == end.row {
+            return match self.cell_val(Sheet::col_to_index(&start.col), start.row)? {
+                Some(cv) => Ok(dtypes::EvalVal::Val(cv)),
+                None => Err(dtypes::EvalErr::Err),
+            }
+        }
+        Ok(dtypes::EvalVal::Grid(self.get_range(start, end)?))
+    }
+
+    fn get_range (&self, start: &dtypes::CellLoc, end: &dtypes::CellLoc) -> Result<dtypes::CellGrid, dtypes::EvalErr> {
+        // all of the values in the block of cells with start and end at opposite 
+        // corners, anything outside of the sheet is just empty (and a block 
+        // too big to hold is #REF!)
+        let (c0, c1) = (Sheet::col_to_index(&start.col), Sheet::col_to_index(&end.col));
+        let (c0, c1) = (cmp::min(c0, c1), cmp::max(c0, c1));
+        let (r0, r1) = (cmp::min(start.row, end.row), cmp::max(start.row, end.row));
+        let n_rows = r1 - r0 + 1;
+        let n_cols = c1 - c0 + 1;
+        match n_rows.checked_mul(n_cols) {
+            Some(n_cells) if n_cells as i64 <= MAX_REF_CELLS => (),
+            _ => return Err(dtypes::EvalErr::Ref),
+        }
+        let mut vals = vec![None; n_rows * n_cols];
+        // cells that have a value or are part of a spill (which can have holes)
+        let mut covered = vec![false; n_rows * n_cols];
+        for col_idx in c0..cmp::min(c1 + 1, self.n_cols) {
+            for cell in self.cells_in_rows(col_idx, r0, r1) {
+                let i = (cell.loc.row - r0) * n_cols + col_idx - c0;
+                vals[i] = self.cell_val(col_idx, cell.loc.row)?;
+                covered[i] = true;
+            }
+        }
+        // then fill in the empty cells that have something spilled into them
+        if covered.contains(&false) {
+            for (c, r, grid) in self.spills(c0, r0, c1, r1) {
+                for gi in 0..grid.n_rows {
+                    for gj in 0..grid.n_cols {
+                        let (col_idx, row) = (c + gj, r + gi);
+                        if col_idx < c0 || col_idx > c1 || row < r0 || row > r1 {
+                            continue;
+                        }
+                        let i = (row - r0) * n_cols + col_idx - c0;
+                        if !covered[i] {
+                            vals[i] = grid.vals[gi * grid.n_cols + gj].clone();
+                            covered[i] = true;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(dtypes::CellGrid { n_rows, n_cols, vals })
+    }
+
+    fn range_values (&self, start: &dtypes::CellLoc, end: &dtypes::CellLoc) -> Result<dtypes::CellGrid, dtypes::EvalErr> {
+        // the values in the block of cells with start and end at opposite 
+        // corners without where they were: the cells with something stored 
+        // or spilled in them, row by row, in a single column, so a whole 
+        // column or row only takes as long as the cells that are there
+        let (c0, c1) = (Sheet::col_to_index(&start.col), Sheet::col_to_index(&end.col));
+        let (c0, c1) = (cmp::min(c0, c1), cmp::max(c0, c1));
+        let (r0, r1) = (cmp::min(start.row, end.row), cmp::max(start.row, end.row));
+        let mut found = Vec::new();
+        for col_idx in c0..cmp::min(c1 + 1, self.cols.len()) {
+            for cell in self.cells_in_rows(col_idx, r0, r1) {
+                if let Some(val) = self.cell_val(col_idx, cell.loc.row)? {
+                    found.push((cell.loc.row, col_idx, val));
+                }
+            }
+        }
+        // only the empty cells can have something spilled into them
+        let n_cells = (c1 - c0 + 1).saturating_mul(r1 - r0 + 1);
+        let spills = if found.len() < n_cells { self.spills(c0, r0, c1, r1) } else { Vec::new() };
+        for (c, r, grid) in spills {
+            let results = self.results.borrow();
+            for row in cmp::max(r, r0)..cmp::min(r + grid.n_rows, r1.saturating_add(1)) {
+                for col_idx in cmp::max(c, c0)..cmp::min(c + grid.n_cols, c1.saturating_add(1)) {
+                    // the first spill to cover a cell is the one it gets its value from
+                    if results.spilled.get(&(col_idx, row)) != Option::Some(&(c, r)) {
+                        continue;
+                    }
+                    if let Some(val) = &grid.vals[(row - r) * grid.n_cols + col_idx - c] {
+                        found.push((row, col_idx, val.clone()));
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|(row, col_idx, _)| (*row, *col_idx));
+        let vals: Vec<Option<dtypes::CellVal>> = found.into_iter().map(|(_, _, val)| Option::Some(val)).collect();
+        Ok(dtypes::CellGrid { n_rows: vals.len(), n_cols: 1, vals })
+    }
+
+    fn eval_let (&self, args: &[dtypes::TknNode], scope: &formulas::Scope) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // LET(name, value, [name, value, ...], body), each value can use
+        // the names before it and the body can use all of them
+        if args.len() < 3 || args.len().is_multiple_of(2) {
+            return Err(dtypes::EvalErr::Value)
+        }
+        let (body, pairs) = args.split_last().ok_or(dtypes::EvalErr::Value)?;
+        let mut scope = scope.called_from(scope)?;
+        for pair in pairs.chunks(2) {
+            let name = match &pair[0].token {
+                dtypes::FormToken::Name(name) => name.clone(),
+                _ => return Err(dtypes::EvalErr::Value),
+            };
+            let binding = self.eval_binding(&pair[1], &scope)?;
+            scope.bind(name, binding);
+        }
+        self.eval_node(body, &scope)
+    }
+
+    fn eval_binding (&self, node: &dtypes::TknNode, scope: &formulas::Scope) -> Result<formulas::Binding, dtypes::EvalErr> {
+        // what a name in LET gets bound to, a LAMBDA (or a name for one) is
+        // kept as is to be called later, anything else gets evaluated
+        if let dtypes::FormToken::Func(name) = &node.token {
+            if name == "LAMBDA" {
+                let lambda = formulas::make_lambda(node.args.clone(), scope.clone())?;
+                return Ok(formulas::Binding::Lambda(Rc::new(lambda)))
+            }
+        }
+        if let dtypes::FormToken::Name(name) = &node.token {
+            if let Some(lambda) = self.find_lambda(name, scope)? {
+                return Ok(formulas::Binding::Lambda(lambda))
+            }
+        }
+        Ok(formulas::Binding::Val(self.eval_node(node, scope)?))
+    }
+
+    fn defined_name (&self, name: &str) -> Option<Compiled> {
+        // the parsed formula for a defined name (if there is one)
+        if let Some(tree) = self.compiled_names.borrow().get(name) {
+            return Option::Some(tree.clone())
+        }
+        let formula = self.names.get(name)?;
+        let tree = Sheet::compile(&dtypes::CellVal::Formula(formula.clone()));
+        self.compiled_names.borrow_mut().insert(name.to_string(), tree.clone());
+        Option::Some(tree)
+    }
+
+    fn find_lambda (&self, name: &str, scope: &formulas::Scope) -> Result<Option<Rc<formulas::Lambda>>, dtypes::EvalErr> {
+        // a LAMBDA bound to a name, first by LET/LAMBDA then as a defined name
+        match scope.get(name) {
+            Some(formulas::Binding::Lambda(lambda)) => return Ok(Option::Some(lambda.clone())),
+            Some(formulas::Binding::Val(_)) => return Ok(Option::None),
+            None => {},
+        }
+        match self.defined_name(name) {
+            Some(tree) => {
+                let root = tree.as_ref().as_ref().map_err(|err| *err)?;
+                match &root.token {
+                    dtypes::FormToken::Func(func) if func == "LAMBDA" => {
+                        // defined names only see their own parameters
+                        let lambda = formulas::make_lambda(root.args.clone(), formulas::Scope::default())?;
+                        Ok(Option::Some(Rc::new(lambda)))
+                    },
+                    _ => Ok(Option::None),
+                }
+            },
+            None => Ok(Option::None),
+        }
+    }
+
+    fn call_lambda (&self, lambda: &formulas::Lambda, args: Vec<dtypes::EvalVal>, scope: &formulas::Scope) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // evaluate the body of a LAMBDA with its parameters bound to the arguments
+        if args.len() != lambda.params.len() {
+            return Err(dtypes::EvalErr::Value)
+        }
+        // it sees the names from where it was made (not where it is called)
+        let mut call_scope = lambda.scope.called_from(scope)?;
+        for (param, arg) in lambda.params.iter().zip(args) {
+            call_scope.bind(param.clone(), formulas::Binding::Val(arg));
+        }
+        self.eval_node(&lambda.body, &call_scope)
+    }
+
+    fn eval_name (&self, name: &str, scope: &formulas::Scope) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // value of a name from LET/LAMBDA or a defined name, a LAMBDA that
+        // is not called does not have a value
+        match scope.get(name) {
+            Some(formulas::Binding::Val(val)) => return Ok(val.clone()),
+            Some(formulas::Binding::Lambda(_)) => return Err(dtypes::EvalErr::Calc),
+            None => {},
+        }
+        let tree = self.defined_name(name).ok_or(dtypes::EvalErr::Name)?;
+        let root = tree.as_ref().as_ref().map_err(|err| *err)?;
+        if let dtypes::FormToken::Func(func) = &root.token {
+            if func == "LAMBDA" {
+                return Err(dtypes::EvalErr::Calc)
+            }
+        }
+        self.eval_node(root, &formulas::Scope::default().called_from(scope)?)
+    }
+
+    fn eval_tree (&self, root: &dtypes::TknLink, scope: &formulas::Scope) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        match root {
+            Some(node) => self.eval_node(node, scope),
+            // empty tree -> return no value
+            None => Err(dtypes::EvalErr::Err),
+        }
+    }
+
+    fn eval_operand (&self, root: &dtypes::TknLink, scope: &formulas::Scope) -> Result<Option<dtypes::EvalVal>, dtypes::EvalErr> {
+        // an operand that is a single empty cell is None instead of an error,
+        // the operator decides what it counts as (see formulas::coerce)
+        if let Some(node) = root {
+            if let dtypes::FormToken::Loc(loc) = &node.token {
+                if self.cell_val(Sheet::col_to_index(&loc.col), loc.row)?.is_none() {
+                    return Ok(Option::None)
+                }
+            }
+        }
+        self.eval_tree(root, scope).map(Option::Some)
+    }
+
+    fn eval_node (&self, node: &dtypes::TknNode, scope: &formulas::Scope) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        self.traced(node, || self.eval_node_untraced(node, scope))
+    }
+
+    fn eval_arg (&self, node: &dtypes::TknNode, scope: &formulas::Scope, values_only: bool) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // a function argument, a range given to a function that only wants 
+        // the values in it is just the cells that have something in them
+        match &node.token {
+            dtypes::FormToken::Range(_, _) | dtypes::FormToken::Cols(_, _) | dtypes::FormToken::Rows(_, _) if values_only => {
+                self.traced(node, || {
+                    let (start, end) = self.range_corners(&node.token).unwrap();
+                    Ok(dtypes::EvalVal::Grid(self.range_values(&start, &end)?))
+                })
+            },
+            _ => self.eval_node(node, scope),
+        }
+    }
+
+    fn traced (&self, node: &dtypes::TknNode, eval: impl FnOnce() -> Result<dtypes::EvalVal, dtypes::EvalErr>) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // while explaining a formula every step of it gets recorded, but not
+        // the steps of other formula cells it uses, only their values
+        let tracing = self.trace.borrow().is_some() && self.evaluating.borrow().len() <= 1;
+        if !tracing {
+            return eval()
+        }
+        let depth = self.trace_depth.get();
+        self.trace_depth.set(depth + 1);
+        let result = eval();
+        self.trace_depth.set(depth);
+        if let Some(steps) = self.trace.borrow_mut().as_mut() {
+            steps.push(TraceStep { depth, node: node.clone(), result: result.clone() });
+        }
+        result
+    }
+
+    fn eval_node_untraced (&self, node: &dtypes::TknNode, scope: &formulas::Scope) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+        // the tree is only borrowed so that parsed formulas can be evaluated
+        // over and over again (see compiled_at)
+        match &node.token {
+            dtypes::FormToken::Num(num) => Ok(dtypes::EvalVal::Val(num.clone())),
+            dtypes::FormToken::Str(text) => Ok(dtypes::EvalVal::Val(dtypes::CellVal::Text(text.clone()))),
+            dtypes::FormToken::Bool(b) => Ok(dtypes::EvalVal::Val(dtypes::CellVal::Bool(*b))),
+            dtypes::FormToken::Loc(loc) => self.ref_val(loc, loc),
+            dtypes::FormToken::Range(_, _) | dtypes::FormToken::Cols(_, _) | dtypes::FormToken::Rows(_, _) => {
+                let (start, end) = self.range_corners(&node.token).unwrap();
+                Ok(dtypes::EvalVal::Grid(self.get_range(&start, &end)?))
+            },
+            dtypes::FormToken::SpillRef(loc) => {
+                // the whole block spilled from a formula, errors from 
+                // the formula (e.g. #SPILL!) come through as they are
+                let col_idx = Sheet::col_to_index(&loc.col);
+                match self.stored_cell(col_idx, loc.row) {
+                    Some(dtypes::CellVal::Formula(_)) => {
+                        match self.eval_formula_at(col_idx, loc.row)? {
+                            dtypes::EvalVal::Grid(grid) => Ok(dtypes::EvalVal::Grid(grid)),
+                            dtypes::EvalVal::Val(_) => Err(dtypes::EvalErr::Ref),
+                        }
+                    },
+                    _ => Err(dtypes::EvalErr::Ref),
+                }
+            },
+            dtypes::FormToken::BinOp(op) => {
+                let left_val = self.eval_operand(&node.left, scope)?;
+                let right_val = self.eval_operand(&node.right, scope)?;
+                formulas::apply_binop_vals(op, left_val, right_val, self.coercion)
+            },
+            dtypes::FormToken::Neg => {
+                formulas::negate_vals(self.eval_operand(&node.left, scope)?, self.coercion)
+            },
+            dtypes::FormToken::Name(name) => self.eval_name(name, scope),
+            dtypes::FormToken::Func(name) => {
+                // LET, LAMBDA, INDIRECT and OFFSET get their arguments before they are evaluated
+                match name.as_str() {
+                    "LET" => return self.eval_let(&node.args, scope),
+                    // INDIRECT and OFFSET give back references, not values
+                    "INDIRECT" | "OFFSET" => {
+                        let (start, end) = self.resolve_ref(name, &node.args, scope)?;
+                        return self.ref_val(&start, &end)
+                    },
+                    // a LAMBDA that is not bound to a name cannot be called
+                    "LAMBDA" => return Err(dtypes::EvalErr::Calc),
+                    _ => {},
+                }
+                if let Some(lambda) = self.find_lambda(name, scope)? {
+                    let mut args = Vec::with_capacity(node.args.len());
+                    for arg in &node.args {
+                        args.push(self.eval_node(arg, scope)?);
+                    }
+                    return self.call_lambda(&lambda, args, scope)
+                }
+                // registered functions come before the builtins
+                let func = self.functions.get(name);
+                let builtin = formulas::find_builtin(name);
+                let (min_args, max_args, volatile) = match (func, builtin) {
+                    (Some(func), _) => (func.arity().0, func.arity().1, func.volatile()),
+                    (None, Some(builtin)) => (builtin.min_args, builtin.max_args, builtin.volatile),
+                    (None, None) => return Err(dtypes::EvalErr::Name),
+                };
+                if volatile {
+                    self.volatile_hit.set(true);
+                }
+                let n_args = node.args.len();
+                if n_args < min_args || n_args > max_args {
+                    return Err(dtypes::EvalErr::Value)
+                }
+                // evaluate all of the arguments first, the first one
+                // that fails makes the whole function fail
+                let values_only = func.is_none() && builtin.is_some_and(|builtin| builtin.values_only);
+                let mut args = Vec::with_capacity(n_args);
+                for arg in &node.args {
+                    args.push(self.eval_arg(arg, scope, values_only)?);
+                }
+                match (func, builtin) {
+                    (Some(func), _) => func.evaluate(&args, &self.view()),
+                    (None, Some(builtin)) => builtin.call(&args, self),
+                    (None, None) => Err(dtypes::EvalErr::Name),
+                }
+            },
+            // these never make it into a tree
+            _ => Err(dtypes::EvalErr::Err),
+        }
+    }
+
+    fn shown_val (res: Result<dtypes::EvalVal, dtypes::EvalErr>) -> Option<dtypes::CellVal> {
+        // what shows up in a formula cell: the value, the top left value of a 
+        // block that spills or the error as text (e.g. "#VALUE!")
+        match res {
+            Ok(dtypes::EvalVal::Val(cv)) => Option::Some(cv),
+            Ok(dtypes::EvalVal::Grid(grid)) => grid.vals[0].clone(),
+            Err(err) => Option::Some(dtypes::CellVal::Text(err.to_string())),
+        }
+    }
+
+    fn collect_range_refs (&self, start: &dtypes::CellLoc, end: &dtypes::CellLoc, refs: &mut BTreeSet<(usize, usize)>) {
+        // the cells with something stored in them in a block
+        let (c0, c1) = (Sheet::col_to_index(&start.col), Sheet::col_to_index(&end.col));
+        let (r0, r1) = (cmp::min(start.row, end.row), cmp::max(start.row, end.row));
+        for col_idx in cmp::min(c0, c1)..cmp::min(cmp::max(c0, c1) + 1, self.cols.len()) {
+            for cell in self.cells_in_rows(col_idx, r0, r1) {
+                refs.insert((col_idx, cell.loc.row));
+            }
+        }
+    }
+
+    fn collect_refs (&self, node: &dtypes::TknNode, refs: &mut BTreeSet<(usize, usize)>, names: &mut Vec<String>) {
+        // the cells a (part of a) formula reads: the cells it refers to, the
+        // cells with something stored in them inside of its ranges, the
+        // formulas it takes spills from and the same for any defined names
+        // it uses (names already looked at are in names)
+        match &node.token {
+            dtypes::FormToken::Loc(loc) | dtypes::FormToken::SpillRef(loc) => {
+                refs.insert((Sheet::col_to_index(&loc.col), loc.row));
+            },
+            dtypes::FormToken::Range(_, _) | dtypes::FormToken::Cols(_, _) | dtypes::FormToken::Rows(_, _) => {
+                let (start, end) = self.range_corners(&node.token).unwrap();
+                self.collect_range_refs(&start, &end, refs);
+            },
+            dtypes::FormToken::Func(name) if name == "INDIRECT" || name == "OFFSET" => {
+                // whatever they point at right now
+                if let Ok((start, end)) = self.resolve_ref(name, &node.args, &formulas::Scope::default()) {
+                    self.collect_range_refs(&start, &end, refs);
+                }
+            },
+            dtypes::FormToken::Name(name) | dtypes::FormToken::Func(name) if !names.contains(name) => {
+                names.push(name.clone());
+                if let Some(tree) = self.defined_name(name) {
+                    if let Ok(root) = tree.as_ref() {
+                        self.collect_refs(root, refs, names);
+                    }
+                }
+            },
+            _ => {},
+        }
+        for child in node.left.iter().chain(node.right.iter()) {
+            self.collect_refs(child, refs, names);
+        }
+        for arg in &node.args {
+            self.collect_refs(arg, refs, names);
+        }
+    }
+
+    fn direct_precedents (&self, col_idx: usize, row: usize) -> BTreeSet<(usize, usize)> {
+        // the cells the formula in a cell reads (nothing if it is not a formula)
+        let mut refs = BTreeSet::new();
+        if let Some(cell_val @ dtypes::CellVal::Formula(_)) = self.stored_cell(col_idx, row) {
+            if let Ok(root) = Sheet::compile(cell_val).as_ref() {
+                self.collect_refs(root, &mut refs, &mut Vec::new());
+            }
+        }
+        refs
+    }
+
+    fn follow (start: (usize, usize), recursive: bool, next: impl Fn((usize, usize)) -> BTreeSet<(usize, usize)>) -> Vec<dtypes::CellLoc> {
+        // the cells next gives for start, and with recursive the cells it 
+        // gives for those and so on (start itself is left out unless it is
+        // part of a circular reference), in column-major order
+        let mut found = next(start);
+        let mut todo: Vec<(usize, usize)> = if recursive { found.iter().copied().collect() } else { Vec::new() };
+        while let Some(cell) = todo.pop() {
+            for other in next(cell) {
+                if found.insert(other) {
+                    todo.push(other);
+                }
+            }
+        }
+        found.into_iter().map(|(c, r)| dtypes::CellLoc { col: Sheet::index_to_col(c), row: r }).collect()
+    }
+
+    pub fn goal_seek (&mut self, set: &dtypes::CellLoc, target: f64, changing: &dtypes::CellLoc) -> Result<dtypes::CellVal, GoalSeekErr> {
+        // the value for the changing cell that gets the formula in the set 
+        // cell to target, found with the secant method starting from the 
+        // value it has now or with bisection if that does not work out
+        // the value is written into the changing cell (the same type of 
+        // number as before if it can be, see typed_solution), which is left 
+        // as it was if there is no such value
+        if !matches!(self.stored_cell(Sheet::col_to_index(&set.col), set.row), Some(dtypes::CellVal::Formula(_))) {
+            return Err(GoalSeekErr::NotAFormula)
+        }
+        let orig = self.stored_cell(Sheet::col_to_index(&changing.col), changing.row).cloned();
+        let start = match &orig {
+            None => 0.0,
+            Some(val @ (dtypes::CellVal::Int(_) | dtypes::CellVal::Real(_) | dtypes::CellVal::Decimal(_))) => {
+                formulas::arg_num(&dtypes::EvalVal::Val(val.clone())).unwrap()
+            },
+            Some(_) => return Err(GoalSeekErr::NotANumber),
+        };
+        let tolerance = GOAL_SEEK_TOLERANCE * target.abs().max(1.0);
+        let found = match self.seek_secant(set, target, changing, start, tolerance) {
+            Some(x) => Option::Some(x),
+            None => self.seek_bisect(set, target, changing, start, tolerance),
+        };
+        match found {
+            Some(x) => {
+                let val = self.typed_solution(set, target, changing, orig.as_ref(), x, tolerance);
+                self.write_cell(changing.clone(), val.clone());
+                Ok(val)
+            },
+            None => {
+                match orig {
+                    Some(val) => self.write_cell(changing.clone(), val),
+                    None => { self.delete_cell(changing.clone()); },
+                }
+                Err(GoalSeekErr::NoSolution)
+            },
+        }
+    }
+
+    fn typed_solution (&mut self, set: &dtypes::CellLoc, target: f64, changing: &dtypes::CellLoc, orig: Option<&dtypes::CellVal>,
+                       x: f64, tolerance: f64) -> dtypes::CellVal {
+        // x the way the changing cell had its number: an Int stays an Int if
+        // the nearest whole number also gets there, a Decimal keeps its digits
+        // after the point (or gets as few more as it takes), otherwise a Real
+        let candidates: Vec<dtypes::CellVal> = match orig {
+            Some(dtypes::CellVal::Int(_)) if x.round().abs() < i64::MAX as f64 => vec![dtypes::CellVal::Int(x.round() as i64)],
+            Some(dtypes::CellVal::Decimal(d)) => (d.scale()..=decimal::MAX_SCALE)
+                .filter_map(|scale| decimal::Decimal::parse(&format!("{:.*}", scale as usize, x)))
+                .map(dtypes::CellVal::Decimal)
+                .collect(),
+            _ => Vec::new(),
+        };
+        for val in candidates {
+            if self.seek_val(set, target, changing, val.clone()).is_some_and(|f| f.abs() <= tolerance) {
+                return val
+            }
+        }
+        dtypes::CellVal::Real(x)
+    }
+
+    fn seek_at (&mut self, set: &dtypes::CellLoc, target: f64, changing: &dtypes::CellLoc, x: f64) -> Option<f64> {
+        // how far the formula is from target with x in the changing cell
+        self.seek_val(set, target, changing, dtypes::CellVal::Real(x))
+    }
+
+    fn seek_val (&mut self, set: &dtypes::CellLoc, target: f64, changing: &dtypes::CellLoc, val: dtypes::CellVal) -> Option<f64> {
+        self.write_cell(changing.clone(), val);
+        self.recalculate();
+        match self.cell_val(Sheet::col_to_index(&set.col), set.row) {
+            Ok(Some(val)) => formulas::arg_num(&dtypes::EvalVal::Val(val)).ok().map(|val| val - target),
+            _ => Option::None,
+        }
+    }
+
+    fn seek_secant (&mut self, set: &dtypes::CellLoc, target: f64, changing: &dtypes::CellLoc, start: f64, tolerance: f64) -> Option<f64> {
+        let (mut x0, mut x1) = (start, start + if start != 0.0 { start.abs() * 0.01 } else { 0.01 });
+        let mut f0 = self.seek_at(set, target, changing, x0)?;
+        if f0.abs() <= tolerance {
+            return Option::Some(x0)
+        }
+        for _ in 0..GOAL_SEEK_ITERATIONS {
+            let f1 = self.seek_at(set, target, changing, x1)?;
+            if f1.abs() <= tolerance {
+                return Option::Some(x1)
+            }
+            if f1 == f0 {
+                return Option::None
+            }
+            let x2 = x1 - f1 * (x1 - x0) / (f1 - f0);
+            if !x2.is_finite() {
+                return Option::None
+            }
+            (x0, f0, x1) = (x1, f1, x2);
+        }
+        Option::None
+    }
+
+    fn seek_bisect (&mut self, set: &dtypes::CellLoc, target: f64, changing: &dtypes::CellLoc, start: f64, tolerance: f64) -> Option<f64> {
+        // look further and further out on both sides of start for a value 
+        // on the other side of the target, then close in on it
+        let f_start = self.seek_at(set, target, changing, start)?;
+        let mut step = start.abs().max(1.0) * 0.01;
+        let mut other = Option::None;
+        for _ in 0..GOAL_SEEK_ITERATIONS {
+            for x in [start - step, start + step] {
+                if let Some(f) = self.seek_at(set, target, changing, x) {
+                    if f.abs() <= tolerance {
+                        return Option::Some(x)
+                    }
+                    if f.signum() != f_start.signum() {
+                        other = Option::Some(x);
+                    }
+                }
+            }
+            if other.is_some() {
+                break;
+            }
+            step *= 2.0;
+        }
+        let (mut lo, mut f_lo, mut hi) = (start, f_start, other?);
+        for _ in 0..GOAL_SEEK_ITERATIONS * 2 {
+            let mid = lo + (hi - lo) / 2.0;
+            let f_mid = self.seek_at(set, target, changing, mid)?;
+            if f_mid.abs() <= tolerance {
+                return Option::Some(mid)
+            }
+            if f_mid.signum() == f_lo.signum() {
+                (lo, f_lo) = (mid, f_mid);
+            } else {
+                hi = mid;
+            }
+        }
+        Option::None
+    }
+
+    pub fn with_overrides<T> (&mut self, overrides: &[(dtypes::CellLoc, Option<dtypes::CellVal>)], eval: impl FnOnce(&Sheet) -> T) -> T {
+        // evaluate with some cells holding other values (or emptied out with 
+        // None) for a bit, they are written the usual way so everything that 
+        // uses them is evaluated again, the sheet is back to how it was afterwards
+        let (n_cols, n_rows) = (self.n_cols, self.n_rows);
+        let orig: Vec<Option<dtypes::CellVal>> = overrides.iter()
+            .map(|(loc, _)| self.stored_cell(Sheet::col_to_index(&loc.col), loc.row).cloned())
+            .collect();
+        for (loc, val) in overrides {
+            self.set_cell(loc, val.clone());
+        }
+        self.recalculate();
+        let res = eval(self);
+        // backwards so that a cell overridden twice gets its own value back
+        for ((loc, _), val) in overrides.iter().zip(orig).rev() {
+            self.set_cell(loc, val);
+        }
+        self.cols.truncate(n_cols);
+        (self.n_cols, self.n_rows) = (n_cols, n_rows);
+        self.results.get_mut().clear();
+        res
+    }
+
+    fn set_cell (&mut self, loc: &dtypes::CellLoc, val: Option<dtypes::CellVal>) {
+        // write a value or empty out a cell (quietly, unlike delete_cell)
+        match val {
+            Some(val) => self.write_cell(loc.clone(), val),
+            None => {
+                let col_idx = Sheet::col_to_index(&loc.col);
+                if let Some(col) = self.cols.get_mut(col_idx) {
+                    if let Ok(i) = col.binary_search_by_key(&loc.row, |cell| cell.loc.row) {
+                        col.remove(i);
                     }
                 }
+                self.compiled.get_mut().remove(&(col_idx, loc.row));
+                self.results.get_mut().clear();
+            },
+        }
+    }
+
+    pub fn data_table (&mut self, result: &dtypes::CellLoc, inputs: &[(dtypes::CellLoc, Vec<dtypes::CellVal>)]) -> Option<dtypes::CellGrid> {
+        // what-if data table: what the formula in result shows for each value 
+        // of one input (down a column) or each pair of values of two inputs 
+        // (the first down, the second across), none of the inputs change
+        // None if result is not a formula or there are not one or two inputs
+        let (col_idx, row) = (Sheet::col_to_index(&result.col), result.row);
+        if !matches!(self.stored_cell(col_idx, row), Some(dtypes::CellVal::Formula(_))) || inputs.is_empty() || inputs.len() > 2 {
+            return Option::None
+        }
+        let (down, across) = (&inputs[0], inputs.get(1));
+        let n_rows = down.1.len();
+        let n_cols = across.map_or(1, |(_, vals)| vals.len());
+        let mut vals = Vec::with_capacity(n_rows * n_cols);
+        for down_val in &down.1 {
+            let mut overrides = vec![(down.0.clone(), Option::Some(down_val.clone()))];
+            match across {
+                Some((loc, across_vals)) => {
+                    for across_val in across_vals {
+                        overrides.truncate(1);
+                        overrides.push((loc.clone(), Option::Some(across_val.clone())));
+                        vals.push(self.with_overrides(&overrides, |sheet| Sheet::shown_val(sheet.eval_formula_at(col_idx, row))));
+                    }
+                },
+                None => vals.push(self.with_overrides(&overrides, |sheet| Sheet::shown_val(sheet.eval_formula_at(col_idx, row)))),
             }
-            eprintln!("loaded {} cells", n_cells);
         }
+        Option::Some(dtypes::CellGrid { n_rows, n_cols, vals })
     }
 
-    pub fn save_sheet (&self) {
-        eprintln!("saving sheet");
-        let file = fs::File::create("./sheet.txt").unwrap();
-        let mut buf = io::BufWriter::new(file);
-        // first print <n_cols> <n_rows>s
-        let _ = buf.write_fmt(format_args!("{} {}\n", self.n_cols, self.n_rows)).unwrap();
-        for col in &self.cols {
-            for cell in col {
-                let _ = buf.write_fmt(format_args!("{}{} {:?}\n", cell.loc.col, cell.loc.row, cell.val)).unwrap();
+    pub fn write_grid (&mut self, start: &dtypes::CellLoc, grid: dtypes::CellGrid) {
+        // write a block of values down and to the right of start,
+        // the empty ones leave what is there alone
+        let col_idx = Sheet::col_to_index(&start.col);
+        for (i, val) in grid.vals.into_iter().enumerate() {
+            if let Some(val) = val {
+                let loc = dtypes::CellLoc { col: Sheet::index_to_col(col_idx + i % grid.n_cols), row: start.row + i / grid.n_cols };
+                self.write_cell(loc, val);
             }
         }
-        let _ = buf.flush().unwrap();
     }
 
-    fn col_to_index (col: &String) -> usize {
-        let mut idx: usize = 0;
-        let base: usize = 26;
-        // iterate index letters from right to left 
-        for (i, c) in col.chars().rev().enumerate() {
-            idx += base.pow(i as u32) * (c as usize - 64);
+    pub fn save_scenario (&mut self, name: &str, cells: &[dtypes::CellLoc]) {
+        // remember what is in the cells now under name (saved with the
+        // sheet), replacing the scenario that had that name before
+        let cells = cells.iter().map(|loc| (loc.clone(), self.stored_cell(Sheet::col_to_index(&loc.col), loc.row).cloned())).collect();
+        self.scenarios.insert(String::from(name), cells);
+    }
+
+    pub fn show_scenario (&mut self, name: &str) -> bool {
+        // put the values from a scenario back into its cells, returns
+        // a bool indicating whether there was a scenario to show
+        let cells = match self.scenarios.get(name) {
+            Some(cells) => cells.clone(),
+            None => return false,
+        };
+        for (loc, val) in cells {
+            self.set_cell(&loc, val);
         }
-        // 0 indexed
-        idx - 1
+        true
     }
 
-    pub fn add_col (&mut self) {
-        self.cols.push(Vec::new());
-        self.n_cols += 1;
+    pub fn delete_scenario (&mut self, name: &str) -> bool {
+        // returns a bool indicating whether there was a scenario to delete
+        self.scenarios.remove(name).is_some()
     }
 
-    pub fn add_row (&mut self) {
-        self.n_rows += 1;
+    pub fn scenarios (&self) -> &BTreeMap<String, Vec<(dtypes::CellLoc, Option<dtypes::CellVal>)>> {
+        &self.scenarios
     }
 
-    pub fn write_cell (&mut self, loc: dtypes::CellLoc, val: dtypes::CellVal) {
-        // find out the column index, add columns if it is 
-        // beyond the current bounds of the sheet
-        let col_idx = Sheet::col_to_index(&loc.col);
-        while col_idx >= self.n_cols {
-            self.add_col();
+    pub fn scenario_summary (&mut self, results: &[dtypes::CellLoc]) -> Vec<(String, Vec<Option<dtypes::CellVal>>)> {
+        // what the result cells show in each scenario (by name), without
+        // changing what is in the sheet
+        let scenarios = self.scenarios.clone();
+        scenarios.into_iter().map(|(name, cells)| {
+            let vals = self.with_overrides(&cells, |sheet| {
+                results.iter().map(|loc| sheet.shown_at(loc)).collect()
+            });
+            (name, vals)
+        }).collect()
+    }
+
+    pub fn shown_at (&self, loc: &dtypes::CellLoc) -> Option<dtypes::CellVal> {
+        // what shows up in a cell in read_sheet (None if it is empty), 
+        // errors are shown as text (e.g. "#VALUE!")
+        match self.cell_val(Sheet::col_to_index(&loc.col), loc.row) {
+            Ok(val) => val,
+            Err(err) => Option::Some(dtypes::CellVal::Text(err.to_string())),
         }
-        // now there are for sure enough columns in the sheet
-        // add the cell to the appropriate position in the column
-        // vector (sorted by row number)
-        let col = &mut self.cols[col_idx];
-        let row = loc.row;
-        let new_cell = dtypes::Cell {loc, val};
-        if col.len() == 0 || row > col.last().unwrap().loc.row {
-            // add to end if cols is empty or row greater than row of last cell
-            col.push(new_cell);
-        } else {
-            // determine the insertion index of the cell
-            let mut ins_idx: usize = 0;
-            let mut ins_flg = true;
-            for cell in col.iter() {
-                if cell.loc.row == row {
-                    ins_flg = false;
-                    break;
-                }
-                if row < cell.loc.row {
-                    break;
-                } else {
-                    ins_idx += 1;
+    }
+
+    pub fn precedents (&self, loc: &dtypes::CellLoc, recursive: bool) -> Vec<dtypes::CellLoc> {
+        // the cells the formula at loc reads, with recursive also 
+        // the cells those read if they are formulas and so on
+        let start = (Sheet::col_to_index(&loc.col), loc.row);
+        Sheet::follow(start, recursive, |(c, r)| self.direct_precedents(c, r))
+    }
+
+    pub fn dependents (&self, loc: &dtypes::CellLoc, recursive: bool) -> Vec<dtypes::CellLoc> {
+        // the formulas that read the cell at loc, with recursive also
+        // the formulas that read those and so on
+        let mut readers: HashMap<(usize, usize), BTreeSet<(usize, usize)>> = HashMap::new();
+        for (c, col) in self.cols.iter().enumerate() {
+            for cell in col {
+                for prec in self.direct_precedents(c, cell.loc.row) {
+                    readers.entry(prec).or_default().insert((c, cell.loc.row));
                 }
             }
-            //
-            if ins_flg {
-                col.insert(ins_idx, new_cell);
-            } else {
-                // replace returns the element that was replaced
-                // so store that in a throwaway variable
-                let _ = mem::replace(&mut col[ins_idx], new_cell);
-            }
-        }
-        // update Sheet.n_rows if needed
-        if row > self.n_rows {
-            self.n_rows = row;
         }
+        let start = (Sheet::col_to_index(&loc.col), loc.row);
+        Sheet::follow(start, recursive, |cell| readers.get(&cell).cloned().unwrap_or_default())
     }
 
-    fn eval_tree (&self, root: dtypes::TknLink) -> Option<f64> {
-        match root {
-            Some(node) => {
-                match node.token {
-                    dtypes::FormToken::Num(num) => Option::Some(num),
-                    dtypes::FormToken::Loc(loc) => {
-                        if let Some(cv) = self.get_cell(loc) {
-                            match cv {
-                                dtypes::CellVal::Int(v) => {
-                                    Option::Some(v as f64)
-                                },    
-                                dtypes::CellVal::Real(v) => {
-                                    Option::Some(v)
-                                },
-                                _ => Option::None
-                            }
-                        } else {
-                            Option::None
-                        }
-                    },
-                    dtypes::FormToken::BinOp(op) => {
-                        match op {
-                            dtypes::Op::Plus => {
-                                if let Some(left_val) = self.eval_tree(node.left) {
-                                    if let Some(right_val) = self.eval_tree(node.right) {
-                                        Option::Some(left_val + right_val) 
-                                    } else {
-                                        Option::None
-                                    }
-                                } else {
-                                    Option::None
-                                }
-                            },
-                            dtypes::Op::Minus => {
-                                if let Some(left_val) = self.eval_tree(node.left) {
-                                    if let Some(right_val) = self.eval_tree(node.right) {
-                                        Option::Some(left_val - right_val) 
-                                    } else {
-                                        Option::None
-                                    }
-                                } else {
-                                    Option::None
-                                }
-                            },
-                        }
-                    },
-                }
-            },
-            // empty tree -> return no value
-            None => Option::None,
-        }  
+    pub fn explain (&self, loc: &dtypes::CellLoc) -> Option<Explanation> {
+        // evaluate the formula in a cell step by step, None if there is no formula there
+        let col_idx = Sheet::col_to_index(&loc.col);
+        let formula = match self.stored_cell(col_idx, loc.row) {
+            Some(dtypes::CellVal::Formula(formula)) => formula.clone(),
+            _ => return Option::None,
+        };
+        let tree = parsing::parse_formula_expr(&dtypes::CellVal::Formula(formula.clone()));
+        // it has to actually be evaluated (not remembered) for the steps to show up
+        self.recalculate();
+        self.results.borrow_mut().remove(&(col_idx, loc.row));
+        *self.trace.borrow_mut() = Option::Some(Vec::new());
+        let result = self.eval_formula_at(col_idx, loc.row);
+        let steps = self.trace.borrow_mut().take().unwrap_or_default();
+        Option::Some(Explanation { loc: loc.clone(), formula, tree, steps, result })
     }
 
     pub fn eval_formula_cell (&self, cell_val: &dtypes::CellVal) -> dtypes::CellVal {
+        // evaluate a formula that is not stored in the sheet
         // step 1: parse into token tree
         let tree_res = parsing::parse_formula_expr(cell_val);
         match tree_res {
-            Some(tree) => {
+            Ok(tree) => {
                 // step 2: evaluate token tree into a cell value
-                let cv_res = self.eval_tree(tree.root);
-                match cv_res {
-                    Some(cv) => dtypes::CellVal::Real(cv),
-                    None => dtypes::CellVal::Text(String::from("#ERR")),
-                }
+                let shown = Sheet::shown_val(self.eval_tree(&tree.root, &formulas::Scope::default()));
+                shown.unwrap_or_else(|| dtypes::CellVal::Text(dtypes::EvalErr::Value.to_string()))
             },
-            None => dtypes::CellVal::Text(String::from("#ERR")),
+            Err(_) => dtypes::CellVal::Text(dtypes::EvalErr::Parse.to_string()),
         }
     }
     
     pub fn read_sheet (&self) {
-        // first print <n_cols> <n_rows>
-        println!("{} {}", self.n_cols, self.n_rows);
-        // then print all cell values
+        self.print_sheet(false);
+    }
+
+    pub fn read_sheet_localized (&self) {
+        // read_sheet with numbers written the way the sheet's locale does
+        // (just like read_sheet if it does not have one)
+        self.print_sheet(true);
+    }
+
+    fn print_sheet (&self, localized: bool) {
         // formulas are evaluated at this point and evaluated values
-        // are printed
-        for col in &self.cols {
+        // are printed, including the values that spill out of them
+        // into empty cells
+        if let Some(convergence) = self.recalculate() {
+            if convergence.converged {
+                eprintln!("iterative calculation converged after {} iterations", convergence.iterations);
+            } else {
+                eprintln!("iterative calculation did not converge after {} iterations (last change: {})", convergence.iterations, convergence.max_change);
+            }
+        }
+        let mut entries: Vec<(usize, usize, dtypes::CellVal)> = Vec::new();
+        let mut stored: HashSet<(usize, usize)> = HashSet::new();
+        for (c, col) in self.cols.iter().enumerate() {
             for cell in col {
-                // print value of Int, Real, and Text cells to stdout
-                // evaluate and print result of Formula cells
-                match cell.val {
-                    dtypes::CellVal::Formula(_) => {
-                        let display_val = self.eval_formula_cell(&cell.val);
-                        println!("{}{} {:?}", cell.loc.col, cell.loc.row, display_val);
-                    }
-                    _ => {
-                        println!("{}{} {:?}", cell.loc.col, cell.loc.row, cell.val);
+                stored.insert((c, cell.loc.row));
+                // evaluate Formula cells, everything else as is
+                let shown = match cell.val {
+                    dtypes::CellVal::Formula(_) => Sheet::shown_val(self.eval_formula_at(c, cell.loc.row)),
+                    _ => Option::Some(cell.val.clone()),
+                };
+                if let Some(val) = shown {
+                    entries.push((c, cell.loc.row, val));
+                }
+            }
+        }
+        // spills can go past the last column/row with anything stored in it
+        let (mut n_cols, mut n_rows) = (self.n_cols, self.n_rows);
+        for (c, r, grid) in self.spills(0, 0, usize::MAX, usize::MAX) {
+            n_cols = cmp::max(n_cols, c + grid.n_cols);
+            n_rows = cmp::max(n_rows, r + grid.n_rows - 1);
+            for (i, val) in grid.vals.into_iter().enumerate() {
+                let (col_idx, row) = (c + i % grid.n_cols, r + i / grid.n_cols);
+                // the first spill to cover a cell is the one that shows up in it
+                if stored.insert((col_idx, row)) {
+                    if let Some(val) = val {
+                        entries.push((col_idx, row, val));
                     }
                 }
             }
         }
+        entries.sort_by_key(|(c, r, _)| (*c, *r));
+        // first print <n_cols> <n_rows>
+        println!("{} {}", n_cols, n_rows);
+        // then print all cell values
+        for (c, r, val) in entries {
+            match self.locale {
+                Some(locale) if localized => println!("{}{} {}", Sheet::index_to_col(c), r, locale.show(&val)),
+                _ => println!("{}{} {:?}", Sheet::index_to_col(c), r, val),
+            }
+        }
     }
 
     fn get_cell (&self, loc: dtypes::CellLoc) -> Option<dtypes::CellVal> {
@@ -281,9 +1968,11 @@ impl Sheet {
         // deletes the selected cell
         // do nothing if there is no cell there
         // returns a bool indicating whether a cell was deleted or not
+        self.results.get_mut().clear();
         let mut found_cell = false;
         let mut rm_idx: usize = 0;
         let col_idx = Sheet::col_to_index(&loc.col);
+        self.compiled.get_mut().remove(&(col_idx, loc.row));
         if col_idx < self.n_cols {
             let col = &self.cols[col_idx];
             for (i, cell) in col.iter().enumerate() {
@@ -314,9 +2003,9 @@ impl Sheet {
         eprintln!("clearing sheet");
         let file = fs::File::create("./sheet.txt").unwrap();
         let mut buf = io::BufWriter::new(file);
-        // first print <n_cols> <n_rows>s
-        let _ = buf.write(b"0 0\n").unwrap();
-        let _ = buf.flush().unwrap();
+        // first print <n_cols> <n_rows>s then the format version
+        buf.write_fmt(format_args!("0 0\n#version {}\n", FORMAT_VERSION)).unwrap();
+        buf.flush().unwrap();
     }
 
     pub fn shrink (&mut self) -> bool {
@@ -370,7 +2059,7 @@ mod tests {
         eprintln!("--------------------");
         eprintln!("tree: {:?}", tree);
         eprintln!("--------------------");
-        if let Some(eval_cell_val) = sheet.eval_tree(tree.root) {
+        if let Ok(eval_cell_val) = sheet.eval_tree(&tree.root, &formulas::Scope::default()) {
             println!("eval_cell_val: {:?}", eval_cell_val);
         }
         eprintln!("--------------------");
@@ -380,9 +2069,628 @@ mod tests {
         eprintln!("--------------------");
         eprintln!("tree: {:?}", tree);
         eprintln!("--------------------");
-        if let Some(eval_cell_val) = sheet.eval_tree(tree.root) {
+        if let Ok(eval_cell_val) = sheet.eval_tree(&tree.root, &formulas::Scope::default()) {
             println!("eval_cell_val: {:?}", eval_cell_val);
         }
         eprintln!("--------------------");
     }
+
+    #[test]
+    fn test_sheet_eval_decimal () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("0.1d"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("0.2d"));
+        let cell_val = parsing::parse_val("=A1+A2");
+        let res = sheet.eval_formula_cell(&cell_val);
+        assert!(matches!(&res, dtypes::CellVal::Decimal(d) if d.to_string() == "0.3"), "expected exact Decimal 0.3 got {:?}", res);
+        let cell_val = parsing::parse_val("=A1+0.25d-1");
+        let res = sheet.eval_formula_cell(&cell_val);
+        assert!(matches!(&res, dtypes::CellVal::Decimal(d) if d.to_string() == "-0.65"), "expected exact Decimal -0.65 got {:?}", res);
+    }
+
+    fn eval_str (sheet: &Sheet, formula: &str) -> String {
+        // evaluate a formula and give back its value printed like in read_sheet
+        format!("{:?}", sheet.eval_formula_cell(&parsing::parse_val(formula)))
+    }
+
+    fn loc (loc: &str) -> dtypes::CellLoc {
+        parsing::parse_loc(loc).unwrap()
+    }
+
+    #[test]
+    fn test_sheet_eval_date_functions () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("2024-01-31"));
+        sheet.write_cell(parsing::parse_loc("B1").unwrap(), parsing::parse_val("2024-03-15"));
+        assert_eq!(eval_str(&sheet, "=DATE(2024,2,30)"), "Date(2024-03-01)");
+        // years, months and days too far out for a date
+        assert_eq!(eval_str(&sheet, "=DATE(100000000000000000,1,1)"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=DATE(2024,-9223372036854775807-1,1)"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=DATE(2024,1,-9223372036854775807-1)"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=DATE(2024,1,9223372036854775807)"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=YEAR(A1)+MONTH(A1)+DAY(A1)"), "Int(2056)");
+        assert_eq!(eval_str(&sheet, "=WEEKDAY(A1)"), "Int(4)");
+        assert_eq!(eval_str(&sheet, "=WEEKDAY(A1,2)"), "Int(3)");
+        assert_eq!(eval_str(&sheet, "=EDATE(A1,1)"), "Date(2024-02-29)");
+        assert_eq!(eval_str(&sheet, "=EOMONTH(B1,-1)"), "Date(2024-02-29)");
+        assert_eq!(eval_str(&sheet, "=DATEDIF(A1,B1,\"m\")"), "Int(1)");
+        assert_eq!(eval_str(&sheet, "=DATEDIF(A1,B1,\"D\")"), "Int(44)");
+        assert_eq!(eval_str(&sheet, "=DATEDIF(B1,A1,\"D\")"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=NETWORKDAYS(A1,B1)"), "Int(33)");
+        assert_eq!(eval_str(&sheet, "=NETWORKDAYS(A1,B1,DATE(2024,2,19))"), "Int(32)");
+        assert_eq!(eval_str(&sheet, "=NOSUCHFUNCTION(A1)"), "Text(\"#NAME?\")");
+        assert_eq!(eval_str(&sheet, "=YEAR(A1,B1)"), "Text(\"#VALUE!\")");
+    }
+
+    #[test]
+    fn test_sheet_eval_date_arithmetic () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("2024-01-31"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("2024-03-15T12:00"));
+        sheet.write_cell(parsing::parse_loc("A3").unwrap(), parsing::parse_val("PT6H"));
+        assert_eq!(eval_str(&sheet, "=A1+30"), "Date(2024-03-01)");
+        assert_eq!(eval_str(&sheet, "=DATE(2024,3,15)-A1"), "Duration(P44D)");
+        assert_eq!(eval_str(&sheet, "=A2-A1"), "Duration(P44DT12H)");
+        assert_eq!(eval_str(&sheet, "=A1+A3"), "DateTime(2024-01-31T06:00:00)");
+        assert_eq!(eval_str(&sheet, "=A2+A3-1"), "DateTime(2024-03-14T18:00:00)");
+        assert_eq!(eval_str(&sheet, "=A1+A1"), "Text(\"#VALUE!\")");
+        assert_eq!(eval_str(&sheet, "=A1+-A3"), "DateTime(2024-01-30T18:00:00)");
+        assert_eq!(eval_str(&sheet, "=-(1-3)"), "Int(2)");
+        assert_eq!(eval_str(&sheet, "=-A1"), "Text(\"#VALUE!\")");
+    }
+
+    #[test]
+    fn test_sheet_eval_today_now () {
+        let mut sheet = Sheet::new();
+        let now = datetime::DateTime::parse("2024-06-30T23:59:59").unwrap();
+        sheet.set_clock(Box::new(datetime::FixedClock(now)));
+        assert_eq!(eval_str(&sheet, "=TODAY()"), "Date(2024-06-30)");
+        assert_eq!(eval_str(&sheet, "=NOW()"), "DateTime(2024-06-30T23:59:59)");
+        assert_eq!(eval_str(&sheet, "=EOMONTH(TODAY(),0)-TODAY()"), "Duration(P0D)");
+    }
+
+    #[test]
+    fn test_sheet_eval_operators_and_ranges () {
+        let mut sheet = Sheet::new();
+        assert_eq!(eval_str(&sheet, "=1+2*3^2"), "Int(19)");
+        assert_eq!(eval_str(&sheet, "=(1+2)*3-4/2"), "Int(7)");
+        assert_eq!(eval_str(&sheet, "=2^3^2"), "Int(64)");
+        assert_eq!(eval_str(&sheet, "=-2^2"), "Int(4)");
+        assert_eq!(eval_str(&sheet, "=1/0"), "Text(\"#DIV/0!\")");
+        // the one division of two Ints that does not fit in an Int
+        assert_eq!(eval_str(&sheet, "=(-9223372036854775807-1)/-1"), "Real(9.223372036854776e18)");
+        // a loan calculator: 5% over 30 years
+        sheet.write_cell(parsing::parse_loc("B1").unwrap(), parsing::parse_val("0.05"));
+        sheet.write_cell(parsing::parse_loc("B2").unwrap(), parsing::parse_val("30"));
+        sheet.write_cell(parsing::parse_loc("B3").unwrap(), parsing::parse_val("250000"));
+        assert_eq!(eval_str(&sheet, "=PMT(B1/12,B2*12,B3)"), "Real(-1342.0540575303496)");
+        // cash flows in a range, the empty cell and text in the range are skipped
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("-10000"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("3000"));
+        sheet.write_cell(parsing::parse_loc("A3").unwrap(), parsing::parse_val("4200"));
+        sheet.write_cell(parsing::parse_loc("A5").unwrap(), parsing::parse_val("6800"));
+        sheet.write_cell(parsing::parse_loc("A6").unwrap(), parsing::parse_val("total"));
+        assert_eq!(eval_str(&sheet, "=NPV(0.1,A6:A1)"), "Real(1188.4434123352216)");
+        assert_eq!(eval_str(&sheet, "=IRR(A1:A5)"), "Real(0.16340560068898924)");
+        // a single cell range is just a single value
+        assert_eq!(eval_str(&sheet, "=A1:A1+1"), "Int(-9999)");
+        // a bigger one is added to element by element, the formula shows the top left value
+        assert_eq!(eval_str(&sheet, "=A1:A2+1"), "Int(-9999)");
+        assert_eq!(eval_str(&sheet, "=A1:A2+B1:B3"), "Text(\"#VALUE!\")");
+        // a range too big to hold
+        assert_eq!(eval_str(&sheet, "=XNPV(0.1,A1:ZZZZZZ5,B1:B5)"), "Text(\"#REF!\")");
+    }
+
+    #[test]
+    fn test_sheet_index_to_col () {
+        for col in ["A", "Z", "AA", "AZ", "BA", "ZZ", "AAA"] {
+            assert_eq!(Sheet::index_to_col(Sheet::col_to_index(col)), col);
+        }
+    }
+
+    #[test]
+    fn test_sheet_spill () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("=SEQUENCE(3)"));
+        sheet.write_cell(parsing::parse_loc("B1").unwrap(), parsing::parse_val("=A1#*10"));
+        sheet.write_cell(parsing::parse_loc("C1").unwrap(), parsing::parse_val("=A3+B2"));
+        // the spilled values show up in the empty cells and can be referenced
+        assert!(matches!(sheet.cell_val(0, 3), Ok(Some(dtypes::CellVal::Int(3)))));
+        assert!(matches!(sheet.cell_val(1, 2), Ok(Some(dtypes::CellVal::Int(20)))));
+        assert!(matches!(sheet.cell_val(2, 1), Ok(Some(dtypes::CellVal::Int(23)))));
+        assert_eq!(eval_str(&sheet, "=TRANSPOSE(B1:B3)"), "Int(10)");
+        assert!(matches!(sheet.cell_val(0, 4), Ok(None)), "nothing spills past the end");
+        // anything in the way blocks the whole spill
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("x"));
+        assert!(matches!(sheet.eval_formula_at(0, 1), Err(dtypes::EvalErr::Spill)));
+        assert!(matches!(sheet.cell_val(0, 3), Ok(None)));
+        assert!(matches!(sheet.eval_formula_at(1, 1), Err(dtypes::EvalErr::Spill)));
+        // only formulas that spill can be referenced with #
+        assert_eq!(eval_str(&sheet, "=A2#"), "Text(\"#REF!\")");
+        sheet.delete_cell(parsing::parse_loc("A2").unwrap());
+        assert!(matches!(sheet.cell_val(2, 1), Ok(Some(dtypes::CellVal::Int(23)))));
+        // where spills overlap the one that comes first in column-major order wins
+        sheet.write_cell(loc("E1"), parsing::parse_val("=SEQUENCE(2,2)"));
+        sheet.write_cell(loc("D2"), parsing::parse_val("=SEQUENCE(1,3)*10"));
+        assert!(matches!(sheet.cell_val(4, 2), Ok(Some(dtypes::CellVal::Int(20)))));
+        assert!(matches!(sheet.cell_val(5, 1), Ok(Some(dtypes::CellVal::Int(2)))));
+        assert_eq!(eval_str(&sheet, "=SUM(E1:F2)"), "Int(53)");
+        // and the other one takes over once it is gone
+        sheet.delete_cell(loc("D2"));
+        assert!(matches!(sheet.cell_val(4, 2), Ok(Some(dtypes::CellVal::Int(3)))));
+        assert_eq!(eval_str(&sheet, "=SUM(E1:F2)"), "Int(10)");
+        // a formula that takes a value from a volatile spill is volatile too
+        sheet.write_cell(loc("H1"), parsing::parse_val("=SEQUENCE(2)+0*RAND()"));
+        sheet.write_cell(loc("I1"), parsing::parse_val("=H2"));
+        assert!(matches!(sheet.cell_val(8, 1), Ok(Some(dtypes::CellVal::Real(x))) if x == 2.0));
+        assert!(sheet.volatile.borrow().contains(&(8, 1)));
+    }
+
+    #[test]
+    fn test_sheet_array_functions () {
+        let mut sheet = Sheet::new();
+        for (loc, val) in [("A1", "pear"), ("A2", "apple"), ("A3", "fig"), ("A4", "apple"), 
+                           ("B1", "3"), ("B2", "10"), ("B3", "7"), ("B4", "1")] {
+            sheet.write_cell(parsing::parse_loc(loc).unwrap(), parsing::parse_val(val));
+        }
+        sheet.write_cell(parsing::parse_loc("D1").unwrap(), parsing::parse_val("=SORT(UNIQUE(A1:A4))"));
+        sheet.write_cell(parsing::parse_loc("E1").unwrap(), parsing::parse_val("=FILTER(A1:A4,B1:B4>5)"));
+        sheet.write_cell(parsing::parse_loc("F1").unwrap(), parsing::parse_val("=FILTER(A1:A4,B1:B4>50,\"none\")"));
+        assert!(matches!(&sheet.cell_val(3, 3), Ok(Some(dtypes::CellVal::Text(t))) if t == "pear"));
+        assert!(matches!(sheet.cell_val(3, 4), Ok(None)));
+        assert!(matches!(&sheet.cell_val(4, 2), Ok(Some(dtypes::CellVal::Text(t))) if t == "fig"));
+        assert!(matches!(&sheet.cell_val(5, 1), Ok(Some(dtypes::CellVal::Text(t))) if t == "none"));
+        assert_eq!(eval_str(&sheet, "=B1>=3"), "Bool(true)");
+        assert_eq!(eval_str(&sheet, "=FILTER(A1:A4,B1:B4>50)"), "Text(\"#CALC!\")");
+    }
+
+    #[test]
+    fn test_sheet_formula_chain_and_circular () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("2"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("=A1*3"));
+        sheet.write_cell(parsing::parse_loc("A3").unwrap(), parsing::parse_val("=A2+A1"));
+        assert!(matches!(sheet.cell_val(0, 3), Ok(Some(dtypes::CellVal::Int(8)))));
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("=A3"));
+        assert!(matches!(sheet.eval_formula_at(0, 3), Err(dtypes::EvalErr::Circ)));
+        assert!(matches!(sheet.eval_formula_at(0, 1), Err(dtypes::EvalErr::Circ)));
+    }
+
+    #[test]
+    fn test_sheet_canonical_formulas () {
+        // formulas and defined names are stored in their canonical form unless they do not parse
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("= sum( b1 : b3 ) * (2)"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("= 1 +"));
+        sheet.define_name("margin", "=lambda( p , c , (p - c) / p )");
+        assert!(matches!(sheet.stored_cell(0, 1), Some(dtypes::CellVal::Formula(f)) if f == "=SUM(B1:B3)*2"));
+        assert!(matches!(sheet.stored_cell(0, 2), Some(dtypes::CellVal::Formula(f)) if f == "= 1 +"));
+        assert_eq!(sheet.names.get("MARGIN").map(String::as_str), Some("=LAMBDA(P,C,(P-C)/P)"));
+    }
+
+    #[test]
+    fn test_sheet_compiled_formulas () {
+        // parsed formulas are reused until their cell or name changes
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("=1+1"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("=A1*RATE"));
+        sheet.define_name("rate", "=10");
+        assert!(matches!(sheet.cell_val(0, 2), Ok(Some(dtypes::CellVal::Int(20)))));
+        assert_eq!(sheet.compiled.borrow().len(), 2);
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("=2+2"));
+        assert!(matches!(sheet.cell_val(0, 2), Ok(Some(dtypes::CellVal::Int(40)))));
+        sheet.define_name("rate", "=100");
+        assert!(matches!(sheet.cell_val(0, 2), Ok(Some(dtypes::CellVal::Int(400)))));
+        sheet.delete_cell(parsing::parse_loc("A1").unwrap());
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("3"));
+        assert!(matches!(sheet.cell_val(0, 2), Ok(Some(dtypes::CellVal::Int(300)))));
+        // a name that takes the place of a builtin after the formula was compiled
+        sheet.write_cell(loc("A3"), parsing::parse_val("=NPV(0,-2)+A1"));
+        assert!(matches!(sheet.cell_val(0, 3), Ok(Some(dtypes::CellVal::Real(v))) if v == 1.0));
+        sheet.define_name("npv", "=LAMBDA(r,x,x*10)");
+        assert!(matches!(sheet.cell_val(0, 3), Ok(Some(dtypes::CellVal::Int(-17)))));
+        sheet.delete_name("npv");
+        assert!(matches!(sheet.cell_val(0, 3), Ok(Some(dtypes::CellVal::Real(v))) if v == 1.0));
+        // explaining a compiled formula still records every step
+        let explanation = sheet.explain(&loc("A3")).unwrap();
+        assert!(matches!(explanation.result, Ok(dtypes::EvalVal::Val(dtypes::CellVal::Real(v))) if v == 1.0));
+        assert_eq!(explanation.steps.len(), 6);
+    }
+
+    #[test]
+    fn test_sheet_compiled_formulas_match_trees () {
+        // running the compiled steps gives the same as evaluating the tree 
+        // (which is still used for defined names, LAMBDA bodies and anything 
+        // the steps do not cover)
+        let mut sheet = Sheet::new();
+        for (loc, val) in [("A1", "=SEQUENCE(2)"), ("B1", "-3"), ("B2", "2.5d"), ("B3", "text"), ("C2", "true"), ("C3", "2024-01-31"),
+                           ("C5", "9000000000"), ("C9", "=B2*2"), ("ZZ10", "=A1#*2")] {
+            sheet.write_cell(parsing::parse_loc(loc).unwrap(), parsing::parse_val(val));
+        }
+        for (name, formula) in [("x", "=3"), ("rate", "=0.1"), ("net.margin", "=LAMBDA(a,b,a-b)"), ("_tmp", "=A1:B3")] {
+            sheet.define_name(name, formula);
+        }
+        let mut formulas: Vec<String> = ["=NPV(rate,B1:B3,C5)", "=DAY(DATE(2024,1,31)+x)", "=SEQUENCE(2,2)+A1#", "=TRANSPOSE(A1:B2)&\"!\"",
+                                         "=net.margin(B2,x)", "=LET(y,B1,y*y)-ZZ10", "=-C3+1", "=NPV(0,_tmp)", "=B4/0", "=SUM(A1:C9)*2",
+                                         "=SUM(_tmp)", "=SUM(B:B,2:2)", "=SUM(OFFSET(A1,1,1,2,1))&INDIRECT(\"B3\")",
+                                         "=MMULT(A1#,TRANSPOSE(A1#))", "=REGEXMATCH(B3,\"^t\")=C2"]
+            .iter().map(|formula| formula.to_string()).collect();
+        let mut rng = parsing::tests::Rng(0x2545F4914F6CDD1D);
+        formulas.extend((0..2000).map(|_| format!("={}", parsing::tests::random_formula(&mut rng, 4))));
+        for formula in formulas {
+            let tree = Sheet::compile(&dtypes::CellVal::Formula(formula.clone()));
+            let root = tree.as_ref().as_ref().unwrap();
+            let mut steps = Vec::new();
+            sheet.compile_steps(root, false, false, &mut steps);
+            let compiled = format!("{:?}", sheet.run(&steps));
+            assert_eq!(compiled, format!("{:?}", sheet.eval_node(root, &formulas::Scope::default())), "{} compiled to {:?}", formula, steps);
+        }
+    }
+
+    #[test]
+    fn test_sheet_precedents_and_dependents () {
+        let mut sheet = Sheet::new();
+        for (loc, val) in [("A1", "1"), ("A2", "2"), ("A4", "4"), ("B1", "=A1+A2*RATE"), ("B2", "=SEQUENCE(2,1,B1)"),
+                           ("C1", "=B2#*2"), ("C2", "=LET(x,A1:A3,x+B1)"), ("D1", "0.5")] {
+            sheet.write_cell(parsing::parse_loc(loc).unwrap(), parsing::parse_val(val));
+        }
+        sheet.define_name("rate", "=D1");
+        let locs = |locs: Vec<dtypes::CellLoc>| locs.iter().map(|loc| format!("{}{}", loc.col, loc.row)).collect::<Vec<String>>().join(" ");
+        // ranges only count the cells with something in them, names count what they use
+        assert_eq!(locs(sheet.precedents(&loc("B1"), false)), "A1 A2 D1");
+        assert_eq!(locs(sheet.precedents(&loc("C2"), false)), "A1 A2 B1");
+        assert_eq!(locs(sheet.precedents(&loc("C1"), false)), "B2");
+        assert_eq!(locs(sheet.precedents(&loc("C1"), true)), "A1 A2 B1 B2 D1");
+        assert_eq!(locs(sheet.precedents(&loc("A1"), true)), "");
+        assert_eq!(locs(sheet.dependents(&loc("A1"), false)), "B1 C2");
+        assert_eq!(locs(sheet.dependents(&loc("A1"), true)), "B1 B2 C1 C2");
+        assert_eq!(locs(sheet.dependents(&loc("D1"), true)), "B1 B2 C1 C2");
+        assert_eq!(locs(sheet.dependents(&loc("A4"), true)), "");
+        // a cell in a circular reference depends on itself
+        sheet.write_cell(loc("A1"), parsing::parse_val("=C1"));
+        assert_eq!(locs(sheet.dependents(&loc("C1"), true)), "A1 B1 B2 C1 C2");
+    }
+
+    #[test]
+    fn test_sheet_whole_columns_and_rows () {
+        // whole columns and rows follow the sheet as it grows
+        let mut sheet = Sheet::new();
+        assert!(matches!(sheet.eval_formula_cell(&parsing::parse_val("=SUM(C:C)")), dtypes::CellVal::Int(0)));
+        for (l, val) in [("C1", "1"), ("C2", "x"), ("C3", "2.50d"), ("D2", "=SUM(C:C)"), ("E1", "=SUM(2:2)"), ("A5", "=SUM(b:c, 1)")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        assert!(matches!(sheet.cell_val(3, 2), Ok(Some(dtypes::CellVal::Decimal(d))) if d.to_string() == "3.50"));
+        assert!(matches!(sheet.cell_val(0, 5), Ok(Some(dtypes::CellVal::Decimal(d))) if d.to_string() == "4.50"));
+        sheet.write_cell(loc("C500"), parsing::parse_val("10"));
+        sheet.write_cell(loc("B2"), parsing::parse_val("1.5"));
+        assert!(matches!(sheet.cell_val(3, 2), Ok(Some(dtypes::CellVal::Decimal(d))) if d.to_string() == "13.50"));
+        // the row has the column sum and B2 in it
+        assert!(matches!(sheet.cell_val(4, 1), Ok(Some(dtypes::CellVal::Real(v))) if v == 15.0));
+        // a formula inside of its own column is circular
+        sheet.write_cell(loc("C4"), parsing::parse_val("=SUM(C:C)"));
+        assert!(matches!(sheet.cell_val(2, 4), Err(dtypes::EvalErr::Circ)));
+        sheet.delete_cell(loc("C4"));
+        let locs = |locs: Vec<dtypes::CellLoc>| locs.iter().map(|loc| format!("{}{}", loc.col, loc.row)).collect::<Vec<String>>().join(" ");
+        assert_eq!(locs(sheet.precedents(&loc("D2"), false)), "C1 C2 C3 C500");
+        assert_eq!(locs(sheet.dependents(&loc("C500"), false)), "A5 D2");
+        // SUM only gets the cells that have something stored or spilled in them
+        sheet.write_cell(loc("F1"), parsing::parse_val("=SEQUENCE(3)"));
+        sheet.write_cell(loc("G1000000"), parsing::parse_val("1"));
+        let grid = sheet.range_values(&loc("A1"), &loc("Z1000000")).unwrap();
+        assert_eq!((grid.n_rows, grid.n_cols), (12, 1));
+        assert!(matches!(&grid.vals[..4], [Some(dtypes::CellVal::Int(1)), Some(_), Some(dtypes::CellVal::Int(1)), Some(dtypes::CellVal::Real(v))] if *v == 1.5));
+        assert_eq!(eval_str(&sheet, "=SUM(F:F)"), "Int(6)");
+        assert_eq!(eval_str(&sheet, "=SUM(G:G, 1000000:1000000)"), "Int(2)");
+        assert_eq!(eval_str(&sheet, "=NPV(0,F:G)"), "Real(7.0)");
+        // everything else gets the whole block, as long as it is not too big
+        assert_eq!(eval_str(&sheet, "=TRANSPOSE(F:G)"), "Text(\"#REF!\")");
+    }
+
+    #[test]
+    fn test_sheet_indirect_and_offset () {
+        // references worked out from values when the formula is evaluated
+        let mut sheet = Sheet::new();
+        for (l, val) in [("A1", "2"), ("A2", "3"), ("A3", "4"), ("B2", "20"), ("B3", "30"), ("C1", "=INDIRECT(\"B\"&A1)"),
+                         ("C2", "=SUM(OFFSET(A1,0,0,A1,1))"), ("C3", "=OFFSET(B3,-1,0)"), ("C4", "=SUM(OFFSET(A1:A2,1,1))"),
+                         ("C5", "=SUM(INDIRECT(\"a:a\"))"), ("C6", "=INDIRECT(\"nope\")"), ("C7", "=OFFSET(A1,-1,0)"),
+                         ("C8", "=OFFSET(A1,0,0,0,1)"), ("C9", "=OFFSET(1,0,0)"), ("C10", "=OFFSET(A1,0,0,0-9223372036854775807-1,1)")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        assert!(matches!(sheet.cell_val(2, 1), Ok(Some(dtypes::CellVal::Int(20)))));
+        assert!(matches!(sheet.cell_val(2, 2), Ok(Some(dtypes::CellVal::Int(5)))));
+        assert!(matches!(sheet.cell_val(2, 3), Ok(Some(dtypes::CellVal::Int(20)))));
+        assert!(matches!(sheet.cell_val(2, 4), Ok(Some(dtypes::CellVal::Int(50)))));
+        assert!(matches!(sheet.cell_val(2, 5), Ok(Some(dtypes::CellVal::Int(9)))));
+        for row in [6, 7, 8, 10] {
+            assert!(matches!(sheet.cell_val(2, row), Err(dtypes::EvalErr::Ref)), "C{} should be #REF!", row);
+        }
+        assert!(matches!(sheet.cell_val(2, 9), Err(dtypes::EvalErr::Value)));
+        // they are volatile and follow the values they are worked out from
+        assert!(sheet.volatile.borrow().contains(&(2, 1)));
+        let locs = |locs: Vec<dtypes::CellLoc>| locs.iter().map(|loc| format!("{}{}", loc.col, loc.row)).collect::<Vec<String>>().join(" ");
+        assert_eq!(locs(sheet.precedents(&loc("C1"), false)), "A1 B2");
+        sheet.write_cell(loc("A1"), parsing::parse_val("3"));
+        assert!(matches!(sheet.cell_val(2, 1), Ok(Some(dtypes::CellVal::Int(30)))));
+        assert!(matches!(sheet.cell_val(2, 2), Ok(Some(dtypes::CellVal::Int(10)))));
+        assert_eq!(locs(sheet.precedents(&loc("C1"), false)), "A1 B3");
+        assert_eq!(locs(sheet.precedents(&loc("C2"), false)), "A1 A2 A3");
+    }
+
+    #[test]
+    fn test_sheet_iterative_calculation () {
+        // interest on the average balance needs the closing balance which needs the interest
+        let mut sheet = Sheet::new();
+        for (loc, val) in [("A1", "1000"), ("A2", "=0.1*(A1+A3)/2"), ("A3", "=A1+A2"), ("B1", "=A3*2")] {
+            sheet.write_cell(parsing::parse_loc(loc).unwrap(), parsing::parse_val(val));
+        }
+        assert!(sheet.recalculate().is_none());
+        assert!(matches!(sheet.cell_val(0, 3), Err(dtypes::EvalErr::Circ)));
+        sheet.set_iteration(Option::Some(Iteration { max_iterations: 100, max_change: 1e-9 }));
+        let convergence = sheet.recalculate().unwrap();
+        assert!(convergence.converged && convergence.iterations > 2 && convergence.max_change <= 1e-9, "{:?}", convergence);
+        let interest = 100.0 / 0.95;
+        assert!(matches!(sheet.cell_val(0, 2), Ok(Some(dtypes::CellVal::Real(v))) if (v - interest).abs() < 1e-6));
+        assert!(matches!(sheet.cell_val(1, 1), Ok(Some(dtypes::CellVal::Real(v))) if (v - 2.0 * (1000.0 + interest)).abs() < 1e-6));
+        // something that never settles stops at max_iterations
+        sheet.write_cell(parsing::parse_loc("C1").unwrap(), parsing::parse_val("=C1+1"));
+        let convergence = sheet.recalculate().unwrap();
+        assert!(!convergence.converged && convergence.iterations == 100 && convergence.max_change == 1.0, "{:?}", convergence);
+        assert!(matches!(sheet.cell_val(2, 1), Ok(Some(dtypes::CellVal::Int(100)))));
+        // and without any circular references one iteration is enough
+        sheet.delete_cell(parsing::parse_loc("C1").unwrap());
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("=A1*0.1"));
+        assert!(matches!(sheet.recalculate(), Some(Convergence { iterations: 1, converged: true, .. })));
+    }
+
+    #[test]
+    fn test_sheet_goal_seek () {
+        let mut sheet = Sheet::new();
+        for (l, val) in [("A1", "1"), ("B1", "=A1^2+1"), ("B2", "=A2*3-7"), ("A3", "2"), ("B3", "=A3^2"), ("A4", "x"),
+                         ("B4", "=A4*2"), ("B5", "=NOPE(A5)")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        // from where the changing cell is now, or 0 if it is empty
+        // an Int stays an Int when a whole number gets there
+        let x = sheet.goal_seek(&loc("B1"), 10.0, &loc("A1")).unwrap();
+        assert!(matches!(x, dtypes::CellVal::Int(3)), "{:?}", x);
+        assert!(matches!(sheet.stored_cell(0, 1), Some(dtypes::CellVal::Int(3))));
+        assert!(matches!(sheet.cell_val(1, 1), Ok(Some(dtypes::CellVal::Int(10)))));
+        let x = sheet.goal_seek(&loc("B2"), 5.0, &loc("A2")).unwrap();
+        assert!(matches!(x, dtypes::CellVal::Real(v) if (v - 4.0).abs() < 1e-6), "{:?}", x);
+        // a Decimal stays a Decimal, with more digits if it needs them
+        for (l, val) in [("C1", "100.00d"), ("D1", "=C1*1.25d"), ("C2", "1.0d"), ("D2", "=C2*8")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        let x = sheet.goal_seek(&loc("D1"), 150.0, &loc("C1")).unwrap();
+        assert!(matches!(&x, dtypes::CellVal::Decimal(d) if d.to_string() == "120.00"), "{:?}", x);
+        let x = sheet.goal_seek(&loc("D2"), 1.0, &loc("C2")).unwrap();
+        assert!(matches!(&x, dtypes::CellVal::Decimal(d) if d.to_string() == "0.125"), "{:?}", x);
+        // no whole number gets there
+        sheet.write_cell(loc("C3"), parsing::parse_val("1"));
+        sheet.write_cell(loc("D3"), parsing::parse_val("=C3*2"));
+        let x = sheet.goal_seek(&loc("D3"), 5.0, &loc("C3")).unwrap();
+        assert!(matches!(x, dtypes::CellVal::Real(v) if (v - 2.5).abs() < 1e-6), "{:?}", x);
+        // nothing gets there so the changing cell is left alone
+        assert!(matches!(sheet.goal_seek(&loc("B3"), -1.0, &loc("A3")), Err(GoalSeekErr::NoSolution)));
+        assert!(matches!(sheet.stored_cell(0, 3), Some(dtypes::CellVal::Int(2))));
+        assert!(matches!(sheet.goal_seek(&loc("A3"), 1.0, &loc("A1")), Err(GoalSeekErr::NotAFormula)));
+        assert!(matches!(sheet.goal_seek(&loc("B4"), 1.0, &loc("A4")), Err(GoalSeekErr::NotANumber)));
+        assert!(matches!(sheet.goal_seek(&loc("B5"), 1.0, &loc("A5")), Err(GoalSeekErr::NoSolution)));
+        assert!(sheet.stored_cell(0, 5).is_none());
+    }
+
+    #[test]
+    fn test_sheet_data_table () {
+        let mut sheet = Sheet::new();
+        for (l, val) in [("A1", "10"), ("A2", "5"), ("B1", "=A1*A2"), ("B2", "=B1-D4")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        let ints = |vals: &[i64]| vals.iter().map(|v| dtypes::CellVal::Int(*v)).collect::<Vec<dtypes::CellVal>>();
+        let shown = |grid: &dtypes::CellGrid| grid.vals.iter().map(|v| format!("{:?}", v.clone().unwrap())).collect::<Vec<String>>().join(" ");
+        let grid = sheet.data_table(&loc("B1"), &[(loc("A1"), ints(&[8, 10, 12]))]).unwrap();
+        assert_eq!((grid.n_rows, grid.n_cols), (3, 1));
+        assert_eq!(shown(&grid), "Int(40) Int(50) Int(60)");
+        let grid = sheet.data_table(&loc("B1"), &[(loc("A1"), ints(&[8, 10])), (loc("A2"), ints(&[1, 2, 3]))]).unwrap();
+        assert_eq!((grid.n_rows, grid.n_cols), (2, 3));
+        assert_eq!(shown(&grid), "Int(8) Int(16) Int(24) Int(10) Int(20) Int(30)");
+        // an input that was empty (and past the end of the sheet) is again afterwards
+        let grid = sheet.data_table(&loc("B2"), &[(loc("D4"), vec![dtypes::CellVal::Int(1), dtypes::CellVal::Text(String::from("x"))])]).unwrap();
+        assert_eq!(shown(&grid), "Int(49) Text(\"#VALUE!\")");
+        assert!(sheet.stored_cell(3, 4).is_none());
+        assert_eq!((sheet.n_cols, sheet.n_rows), (2, 2));
+        assert!(matches!(sheet.stored_cell(0, 1), Some(dtypes::CellVal::Int(10))));
+        assert!(matches!(sheet.cell_val(1, 1), Ok(Some(dtypes::CellVal::Int(50)))));
+        assert!(sheet.data_table(&loc("A1"), &[(loc("A2"), ints(&[1]))]).is_none());
+    }
+
+    #[test]
+    fn test_sheet_coercion () {
+        let mut sheet = Sheet::new();
+        // a number that came in as text
+        sheet.write_cell(loc("A1"), dtypes::CellVal::Text(String::from("42")));
+        sheet.write_cell(loc("A2"), dtypes::CellVal::Bool(true));
+        for (l, val) in [("B1", "=A1+A2"), ("B2", "=A1*C9"), ("B3", "=-C9&\"x\""), ("B4", "=C9")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        assert!(matches!(sheet.shown_at(&loc("B1")), Some(dtypes::CellVal::Int(43))));
+        assert!(matches!(sheet.shown_at(&loc("B2")), Some(dtypes::CellVal::Int(0))));
+        assert!(matches!(sheet.shown_at(&loc("B3")), Some(dtypes::CellVal::Text(t)) if t == "0x"));
+        // an empty cell on its own is still not a value
+        assert!(matches!(sheet.shown_at(&loc("B4")), Some(dtypes::CellVal::Text(t)) if t == "#ERR"));
+        sheet.set_coercion(formulas::Coercion::Strict);
+        for l in ["B1", "B2", "B3"] {
+            assert!(matches!(sheet.shown_at(&loc(l)), Some(dtypes::CellVal::Text(t)) if t == "#VALUE!"), "{}", l);
+        }
+    }
+
+    #[test]
+    fn test_sheet_scenarios () {
+        let mut sheet = Sheet::new();
+        for (l, val) in [("B2", "100"), ("B3", "0.5"), ("C1", "=B2*B3"), ("C2", "=B2+B4")] {
+            sheet.write_cell(loc(l), parsing::parse_val(val));
+        }
+        sheet.save_scenario("Base", &[loc("B2"), loc("B3"), loc("B4")]);
+        sheet.write_cell(loc("B2"), parsing::parse_val("200"));
+        sheet.write_cell(loc("B4"), parsing::parse_val("1"));
+        sheet.save_scenario("Optimistic", &[loc("B2"), loc("B3"), loc("B4")]);
+        assert_eq!(sheet.scenarios().keys().collect::<Vec<&String>>(), ["Base", "Optimistic"]);
+        // the summary leaves the sheet alone
+        let summary = sheet.scenario_summary(&[loc("C1"), loc("C2")]);
+        let summary: Vec<String> = summary.iter().map(|(name, vals)| format!("{} {:?}", name, vals)).collect();
+        assert_eq!(summary, ["Base [Some(Real(50.0)), Some(Int(100))]", "Optimistic [Some(Real(100.0)), Some(Int(201))]"]);
+        assert!(matches!(sheet.shown_at(&loc("C2")), Some(dtypes::CellVal::Int(201))));
+        // showing one puts its values (and empty cells) back
+        assert!(sheet.show_scenario("Base"));
+        assert!(matches!(sheet.shown_at(&loc("C1")), Some(dtypes::CellVal::Real(v)) if v == 50.0));
+        assert!(sheet.stored_cell(1, 4).is_none());
+        assert!(!sheet.show_scenario("Pessimistic"));
+        assert!(sheet.delete_scenario("Base"));
+        assert!(!sheet.delete_scenario("Base"));
+    }
+
+    #[test]
+    fn test_sheet_random () {
+        // the same seed gives the same numbers, recalculating only changes volatile cells
+        let build = |seed: u64| {
+            let mut sheet = Sheet::new();
+            sheet.set_seed(Option::Some(seed));
+            for (loc, val) in [("A1", "=RAND()"), ("A2", "=RANDBETWEEN(1,6)"), ("A3", "=A1*10"), ("A4", "=5*2"),
+                               ("B1", "=RANDARRAY(2,3,-5,5,TRUE)")] {
+                sheet.write_cell(parsing::parse_loc(loc).unwrap(), parsing::parse_val(val));
+            }
+            sheet
+        };
+        let sheet = build(7);
+        let vals = |sheet: &Sheet| (0..4).flat_map(|c| (1..=4).map(move |r| (c, r))).map(|(c, r)| format!("{:?}", sheet.cell_val(c, r))).collect::<Vec<String>>();
+        let first = vals(&sheet);
+        assert_eq!(first, vals(&build(7)));
+        assert_ne!(first, vals(&build(8)));
+        assert!(matches!(sheet.cell_val(0, 1), Ok(Some(dtypes::CellVal::Real(x))) if (0.0..1.0).contains(&x)));
+        assert!(matches!(sheet.cell_val(0, 2), Ok(Some(dtypes::CellVal::Int(n))) if (1..=6).contains(&n)));
+        assert!(matches!(sheet.eval_formula_at(1, 1), Ok(dtypes::EvalVal::Grid(g)) if g.n_rows == 2 && g.n_cols == 3 
+                         && g.vals.iter().all(|v| matches!(v, Some(dtypes::CellVal::Int(n)) if (-5..=5).contains(n)))));
+        // nothing changes until the sheet is recalculated
+        assert_eq!(vals(&sheet), first);
+        sheet.recalculate();
+        let second = vals(&sheet);
+        assert_ne!(second[0], first[0]);
+        assert_eq!(second[3], first[3]);
+        assert!(sheet.volatile.borrow().contains(&(0, 3)) && !sheet.volatile.borrow().contains(&(0, 4)));
+        assert_eq!(eval_str(&sheet, "=RANDBETWEEN(3,1)"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=RANDBETWEEN(2.5,3.5)"), "Int(3)");
+        assert_eq!(eval_str(&sheet, "=RANDARRAY(0)"), "Text(\"#CALC!\")");
+    }
+
+    #[test]
+    fn test_sheet_parse_error () {
+        // a formula that does not parse shows #PARSE! and everything else still works
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("=1+"));
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("=2*3"));
+        sheet.write_cell(parsing::parse_loc("A3").unwrap(), parsing::parse_val("=A1+1"));
+        assert!(matches!(sheet.eval_formula_at(0, 1), Err(dtypes::EvalErr::Parse)));
+        assert!(matches!(sheet.eval_formula_at(0, 2), Ok(dtypes::EvalVal::Val(dtypes::CellVal::Int(6)))));
+        assert!(matches!(sheet.eval_formula_at(0, 3), Err(dtypes::EvalErr::Parse)));
+        assert_eq!(eval_str(&sheet, "=SUM(A1:"), "Text(\"#PARSE!\")");
+        sheet.define_name("BROKEN", "=(1");
+        assert_eq!(eval_str(&sheet, "=BROKEN+1"), "Text(\"#PARSE!\")");
+    }
+
+    #[derive(Debug)]
+    struct Tariff;
+
+    impl formulas::Function for Tariff {
+        // TARIFF(usage, band): the first 100 units at the band rate, the rest at double
+        fn name (&self) -> &str {
+            "tariff"
+        }
+
+        fn arity (&self) -> (usize, usize) {
+            (2, 2)
+        }
+
+        fn evaluate (&self, args: &[dtypes::EvalVal], _sheet: &SheetView) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+            let usage = formulas::arg_num(&args[0])?;
+            let rate = match formulas::arg_int(&args[1])? {
+                1 => 0.10,
+                2 => 0.25,
+                _ => return Err(dtypes::EvalErr::Num),
+            };
+            let cost = usage.min(100.0) * rate + (usage - 100.0).max(0.0) * rate * 2.0;
+            Ok(dtypes::EvalVal::Val(dtypes::CellVal::Real(cost)))
+        }
+    }
+
+    #[derive(Debug)]
+    struct Lookup;
+
+    impl formulas::Function for Lookup {
+        // LOOKUP_CELL("B2"): the value in a cell named by some text
+        fn name (&self) -> &str {
+            "LOOKUP_CELL"
+        }
+
+        fn arity (&self) -> (usize, usize) {
+            (1, 1)
+        }
+
+        fn evaluate (&self, args: &[dtypes::EvalVal], sheet: &SheetView) -> Result<dtypes::EvalVal, dtypes::EvalErr> {
+            let loc = parsing::parse_loc(formulas::arg_text(&args[0])?).ok_or(dtypes::EvalErr::Ref)?;
+            sheet.cell(&loc)?.map(dtypes::EvalVal::Val).ok_or(dtypes::EvalErr::Ref)
+        }
+    }
+
+    #[test]
+    fn test_sheet_register_function () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("150"));
+        sheet.write_cell(parsing::parse_loc("B1").unwrap(), parsing::parse_val("=A1*2"));
+        assert_eq!(eval_str(&sheet, "=TARIFF(A1,1)"), "Text(\"#NAME?\")");
+        sheet.register_function(Box::new(Tariff));
+        sheet.register_function(Box::new(Lookup));
+        assert_eq!(eval_str(&sheet, "=TARIFF(A1,1)"), "Real(20.0)");
+        assert_eq!(eval_str(&sheet, "=Tariff(50,2)+1"), "Real(13.5)");
+        assert_eq!(eval_str(&sheet, "=TARIFF(A1,3)"), "Text(\"#NUM!\")");
+        assert_eq!(eval_str(&sheet, "=TARIFF(A1)"), "Text(\"#VALUE!\")");
+        // the sheet view sees evaluated formulas
+        assert_eq!(eval_str(&sheet, "=LOOKUP_CELL(\"B1\")"), "Int(300)");
+        assert_eq!(eval_str(&sheet, "=LOOKUP_CELL(\"C9\")"), "Text(\"#REF!\")");
+    }
+
+    #[test]
+    fn test_sheet_let () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A1").unwrap(), parsing::parse_val("3"));
+        assert_eq!(eval_str(&sheet, "=LET(x,A1*2,x+x^2)"), "Int(42)");
+        assert_eq!(eval_str(&sheet, "=LET(x,1,y,x+1,LET(x,10,x+y))"), "Int(12)");
+        assert_eq!(eval_str(&sheet, "=LET(x,1,x)+x"), "Text(\"#NAME?\")");
+        assert_eq!(eval_str(&sheet, "=LET(x,1)"), "Text(\"#VALUE!\")");
+        assert_eq!(eval_str(&sheet, "=LET(A2,1,A2)"), "Text(\"#VALUE!\")");
+        // LAMBDAs bound by LET can be called and see the names from where they were made
+        assert_eq!(eval_str(&sheet, "=LET(k,10,f,LAMBDA(x,x*k),g,f,g(A1)+f(1))"), "Int(40)");
+        assert_eq!(eval_str(&sheet, "=LET(f,LAMBDA(x,y,x-y),f(1))"), "Text(\"#VALUE!\")");
+        assert_eq!(eval_str(&sheet, "=LET(f,LAMBDA(x,x),f)"), "Text(\"#CALC!\")");
+        assert_eq!(eval_str(&sheet, "=LAMBDA(x,x)"), "Text(\"#CALC!\")");
+    }
+
+    #[test]
+    fn test_sheet_defined_names () {
+        let mut sheet = Sheet::new();
+        sheet.write_cell(parsing::parse_loc("A2").unwrap(), parsing::parse_val("200"));
+        sheet.write_cell(parsing::parse_loc("B2").unwrap(), parsing::parse_val("150"));
+        sheet.define_name("Margin", "=LAMBDA(price,cost,(price-cost)/price)");
+        sheet.define_name("tax_rate", "=0.2");
+        sheet.write_cell(parsing::parse_loc("C2").unwrap(), parsing::parse_val("=MARGIN(A2,B2)"));
+        assert!(matches!(sheet.cell_val(2, 2), Ok(Some(dtypes::CellVal::Real(v))) if v == 0.25));
+        assert_eq!(eval_str(&sheet, "=A2*Tax_Rate"), "Real(40.0)");
+        // parameters hide defined names and defined names do not see the caller's names
+        assert_eq!(eval_str(&sheet, "=LET(tax_rate,1,margin(A2,B2)+tax_rate)"), "Real(1.25)");
+        sheet.define_name("uses_price", "=price");
+        assert_eq!(eval_str(&sheet, "=LET(price,1,USES_PRICE)"), "Text(\"#NAME?\")");
+        // a name that calls itself forever runs out of depth
+        sheet.define_name("forever", "=LAMBDA(n,FOREVER(n+1))");
+        assert_eq!(eval_str(&sheet, "=FOREVER(1)"), "Text(\"#NUM!\")");
+        assert!(sheet.delete_name("margin"));
+        assert!(matches!(sheet.cell_val(2, 2), Err(dtypes::EvalErr::Name)));
+    }
 }
@@ -0,0 +1,201 @@
+/*
+    Module with the text formula functions that look for patterns,
+    regular expressions (see regex.rs) and SEARCH wildcards
+*/
+
+
+use crate::dtypes::{CellGrid, CellVal, EvalErr, EvalVal};
+use crate::formulas;
+use crate::regex::{Captures, Regex};
+use crate::sheet;
+
+
+fn arg_regex (val: &EvalVal) -> Result<Regex, EvalErr> {
+    // regular expression argument, a pattern that does not parse is #VALUE!
+    Regex::new(formulas::arg_text(val)?).ok_or(EvalErr::Value)
+}
+
+
+fn find (regex: &Regex, text: &[char], start: usize) -> Result<Option<Captures>, EvalErr> {
+    // a match that takes too long to look for is #CALC!
+    regex.find_at(text, start).map_err(|_| EvalErr::Calc)
+}
+
+
+fn chars (val: &EvalVal) -> Result<Vec<char>, EvalErr> {
+    Ok(formulas::arg_text(val)?.chars().collect())
+}
+
+
+pub fn fn_regexmatch (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // REGEXMATCH(text, regex)
+    // whether regex matches anywhere in text
+    let (text, regex) = (chars(&args[0])?, arg_regex(&args[1])?);
+    Ok(CellVal::Bool(find(&regex, &text, 0)?.is_some()))
+}
+
+
+pub fn fn_regexextract (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<EvalVal, EvalErr> {
+    // REGEXEXTRACT(text, regex)
+    // the first match, or with capture groups what each of them matched
+    // (spilling across a row if there is more than one)
+    let (text, regex) = (chars(&args[0])?, arg_regex(&args[1])?);
+    let caps = find(&regex, &text, 0)?.ok_or(EvalErr::NA)?;
+    let groups = if regex.n_groups() == 0 { &caps[..] } else { &caps[1..] };
+    let vals: Vec<Option<CellVal>> = groups.iter()
+        .map(|cap| cap.map(|(s, e)| CellVal::Text(text[s..e].iter().collect())))
+        .collect();
+    match &vals[..] {
+        [val] => Ok(EvalVal::Val(val.clone().unwrap_or(CellVal::Text(String::new())))),
+        _ => Ok(EvalVal::Grid(CellGrid { n_rows: 1, n_cols: vals.len(), vals })),
+    }
+}
+
+
+pub fn fn_regexreplace (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // REGEXREPLACE(text, regex, replacement)
+    // every match replaced, $1 to $9 in replacement are what the capture
+    // groups matched ($0 the whole match) and $$ is a $
+    let (text, regex, replacement) = (chars(&args[0])?, arg_regex(&args[1])?, chars(&args[2])?);
+    let mut out = String::new();
+    let mut pos = 0;
+    while let Some(caps) = find(&regex, &text, pos)? {
+        let (start, end) = caps[0].unwrap();
+        out.extend(&text[pos..start]);
+        let mut i = 0;
+        while i < replacement.len() {
+            match (replacement[i], replacement.get(i + 1).and_then(|c| c.to_digit(10))) {
+                ('$', Some(group)) if (group as usize) <= regex.n_groups() => {
+                    if let Some((s, e)) = caps[group as usize] {
+                        out.extend(&text[s..e]);
+                    }
+                    i += 2;
+                },
+                ('$', _) if replacement.get(i + 1) == Some(&'$') => {
+                    out.push('$');
+                    i += 2;
+                },
+                (c, _) => {
+                    out.push(c);
+                    i += 1;
+                },
+            }
+        }
+        // after a match of nothing the next character is kept as it is
+        if end == start {
+            if end == text.len() {
+                pos = end;
+                break;
+            }
+            out.push(text[end]);
+            pos = end + 1;
+        } else {
+            pos = end;
+        }
+    }
+    out.extend(&text[pos.min(text.len())..]);
+    Ok(CellVal::Text(out))
+}
+
+
+fn wildcard_regex (pattern: &str) -> Option<Regex> {
+    // * is any number of characters, ? any one character and ~ makes
+    // the character after it (including * ? and ~) match itself
+    let mut re = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '*' => { re.push_str(r"[\s\S]*"); continue; },
+            '?' => { re.push_str(r"[\s\S]"); continue; },
+            '~' => chars.next().unwrap_or('~'),
+            c => c,
+        };
+        if !c.is_alphanumeric() {
+            re.push('\\');
+        }
+        re.push(c);
+    }
+    Regex::with_case(&re, true)
+}
+
+
+pub fn fn_search (args: &[EvalVal], _sheet: &sheet::Sheet) -> Result<CellVal, EvalErr> {
+    // SEARCH(find_text, within_text, [start_num])
+    // position (from 1) of the first match of find_text in within_text
+    // at or after start_num, ignoring case and with wildcards in find_text
+    let within = chars(&args[1])?;
+    let start = match args.get(2) {
+        Some(arg) => formulas::arg_int(arg)?,
+        None => 1,
+    };
+    if start < 1 || start as usize > within.len() + 1 {
+        return Err(EvalErr::Value)
+    }
+    let regex = wildcard_regex(formulas::arg_text(&args[0])?).ok_or(EvalErr::Value)?;
+    let caps = find(&regex, &within, start as usize - 1)?.ok_or(EvalErr::Value)?;
+    Ok(CellVal::Int(caps[0].unwrap().0 as i64 + 1))
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::parsing;
+
+    fn eval (formula: &str) -> CellVal {
+        let sheet = sheet::Sheet::new();
+        sheet.eval_formula_cell(&parsing::parse_val(formula))
+    }
+
+    fn is_text (val: CellVal, expected: &str) -> bool {
+        matches!(val, CellVal::Text(t) if t == expected)
+    }
+
+    #[test]
+    fn pattern_functions () {
+        let note = "\"shipped, see ORD-20417 and ord-20418\"";
+        assert!(matches!(eval(&format!("=REGEXMATCH({}, \"ORD-\\d+\")", note)), CellVal::Bool(true)));
+        assert!(matches!(eval(&format!("=REGEXMATCH({}, \"^ORD\")", note)), CellVal::Bool(false)));
+        assert!(is_text(eval(&format!("=REGEXEXTRACT({}, \"(?i)ord-(\\d+)\")", note)), "20417"));
+        assert!(is_text(eval(&format!("=REGEXEXTRACT({}, \"\\d+\")", note)), "20417"));
+        let text = |s: &str| EvalVal::Val(CellVal::Text(String::from(s)));
+        let res = fn_regexextract(&[text("a1-b2"), text(r"(\w)(\d)-(x)?")], &sheet::Sheet::new());
+        assert!(matches!(&res, Ok(EvalVal::Grid(g)) if g.n_rows == 1 && matches!(&g.vals[..], [Some(CellVal::Text(a)), Some(CellVal::Text(b)), None] if a == "a" && b == "1")), "{:?}", res);
+        assert!(is_text(eval("=REGEXEXTRACT(\"abc\", \"\\d\")"), "#N/A"));
+        assert!(is_text(eval(&format!("=REGEXREPLACE({}, \"(?i)ord-(\\d+)\", \"#$1\")", note)), "shipped, see #20417 and #20418"));
+        assert!(is_text(eval("=REGEXREPLACE(\"abc\", \"x*\", \"-\")"), "-a-b-c-"));
+        assert!(is_text(eval("=REGEXREPLACE(\"a.b\", \"\\.\", \"$$\")"), "a$b"));
+        // bad patterns are an error value, not a panic
+        assert!(is_text(eval("=REGEXMATCH(\"abc\", \"(\")"), "#VALUE!"));
+        assert!(is_text(eval("=REGEXEXTRACT(\"abc\", \"[z-a]\")"), "#VALUE!"));
+        assert!(is_text(eval("=REGEXREPLACE(\"abc\", \"a{2\", \"\")"), "#VALUE!"));
+        assert!(matches!(eval("=SEARCH(\"B\", \"abcb\")"), CellVal::Int(2)));
+        assert!(matches!(eval("=SEARCH(\"b\", \"abcb\", 3)"), CellVal::Int(4)));
+        assert!(matches!(eval("=SEARCH(\"a?c\", \"xxABC\")"), CellVal::Int(3)));
+        assert!(matches!(eval("=SEARCH(\"ord-*7\", \"see ORD-20417\")"), CellVal::Int(5)));
+        assert!(matches!(eval("=SEARCH(\"~*\", \"a*b\")"), CellVal::Int(2)));
+        assert!(matches!(eval("=SEARCH(\"(\", \"a(b\")"), CellVal::Int(2)));
+        assert!(is_text(eval("=SEARCH(\"z\", \"abc\")"), "#VALUE!"));
+        assert!(is_text(eval("=SEARCH(\"a\", \"abc\", 5)"), "#VALUE!"));
+    }
+
+    #[test]
+    fn pattern_functions_long_text () {
+        let text = |s: &str| EvalVal::Val(CellVal::Text(String::from(s)));
+        let sheet = sheet::Sheet::new();
+        let long = "x".repeat(200_000);
+        assert!(matches!(fn_regexmatch(&[text(&long), text(".*y")], &sheet), Ok(CellVal::Bool(false))));
+        assert!(matches!(fn_regexmatch(&[text(&format!("{}y", long)), text(".*y")], &sheet), Ok(CellVal::Bool(true))));
+        let res = fn_regexreplace(&[text(&long), text("x"), text("ab")], &sheet);
+        assert!(matches!(&res, Ok(CellVal::Text(t)) if t.len() == 400_000));
+        // patterns that take exponential time to backtrack through
+        let aaac = format!("{}c", "a".repeat(24));
+        assert!(matches!(fn_regexmatch(&[text(&aaac), text("(a|a)*b")], &sheet), Ok(CellVal::Bool(false))));
+        assert!(matches!(fn_regexmatch(&[text(&aaac), text("(a*)*b")], &sheet), Ok(CellVal::Bool(false))));
+        assert!(matches!(fn_regexmatch(&[text(&long), text("(x+x+)+y")], &sheet), Ok(CellVal::Bool(false))));
+        // too much to look through is #CALC!
+        let res = fn_regexmatch(&[text(&"a".repeat(5_000)), text("[\\s\\S]{0,3000}b")], &sheet);
+        assert!(matches!(res, Err(EvalErr::Calc)), "{:?}", res);
+    }
+}